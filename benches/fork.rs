@@ -0,0 +1,93 @@
+//! Fork the game from part way through, the way a search over moves does, and compare with
+//! copying the whole VM through a snapshot. Run with `cargo bench --bench fork`.
+extern crate synacor;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use synacor::binary::Binary;
+use synacor::address::Address;
+use synacor::device::{BufferInput, BufferOutput};
+use synacor::vm::{VM, VMState};
+use synacor::pages::PAGE_SIZE;
+
+const FORKS : u32 = 10_000;
+
+const PAGES : usize = 32768 / PAGE_SIZE;
+
+const MOVES : [&str; 4] = ["north\n", "south\n", "east\n", "west\n"];
+
+/// The game after the first `moves` known answers, waiting for the next one
+fn mid_game(moves: usize) -> VM {
+    let path = format!("{}/data/challenge.bin", env!("CARGO_MANIFEST_DIR"));
+    let mut b = Binary::new(&path);
+    b.parse();
+
+    let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+    vm.load_program(Address::new(0), b.binary()).unwrap();
+    vm.start(Address::new(0));
+
+    let answers = fs::read_to_string(format!("{}/data/answers", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    for line in answers.lines().take(moves) {
+        vm.push_input(&format!("{}\n", line));
+    }
+    assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+    vm.drain_output();
+    return vm;
+}
+
+/// A fresh VM in the same state, the way we had to fork before clones
+fn copy(vm: &VM) -> VM {
+    let mut other = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+    other.restore(&vm.snapshot()).unwrap();
+    return other;
+}
+
+fn secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+fn report(name: &str, elapsed: Duration) -> f64 {
+    let per_fork = secs(elapsed) * 1e6 / FORKS as f64;
+    println!("{:<24} {:>6} forks in {:>8.3}s, {:>8.2}us each", name, FORKS, secs(elapsed), per_fork);
+    return per_fork;
+}
+
+/// Just make the forks
+fn fork_only(vm: &VM, fork: fn(&VM) -> VM) -> Duration {
+    let start = Instant::now();
+    for _ in 0..FORKS {
+        let f = fork(vm);
+        assert_eq!(f.instruction_pointer(), vm.instruction_pointer());
+    }
+    start.elapsed()
+}
+
+/// Fork, then try a move in the fork
+fn fork_and_move(vm: &VM, fork: fn(&VM) -> VM) -> (Duration, usize) {
+    let mut unshared = 0;
+    let start = Instant::now();
+    for i in 0..FORKS {
+        let mut f = fork(vm);
+        f.push_input(MOVES[i as usize % MOVES.len()]);
+        assert_eq!(f.resume(), Ok(VMState::AwaitingInput));
+        f.drain_output();
+        unshared += PAGES - f.shared_memory_pages(vm);
+    }
+    (start.elapsed(), unshared / FORKS as usize)
+}
+
+fn main() {
+    let vm = mid_game(27);
+    println!("forking at step {}, {} pages of memory", vm.step_count(), PAGES);
+
+    let copied = report("snapshot copy", fork_only(&vm, copy));
+    let cloned = report("clone", fork_only(&vm, VM::clone));
+    println!("{:<24} {:.1}x faster to clone", "", copied / cloned);
+
+    let (elapsed, _) = fork_and_move(&vm, copy);
+    let copied = report("snapshot copy + move", elapsed);
+    let (elapsed, pages) = fork_and_move(&vm, VM::clone);
+    let cloned = report("clone + move", elapsed);
+    println!("{:<24} {:.1}x faster to clone, copying {} pages a move", "", copied / cloned, pages);
+}
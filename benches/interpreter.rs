@@ -0,0 +1,69 @@
+//! Compare the interpreter with and without the decode cache, and the block engine. Run with
+//! `cargo bench`.
+extern crate synacor;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use synacor::binary::Binary;
+use synacor::address::Address;
+use synacor::register::Register;
+use synacor::device::{BufferInput, BufferOutput};
+use synacor::vm::{VM, VMState};
+use synacor::blocks::Engine;
+
+fn challenge() -> VM {
+    let path = format!("{}/data/challenge.bin", env!("CARGO_MANIFEST_DIR"));
+    let mut b = Binary::new(&path);
+    b.parse();
+
+    let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+    vm.load_program(Address::new(0), b.binary()).unwrap();
+    vm.start(Address::new(0));
+    return vm;
+}
+
+/// Play through the game with the known answers, until we run out of them
+fn play(vm: &mut VM) {
+    let answers = fs::read_to_string(format!("{}/data/answers", env!("CARGO_MANIFEST_DIR"))).unwrap();
+    vm.push_input(&answers);
+    let _ = vm.resume();
+}
+
+/// Run the teleporter's confirmation routine on inputs small enough to finish
+fn confirm(vm: &mut VM) {
+    vm.set_register(Register::R0, 3).unwrap();
+    vm.set_register(Register::R1, 8).unwrap();
+    vm.set_register(Register::R7, 1).unwrap();
+    assert_eq!(vm.run(Address::new(6027)), Ok(VMState::HALT));
+}
+
+fn time(name: &str, cache: bool, engine: Engine, work: fn(&mut VM)) -> Duration {
+    let mut vm = challenge();
+    vm.set_decode_cache(cache);
+    vm.set_engine(engine);
+
+    let start = Instant::now();
+    work(&mut vm);
+    let elapsed = start.elapsed();
+
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    println!("{:<10} {:<12} cache {:<5} {:>10} steps in {:>8.3}s, {:>12.0} steps/s",
+             name, format!("{:?}", engine), if cache { "on" } else { "off" }, vm.step_count(), secs, vm.step_count() as f64 / secs);
+    return elapsed;
+}
+
+fn ratio(before: Duration, after: Duration) -> f64 {
+    (before.as_secs() as f64 * 1e9 + before.subsec_nanos() as f64) / (after.as_secs() as f64 * 1e9 + after.subsec_nanos() as f64)
+}
+
+fn main() {
+    let workloads : Vec<(&str, fn(&mut VM))> = vec![("play", play), ("confirm", confirm)];
+
+    for &(name, work) in &workloads {
+        let off = time(name, false, Engine::Interpreter, work);
+        let on = time(name, true, Engine::Interpreter, work);
+        let blocks = time(name, true, Engine::Blocks, work);
+        println!("{:<10} speedup {:.2}x with the cache, {:.2}x with blocks", name, ratio(off, on), ratio(off, blocks));
+    }
+}
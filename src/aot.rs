@@ -0,0 +1,537 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs::File;
+use std::io::prelude::*;
+
+use address::Address;
+use argument::Argument;
+use instruction::Instruction;
+use symbols::SymbolTable;
+use vm::VM;
+
+/// Which parts of a program are code, beyond what we can find by following jumps from the entry
+/// point. A file of `code ADDRESS` lines, for routines only reached through a register, and
+/// `data START END` lines for ranges which must never be treated as code. Blank lines and
+/// anything after a `#` are ignored.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CodeMap {
+    pub entries: Vec<Address>,
+    pub data: Vec<(Address, Address)>,
+}
+
+impl CodeMap {
+    pub fn new() -> CodeMap {
+        CodeMap { entries: vec![], data: vec![] }
+    }
+
+    pub fn load(path: &str) -> Result<CodeMap, String> {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => CodeMap::parse(&contents),
+            Err(e) => Err(format!("Could not read `{}': {}", path, e))
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<CodeMap, String> {
+        let mut map = CodeMap::new();
+
+        for (n, raw) in contents.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            if line.is_empty() { continue; }
+
+            let words : Vec<&str> = line.split_whitespace().collect();
+            let addr = |i: usize| words.get(i).and_then(|a| a.trim_start_matches('@').parse::<u16>().ok()).map(Address::new);
+
+            match (words[0], addr(1), addr(2)) {
+                ("code", Some(a), None) if words.len() == 2 => map.entries.push(a),
+                ("data", Some(s), Some(e)) if words.len() == 3 => map.data.push((s, e)),
+                _ => return Err(format!("line {}: expected `code ADDRESS' or `data START END', got `{}'", n + 1, raw))
+            }
+        }
+
+        return Ok(map);
+    }
+
+    fn is_data(&self, address: Address) -> bool {
+        self.data.iter().any(|&(s, e)| s <= address && address <= e)
+    }
+}
+
+/// A run of code with a single entry at the top
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BasicBlock {
+    pub start: Address,
+    pub instructions: Vec<(Address, Instruction)>,
+    /// the address just past the last instruction
+    pub end: Address,
+}
+
+fn literal(a: &Argument) -> Option<Address> {
+    match *a {
+        Argument::Literal(v) => Some(Address::new(v.0)),
+        Argument::Register(_) => None
+    }
+}
+
+/// Where control can go after `instruction`, as far as we can tell without running it: the
+/// static targets, and whether it can fall through to the next instruction.
+fn successors(instruction: &Instruction) -> (Vec<Address>, bool) {
+    match *instruction {
+        Instruction::JMP(ref a) => (literal(a).into_iter().collect(), false),
+        Instruction::JT(_, ref a) | Instruction::JF(_, ref a) | Instruction::CALL(ref a) => (literal(a).into_iter().collect(), true),
+        Instruction::RET | Instruction::HALT => (vec![], false),
+        _ => (vec![], true)
+    }
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    match *instruction {
+        Instruction::JMP(_) | Instruction::JT(_, _) | Instruction::JF(_, _) | Instruction::CALL(_) |
+        Instruction::RET | Instruction::HALT => true,
+        _ => false
+    }
+}
+
+/// Find the code in the program loaded in `vm` by following every static jump and call from
+/// `entry` and the map's entry points, then split it into basic blocks.
+pub fn analyse(vm: &VM, entry: Address, map: &CodeMap) -> Vec<BasicBlock> {
+    let mut code : BTreeMap<Address, Instruction> = BTreeMap::new();
+    let mut leaders = BTreeSet::new();
+    let mut work = vec![entry];
+    work.extend(map.entries.iter().cloned());
+    leaders.extend(work.iter().cloned());
+
+    while let Some(addr) = work.pop() {
+        if code.contains_key(&addr) || !addr.is_memory() || map.is_data(addr) { continue; }
+        let instruction = match vm.decode_at(addr) {
+            Ok(i) => i,
+            Err(_) => continue
+        };
+
+        let next = Address::new(addr.value() + instruction.size());
+        let (targets, falls_through) = successors(&instruction);
+        for t in targets {
+            leaders.insert(t);
+            work.push(t);
+        }
+        if falls_through {
+            // the instruction after a branch or call is where we carry on, or return to
+            if ends_block(&instruction) { leaders.insert(next); }
+            work.push(next);
+        }
+
+        code.insert(addr, instruction);
+    }
+
+    let mut blocks = vec![];
+    let mut current : Option<BasicBlock> = None;
+
+    for (&addr, instruction) in &code {
+        let next = Address::new(addr.value() + instruction.size());
+
+        let continues = match current {
+            Some(ref b) => b.end == addr && !leaders.contains(&addr),
+            None => false
+        };
+        if !continues {
+            if let Some(b) = current.take() { blocks.push(b); }
+            current = Some(BasicBlock { start: addr, instructions: vec![], end: addr });
+        }
+
+        if let Some(ref mut b) = current {
+            b.instructions.push((addr, instruction.clone()));
+            b.end = next;
+        }
+
+        if ends_block(instruction) {
+            if let Some(b) = current.take() { blocks.push(b); }
+        }
+    }
+    if let Some(b) = current.take() { blocks.push(b); }
+
+    return blocks;
+}
+
+/// An argument as a Rust expression over the state
+fn operand(a: &Argument) -> String {
+    match *a {
+        Argument::Literal(v) => format!("{}", v.0),
+        Argument::Register(r) => format!("s.reg[{}]", r.as_index())
+    }
+}
+
+/// Work out arithmetic on two literals now, rather than leaving the compiler to complain about
+/// overflow in the generated code.
+fn fold(instruction: &Instruction) -> Option<(usize, u16)> {
+    let v = |a: &Argument| match *a { Argument::Literal(v) => Some(v), Argument::Register(_) => None };
+
+    match *instruction {
+        Instruction::ADD(r, ref a, ref b) => Some((r.as_index(), (v(a)? + v(b)?).0)),
+        Instruction::MULT(r, ref a, ref b) => Some((r.as_index(), (v(a)? * v(b)?).0)),
+        Instruction::MOD(r, ref a, ref b) if v(b)?.0 != 0 => Some((r.as_index(), (v(a)? % v(b)?).0)),
+        Instruction::AND(r, ref a, ref b) => Some((r.as_index(), (v(a)? & v(b)?).0)),
+        Instruction::OR(r, ref a, ref b) => Some((r.as_index(), (v(a)? | v(b)?).0)),
+        Instruction::NOT(r, ref a) => Some((r.as_index(), (!v(a)?).0)),
+        _ => None
+    }
+}
+
+/// The Rust statements for one instruction, `next` being the address after it
+fn lift(instruction: &Instruction, next: Address) -> String {
+    if let Some((r, value)) = fold(instruction) {
+        return format!("s.reg[{}] = {};", r, value);
+    }
+
+    match *instruction {
+        Instruction::HALT => String::from("return Flow::Halt;"),
+        Instruction::SET(r, ref a) => format!("s.reg[{}] = {};", r.as_index(), operand(a)),
+        Instruction::PUSH(ref a) => format!("s.stack.push({});", operand(a)),
+        Instruction::POP(r) => format!("s.reg[{}] = check!(s.pop());", r.as_index()),
+        Instruction::EQ(r, ref a, ref b) => format!("s.reg[{}] = ({} == {}) as u16;", r.as_index(), operand(a), operand(b)),
+        Instruction::GT(r, ref a, ref b) => format!("s.reg[{}] = ({} > {}) as u16;", r.as_index(), operand(a), operand(b)),
+        Instruction::JMP(ref a) => format!("s.ip = {}; return Flow::Next;", operand(a)),
+        Instruction::JT(ref c, ref a) => format!("if {} != 0 {{ s.ip = {}; return Flow::Next; }}", operand(c), operand(a)),
+        Instruction::JF(ref c, ref a) => format!("if {} == 0 {{ s.ip = {}; return Flow::Next; }}", operand(c), operand(a)),
+        Instruction::ADD(r, ref a, ref b) => format!("s.reg[{}] = ({} + {}) % 32768;", r.as_index(), operand(a), operand(b)),
+        Instruction::MULT(r, ref a, ref b) => format!("s.reg[{}] = (({} as u32 * {} as u32) % 32768) as u16;", r.as_index(), operand(a), operand(b)),
+        Instruction::MOD(r, ref a, ref b) => format!("s.reg[{}] = {} % {};", r.as_index(), operand(a), operand(b)),
+        Instruction::AND(r, ref a, ref b) => format!("s.reg[{}] = {} & {};", r.as_index(), operand(a), operand(b)),
+        Instruction::OR(r, ref a, ref b) => format!("s.reg[{}] = {} | {};", r.as_index(), operand(a), operand(b)),
+        Instruction::NOT(r, ref a) => format!("s.reg[{}] = !{} & 0x7fff;", r.as_index(), operand(a)),
+        Instruction::RMEM(r, ref a) => format!("s.reg[{}] = check!(s.read({}));", r.as_index(), operand(a)),
+        Instruction::WMEM(ref a, ref b) => format!("check!(s.write({}, {}));", operand(a), operand(b)),
+        Instruction::CALL(ref a) => format!("s.stack.push({}); s.ip = {}; return Flow::Next;", next.value(), operand(a)),
+        Instruction::RET => String::from("s.ip = match s.stack.pop() { Some(v) => v, None => return Flow::Halt }; return Flow::Next;"),
+        Instruction::OUT(ref a) => format!("s.output({});", operand(a)),
+        Instruction::IN(Argument::Register(r)) => format!("s.reg[{}] = check!(s.input());", r.as_index()),
+        Instruction::IN(Argument::Literal(v)) => format!("let c = check!(s.input()); check!(s.write({}, c));", v.0),
+        Instruction::NOOP => String::new(),
+    }
+}
+
+fn function_name(address: Address) -> String {
+    format!("block_{}", address.value())
+}
+
+/// Rust source for a block, as a function over the state which leaves `s.ip` wherever control
+/// goes next.
+pub fn lift_block(block: &BasicBlock, symbols: &SymbolTable) -> String {
+    let mut f = String::new();
+    match symbols.describe(block.start) {
+        Some(name) => writeln!(f, "/// {} <{}>", block.start, name).unwrap(),
+        None => writeln!(f, "/// {}", block.start).unwrap()
+    }
+    writeln!(f, "fn {}(s: &mut State) -> Flow {{", function_name(block.start)).unwrap();
+
+    let mut falls_through = true;
+    for &(addr, ref instruction) in &block.instructions {
+        let next = Address::new(addr.value() + instruction.size());
+        let code = lift(instruction, next);
+        if code.is_empty() {
+            writeln!(f, "    // {}: {}", addr, instruction).unwrap();
+        } else {
+            writeln!(f, "    {} // {}: {}", code, addr, instruction).unwrap();
+        }
+        falls_through = successors(instruction).1;
+    }
+
+    if falls_through {
+        writeln!(f, "    s.ip = {};", block.end.value()).unwrap();
+        writeln!(f, "    Flow::Next").unwrap();
+    }
+    writeln!(f, "}}").unwrap();
+    return f;
+}
+
+/// The parts of the generated program which don't depend on the binary: the state, and an
+/// interpreter for anything we couldn't lift ahead of time.
+const RUNTIME : & str = r#"
+use std::io::{self, Read, Write};
+use std::process;
+
+enum Flow {
+    Next,
+    Halt,
+    Fault(String)
+}
+
+/// Unwrap a result, or stop with a fault
+macro_rules! check {
+    ($e:expr) => (match $e { Ok(v) => v, Err(e) => return Flow::Fault(e) })
+}
+
+struct State {
+    mem: Vec<u16>,
+    reg: [u16; 8],
+    stack: Vec<u16>,
+    ip: u16,
+    /// which words were lifted ahead of time
+    code: Vec<bool>,
+    /// set once the program writes over lifted code, after which we only interpret
+    self_modified: bool,
+    out: io::BufWriter<io::Stdout>,
+    input: io::Bytes<io::Stdin>,
+}
+
+impl State {
+    fn new() -> State {
+        let mut mem = vec![0u16; 32768];
+        mem[..IMAGE.len()].copy_from_slice(IMAGE);
+        let mut code = vec![false; 32768];
+        for &(start, end) in CODE {
+            for a in start..end { code[a as usize] = true; }
+        }
+
+        State {
+            mem,
+            reg: REGISTERS,
+            stack: STACK.to_vec(),
+            ip: ENTRY,
+            code,
+            self_modified: false,
+            out: io::BufWriter::new(io::stdout()),
+            input: io::stdin().bytes(),
+        }
+    }
+
+    fn read(&self, addr: u16) -> Result<u16, String> {
+        match self.mem.get(addr as usize) {
+            Some(v) => Ok(*v),
+            None => Err(format!("read outside memory at {}", addr))
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u16) -> Result<(), String> {
+        if addr as usize >= self.mem.len() { return Err(format!("write outside memory at {}", addr)); }
+        if self.code[addr as usize] { self.self_modified = true; }
+        self.mem[addr as usize] = value;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<u16, String> {
+        self.stack.pop().ok_or_else(|| String::from("stack underflow"))
+    }
+
+    fn output(&mut self, c: u16) {
+        let _ = self.out.write_all(&[c as u8]);
+        if c == 10 { let _ = self.out.flush(); }
+    }
+
+    fn input(&mut self) -> Result<u16, String> {
+        let _ = self.out.flush();
+        match self.input.next() {
+            Some(Ok(b)) => Ok(b as u16),
+            _ => Err(String::from("end of input"))
+        }
+    }
+
+    /// The value of an instruction argument
+    fn arg(&self, word: u16) -> Result<u16, String> {
+        match word {
+            0..=32767 => Ok(word),
+            32768..=32775 => Ok(self.reg[word as usize - 32768]),
+            _ => Err(format!("invalid argument {} at {}", word, self.ip))
+        }
+    }
+
+    fn register(&self, word: u16) -> Result<usize, String> {
+        match word {
+            32768..=32775 => Ok(word as usize - 32768),
+            _ => Err(format!("expected a register, got {} at {}", word, self.ip))
+        }
+    }
+}
+
+/// Execute the single instruction at `s.ip` straight from memory
+fn interpret(s: &mut State) -> Flow {
+    let at = s.ip;
+    let w = |i: u16| s.mem.get(at as usize + i as usize).cloned().unwrap_or(0);
+    let (op, a, b, c) = (check!(s.read(at)), w(1), w(2), w(3));
+
+    match op {
+        0 => return Flow::Halt,
+        1 => { let r = check!(s.register(a)); s.reg[r] = check!(s.arg(b)); s.ip = at + 3; },
+        2 => { let v = check!(s.arg(a)); s.stack.push(v); s.ip = at + 2; },
+        3 => { let r = check!(s.register(a)); s.reg[r] = check!(s.pop()); s.ip = at + 2; },
+        4 | 5 | 9 | 10 | 11 | 12 | 13 => {
+            let r = check!(s.register(a));
+            let (x, y) = (check!(s.arg(b)) as u32, check!(s.arg(c)) as u32);
+            s.reg[r] = match op {
+                4 => (x == y) as u32,
+                5 => (x > y) as u32,
+                9 => (x + y) % 32768,
+                10 => (x * y) % 32768,
+                11 => x % y,
+                12 => x & y,
+                _ => x | y,
+            } as u16;
+            s.ip = at + 4;
+        },
+        6 => s.ip = check!(s.arg(a)),
+        7 => s.ip = if check!(s.arg(a)) != 0 { check!(s.arg(b)) } else { at + 3 },
+        8 => s.ip = if check!(s.arg(a)) == 0 { check!(s.arg(b)) } else { at + 3 },
+        14 => { let r = check!(s.register(a)); s.reg[r] = !check!(s.arg(b)) & 0x7fff; s.ip = at + 3; },
+        15 => { let r = check!(s.register(a)); let addr = check!(s.arg(b)); s.reg[r] = check!(s.read(addr)); s.ip = at + 3; },
+        16 => { let (addr, v) = (check!(s.arg(a)), check!(s.arg(b))); check!(s.write(addr, v)); s.ip = at + 3; },
+        17 => { let t = check!(s.arg(a)); s.stack.push(at + 2); s.ip = t; },
+        18 => match s.stack.pop() { Some(v) => s.ip = v, None => return Flow::Halt },
+        19 => { let v = check!(s.arg(a)); s.output(v); s.ip = at + 2; },
+        20 => {
+            let v = check!(s.input());
+            match s.register(a) {
+                Ok(r) => s.reg[r] = v,
+                Err(_) => check!(s.write(a, v))
+            }
+            s.ip = at + 2;
+        },
+        21 => s.ip = at + 1,
+        _ => return Flow::Fault(format!("bad opcode {} at {}", op, at))
+    }
+    Flow::Next
+}
+
+fn main() {
+    let mut s = State::new();
+    loop {
+        let flow = if s.self_modified { interpret(&mut s) } else { dispatch(&mut s) };
+        match flow {
+            Flow::Next => (),
+            Flow::Halt => break,
+            Flow::Fault(e) => {
+                let _ = s.out.flush();
+                eprintln!("fault: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let _ = s.out.flush();
+}
+"#;
+
+/// Merge the words covered by the blocks into ranges
+fn code_ranges(blocks: &[BasicBlock]) -> Vec<(u16, u16)> {
+    let mut ranges : Vec<(u16, u16)> = vec![];
+    for b in blocks {
+        match ranges.last_mut() {
+            Some(last) if last.1 == b.start.value() => { last.1 = b.end.value(); continue; },
+            _ => ()
+        }
+        ranges.push((b.start.value(), b.end.value()));
+    }
+    return ranges;
+}
+
+/// A crate which runs the program in `vm` natively: `(Cargo.toml, src/main.rs)`. The program
+/// starts from wherever the VM is, with its registers and stack, so code that only exists once the
+/// program has unpacked it can be translated from a VM which has already run that far.
+pub fn translate(vm: &VM, map: &CodeMap, symbols: &SymbolTable, name: &str) -> (String, String) {
+    let entry = vm.instruction_pointer();
+    let cargo = format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n\n# not part of any enclosing workspace\n[workspace]\n\n[profile.release]\nopt-level = 3\n", name);
+
+    let blocks = analyse(vm, entry, map);
+    let mut src = String::new();
+
+    writeln!(src, "//! Translated from a synacor binary, {} blocks lifted ahead of time. Anything else,", blocks.len()).unwrap();
+    writeln!(src, "//! and everything once the program writes over its own code, is interpreted.").unwrap();
+    writeln!(src, "#![allow(unreachable_code, unused_parens)]").unwrap();
+    src.push_str(RUNTIME);
+
+    writeln!(src, "\nconst ENTRY : u16 = {};", entry.value()).unwrap();
+    writeln!(src, "const REGISTERS : [u16; 8] = {:?};", vm.registers()).unwrap();
+    writeln!(src, "const STACK : &[u16] = &{:?};\n", vm.stack()).unwrap();
+    writeln!(src, "const CODE : &[(u16, u16)] = &{:?};\n", code_ranges(&blocks)).unwrap();
+
+    let memory = vm.snapshot().memory;
+    let used = memory.iter().rposition(|&v| v != 0).map_or(0, |i| i + 1);
+    writeln!(src, "const IMAGE : &[u16] = &[").unwrap();
+    for chunk in memory[..used].chunks(16) {
+        let words : Vec<String> = chunk.iter().map(|w| w.to_string()).collect();
+        writeln!(src, "    {},", words.join(", ")).unwrap();
+    }
+    writeln!(src, "];\n").unwrap();
+
+    writeln!(src, "fn dispatch(s: &mut State) -> Flow {{").unwrap();
+    writeln!(src, "    match s.ip {{").unwrap();
+    for b in &blocks {
+        writeln!(src, "        {} => {}(s),", b.start.value(), function_name(b.start)).unwrap();
+    }
+    writeln!(src, "        _ => interpret(s)").unwrap();
+    writeln!(src, "    }}\n}}\n").unwrap();
+
+    for b in &blocks {
+        src.push_str(&lift_block(b, symbols));
+        src.push('\n');
+    }
+
+    return (cargo, src);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use register::Register;
+    use device::{BufferInput, BufferOutput};
+    use constants::*;
+
+    // @0  SET R0 3
+    // @3  ADD R0 R0 32767
+    // @7  JT R0 3
+    // @10 CALL 13
+    // @12 HALT
+    // @13 OUT R0
+    // @15 RET
+    fn vm() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(3)),
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(32767)),
+            Instruction::JT(Argument::new(REGISTER_0), Argument::new(3)),
+            Instruction::CALL(Argument::new(13)),
+            Instruction::HALT,
+            Instruction::OUT(Argument::new(REGISTER_0)),
+            Instruction::RET
+        ]).unwrap();
+        return vm;
+    }
+
+    #[test]
+    fn splits_into_blocks() {
+        let blocks = analyse(&vm(), Address::new(0), &CodeMap::new());
+        let starts : Vec<u16> = blocks.iter().map(|b| b.start.value()).collect();
+
+        assert_eq!(starts, vec![0, 3, 10, 12, 13]);
+        assert_eq!(blocks[1].end, Address::new(10));
+        assert_eq!(code_ranges(&blocks), vec![(0, 16)]);
+    }
+
+    #[test]
+    fn map_entries_and_data() {
+        let map = CodeMap::parse("# a comment\ncode 13\ndata 10 12\n").unwrap();
+        assert_eq!(map.entries, vec![Address::new(13)]);
+
+        let blocks = analyse(&vm(), Address::new(0), &map);
+        let starts : Vec<u16> = blocks.iter().map(|b| b.start.value()).collect();
+        assert_eq!(starts, vec![0, 3, 13]);
+
+        assert!(CodeMap::parse("code").is_err());
+        assert!(CodeMap::parse("stuff 1 2").is_err());
+    }
+
+    #[test]
+    fn lifted_source() {
+        let blocks = analyse(&vm(), Address::new(0), &CodeMap::new());
+        let mut symbols = SymbolTable::new();
+        symbols.insert("loop", Address::new(3));
+
+        assert_eq!(lift_block(&blocks[1], &symbols), "\
+/// @3 <loop>
+fn block_3(s: &mut State) -> Flow {
+    s.reg[0] = (s.reg[0] + 32767) % 32768; // @3: ADD R0 R0 32767
+    if s.reg[0] != 0 { s.ip = 3; return Flow::Next; } // @7: JT R0 3
+    s.ip = 10;
+    Flow::Next
+}
+");
+        assert!(lift_block(&blocks[2], &symbols).contains("s.stack.push(12); s.ip = 13; return Flow::Next;"));
+    }
+}
@@ -0,0 +1,105 @@
+extern crate synacor;
+extern crate clap;
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use clap::{Arg, App};
+use synacor::binary::Binary;
+use synacor::vm::VM;
+use synacor::address::Address;
+use synacor::symbols::SymbolTable;
+use synacor::snapshot::Snapshot;
+use synacor::aot::{self, CodeMap};
+
+/// Something cargo will accept as a package name
+fn crate_name(bin_path: &str) -> String {
+    let stem = Path::new(bin_path).file_stem().and_then(|s| s.to_str()).unwrap_or("program");
+    let name : String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) { format!("syn_{}", name) } else { name }
+}
+
+fn main() {
+    let args = App::new("syn-aot")
+        .version("v0.1.0")
+        .author("Joe Fredette <jfredett.at.gmail.dot.com>")
+        .about("Translate a synacor binary into a standalone Rust crate")
+        .arg(Arg::with_name("bin")
+                 .short("b")
+                 .long("bin")
+                 .value_name("FILE")
+                 .help("Path to the .bin to translate")
+                 .takes_value(true))
+        .arg(Arg::with_name("load")
+                 .long("load")
+                 .value_name("FILE")
+                 .help("Translate a snapshot instead of a .bin, picking up where it left off")
+                 .takes_value(true))
+        .arg(Arg::with_name("out")
+                 .short("o")
+                 .long("out")
+                 .value_name("DIR")
+                 .help("Directory to write the crate to")
+                 .takes_value(true)
+                 .required(true))
+        .arg(Arg::with_name("map")
+                 .long("map")
+                 .value_name("FILE")
+                 .help("File of `code ADDRESS' and `data START END' lines, for code we can't find by following jumps")
+                 .takes_value(true))
+        .arg(Arg::with_name("symbols")
+                 .long("symbols")
+                 .value_name("FILE")
+                 .help("File of `ADDRESS NAME' lines, to label the generated functions")
+                 .takes_value(true))
+        .arg(Arg::with_name("entry")
+                 .long("entry")
+                 .value_name("ADDRESS")
+                 .help("Where the program starts, defaults to 0. Ignored with --load")
+                 .takes_value(true))
+        .get_matches();
+
+    let out = Path::new(args.value_of("out").unwrap());
+    let entry = args.value_of("entry").unwrap_or("0").parse::<u16>().expect("--entry must be an address");
+
+    let map = match args.value_of("map") {
+        Some(path) => CodeMap::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => CodeMap::new()
+    };
+    let symbols = match args.value_of("symbols") {
+        Some(path) => SymbolTable::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => SymbolTable::new()
+    };
+
+    let mut vm = VM::init();
+    let source = if let Some(snapshot_path) = args.value_of("load") {
+        let snapshot = Snapshot::load(snapshot_path).unwrap_or_else(|e| panic!("Could not load snapshot `{}': {}", snapshot_path, e));
+        if let Err(e) = vm.restore(&snapshot) {
+            panic!("Could not restore snapshot `{}': {}", snapshot_path, e);
+        }
+        snapshot_path
+    } else {
+        let bin_path = args.value_of("bin").expect("Must provide ``--bin FILE'' or ``--load FILE''");
+        let mut b = Binary::new(&String::from(bin_path));
+        b.parse();
+        if let Err(e) = vm.load_program(Address::new(0), b.binary()) {
+            panic!("Could not load `{}': {}", bin_path, e);
+        }
+        vm.start(Address::new(entry));
+        bin_path
+    };
+
+    let name = crate_name(source);
+    let (cargo, main) = aot::translate(&vm, &map, &symbols, &name);
+
+    let written = fs::create_dir_all(out.join("src"))
+        .and_then(|_| fs::write(out.join("Cargo.toml"), cargo))
+        .and_then(|_| fs::write(out.join("src").join("main.rs"), main));
+    if let Err(e) = written {
+        eprintln!("Could not write the crate to `{}': {}", out.display(), e);
+        process::exit(1);
+    }
+
+    println!("Wrote crate `{}' to `{}', build it with `cargo build --release'", name, out.display());
+}
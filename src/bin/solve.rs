@@ -0,0 +1,86 @@
+extern crate synacor;
+extern crate clap;
+
+use std::process;
+use std::thread;
+
+use clap::{Arg, App, SubCommand};
+use synacor::binary::Binary;
+use synacor::vm::VM;
+use synacor::address::Address;
+use synacor::teleporter;
+
+fn teleporter(bin_path: &str, threads: usize) {
+    let mut b = Binary::new(&String::from(bin_path));
+    b.parse();
+
+    let mut vm = VM::init();
+    if let Err(e) = vm.load_program(Address::new(0), b.binary()) {
+        panic!("Could not load `{}': {}", bin_path, e);
+    }
+
+    let check = match teleporter::locate(&vm) {
+        Some(c) => c,
+        None => {
+            eprintln!("Couldn't find the teleporter confirmation routine in `{}'", bin_path);
+            process::exit(1);
+        }
+    };
+
+    println!("Confirmation routine at {}, called from {} with R0 = {}, R1 = {}, expecting {}",
+             check.routine, check.call_site, check.a, check.b, check.expected);
+
+    if !check.verify(&vm) {
+        eprintln!("The routine at {} doesn't behave like the one we know how to solve", check.routine);
+        process::exit(1);
+    }
+
+    println!("Searching all values of R7 on {} threads", threads);
+    let found = check.search(threads);
+    if found.is_empty() {
+        eprintln!("No value of R7 passes the check");
+        process::exit(1);
+    }
+
+    for r7 in &found {
+        println!("R7 = {}", r7);
+    }
+
+    let words : Vec<String> = check.patch().iter().map(|w| w.to_string()).collect();
+    println!("Patch {}: {}", check.routine, words.join(" "));
+}
+
+fn main() {
+    let default_threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(4).to_string();
+
+    let args = App::new("syn-solve")
+        .version("v0.1.0")
+        .author("Joe Fredette <jfredett.at.gmail.dot.com>")
+        .about("Solve the harder puzzles in a synacor binary")
+        .subcommand(SubCommand::with_name("teleporter")
+                    .about("Find the value of R7 the teleporter accepts, and how to skip its check")
+                    .arg(Arg::with_name("bin")
+                             .short("b")
+                             .long("bin")
+                             .value_name("FILE")
+                             .help("Path to the .bin containing the teleporter")
+                             .default_value("data/challenge.bin"))
+                    .arg(Arg::with_name("threads")
+                             .short("j")
+                             .long("threads")
+                             .value_name("N")
+                             .help("How many threads to search on")
+                             .default_value(&default_threads)))
+        .get_matches();
+
+    match args.subcommand() {
+        ("teleporter", Some(sub)) => {
+            let threads = sub.value_of("threads").unwrap().parse::<usize>().expect("--threads must be a number");
+            teleporter(sub.value_of("bin").unwrap(), threads);
+        },
+        _ => {
+            eprintln!("{}", args.usage());
+            process::exit(1);
+        }
+    }
+}
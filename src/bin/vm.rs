@@ -1,13 +1,29 @@
 extern crate synacor;
 extern crate clap;
 
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::process;
 use std::str::FromStr;
 
 use clap::{Arg, App};
 
 use synacor::binary::Binary;
-use synacor::vm::VM;
+use synacor::vm::{VM, VMState, VMError};
+use synacor::fault::VMFault;
 use synacor::address::Address;
+use synacor::snapshot::Snapshot;
+use synacor::controls::{Controls, Reply, Stop};
+use synacor::session::{Session, Recorder, Replayer};
+use synacor::script::{InputScript, ScriptLine};
+use synacor::blocks::Engine;
+use synacor::symbols::SymbolTable;
+use synacor::debugger::{Debugger, Command};
+use synacor::trace::{TraceFormat, TraceFilter};
+use synacor::profile::Profiler;
+use synacor::livelock::LoopDetector;
+use synacor::config::VMConfig;
 
 
 fn parse_as<T : FromStr>(input: &String) -> T {
@@ -18,12 +34,170 @@ fn parse_as<T : FromStr>(input: &String) -> T {
   }
 }
 
+/// Parse a `START:END` address range
+fn parse_range(s: &str) -> (Address, Address) {
+    let mut parts = s.splitn(2, ':');
+    let start = parse_as::<u16>(&String::from(parts.next().unwrap_or("")));
+    let end = parse_as::<u16>(&String::from(parts.next().expect("Trace range should look like START:END")));
+    (Address::new(start), Address::new(end))
+}
+
+/// The status syn-vm exits with when the program stops with `e`
+fn exit_code(e: &VMError) -> i32 {
+    match *e {
+        VMError::EndOfInput => 2,
+        VMError::DeviceError(_) => 3,
+        VMError::BadOpcode(_) => 4,
+        VMError::MalformedInstruction(_) => 5,
+        VMError::InvalidMemoryAccess(_) => 6,
+        VMError::JumpOutOfBounds(_) => 7,
+        VMError::StackUnderflow => 8,
+        VMError::InvalidValue(_) => 9,
+        VMError::InvalidCharacterArgument(_) => 10,
+        VMError::UnknownError => 11,
+        VMError::Livelock(_) => 12
+    }
+}
+
+/// Where `play` gets lines for the program: a script first, if there is one, then stdin if
+/// we're interactive
+struct Lines {
+    script: Option<InputScript>,
+    echo: bool,
+    interactive: bool,
+}
+
+impl Lines {
+    /// The next line for the program, carrying out any script directives on the way, or `None`
+    /// once there's nothing left to read
+    fn next(&mut self, vm: &mut VM) -> Result<Option<String>, VMError> {
+        let stdin = io::stdin();
+
+        if let Some(ref mut script) = self.script {
+            for line in script {
+                match line {
+                    ScriptLine::Input(line) => {
+                        if self.echo { println!("{}", line); }
+                        return Ok(Some(line));
+                    },
+                    ScriptLine::Pause => {
+                        print!("[paused, press enter to continue]");
+                        let _ = io::stdout().flush();
+                        let _ = stdin.lock().read_line(&mut String::new());
+                    },
+                    ScriptLine::Snapshot(path) => match vm.snapshot().save(&path) {
+                        Ok(()) => println!("[saved `{}']", path),
+                        Err(e) => println!("[could not save `{}': {}]", path, e)
+                    }
+                }
+            }
+        }
+
+        if !self.interactive { return Ok(None); }
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => Err(VMError::EndOfInput),
+            Ok(_) => Ok(Some(line)),
+            Err(e) => Err(VMError::DeviceError(e.to_string()))
+        }
+    }
+}
+
+/// Drive the VM, feeding it `lines` as it asks for them and handing meta-commands to
+/// `controls`. Stops with `BudgetExhausted` at step `stop_at`, and with `AwaitingInput` once the
+/// lines run out. Breakpoints drop into the debugger until the player quits it.
+fn play(vm: &mut VM, controls: &mut Controls, lines: &mut Lines) -> Result<VMState, VMFault> {
+    loop {
+        let result = match controls.resume(vm) {
+            Ok(Stop::Breakpoint(a)) => {
+                print!("{}", vm.drain_output());
+                println!("Breakpoint at {}, type `quit' to go back to playing", controls.debugger().label(a));
+                debug(vm, controls.debugger());
+                continue;
+            },
+            Ok(Stop::State(state)) => Ok(state),
+            Err(e) => Err(e)
+        };
+        print!("{}", vm.drain_output());
+        let _ = io::stdout().flush();
+
+        match result {
+            Ok(VMState::AwaitingInput) => {
+                let line = match lines.next(vm) {
+                    Ok(Some(line)) => line,
+                    Ok(None) => return result,
+                    Err(e) => return Err(vm.fault(e, vm.instruction_pointer()))
+                };
+
+                if controls.is_meta(&line) {
+                    match controls.execute(vm, line.trim()) {
+                        Reply::Message(message) => println!("{}", message),
+                        Reply::Debug(message) => {
+                            println!("{}", message);
+                            debug(vm, controls.debugger());
+                        }
+                    }
+                } else {
+                    controls.remember(&line, vm.snapshot());
+                    vm.push_input(&format!("{}\n", line.trim_end_matches('\n')));
+                }
+            },
+            other => return other
+        }
+    }
+}
+
+/// Run the VM under the debugger, reading commands from stdin. An empty line repeats the last
+/// command.
+fn debug(vm: &mut VM, debugger: &mut Debugger) {
+    let stdin = io::stdin();
+    let mut last = None;
+
+    println!("{}", debugger.location(vm));
+
+    loop {
+        print!("(syn-db) ");
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return,
+            Ok(_) => ()
+        }
+
+        let command = if line.trim().is_empty() {
+            match last.clone() {
+                Some(c) => Ok(c),
+                None => continue
+            }
+        } else {
+            Command::parse(&line)
+        };
+
+        match command {
+            Ok(Command::Quit) => return,
+            Ok(c) => {
+                last = Some(c.clone());
+                match debugger.execute(vm, c) {
+                    Ok(out) => println!("{}", out),
+                    Err(e) => println!("{}", e)
+                }
+            },
+            Err(e) => println!("{}", e)
+        }
+    }
+}
 
 fn main() {
     let args = App::new("syn-vm")
         .version("v0.1.0")
         .author("Joe Fredette <jfredett.at.gmail.dot.com>")
         .about("Run programs on the synacor vm")
+        .after_help("Exits with 0 on halt; on a fault: 2 input ran out, 3 device error, \
+                     4 bad opcode, 5 malformed instruction, 6 invalid memory access, 7 jump out of bounds, \
+                     8 stack underflow, 9 invalid value, 10 invalid character, 11 unknown error, 12 stuck in a loop; \
+                     13 when a replay did not match its recording")
         .arg(Arg::with_name("bin")
                  .short("b")
                  .long("bin")
@@ -35,31 +209,278 @@ fn main() {
                  .long("offset")
                  .help("Where to start the program")
                  .takes_value(true))
+        .arg(Arg::with_name("load")
+                 .long("load")
+                 .value_name("FILE")
+                 .help("Resume from a snapshot instead of loading a .bin")
+                 .takes_value(true))
+        .arg(Arg::with_name("save")
+                 .long("save")
+                 .value_name("FILE")
+                 .help("Write a snapshot of the VM here when the program stops")
+                 .takes_value(true))
+        .arg(Arg::with_name("debug")
+                 .short("d")
+                 .long("debug")
+                 .help("Start in the debugger instead of running the program"))
+        .arg(Arg::with_name("symbols")
+                 .long("symbols")
+                 .value_name("FILE")
+                 .help("File of `ADDRESS NAME' lines, for labels in the debugger")
+                 .takes_value(true))
+        .arg(Arg::with_name("trace")
+                 .long("trace")
+                 .value_name("FILE")
+                 .help("Record every executed instruction to FILE")
+                 .takes_value(true))
+        .arg(Arg::with_name("trace-format")
+                 .long("trace-format")
+                 .value_name("FORMAT")
+                 .help("text (default), jsonl or binary")
+                 .takes_value(true))
+        .arg(Arg::with_name("trace-range")
+                 .long("trace-range")
+                 .value_name("START:END")
+                 .help("Only trace instructions between these addresses")
+                 .takes_value(true))
+        .arg(Arg::with_name("trace-depth")
+                 .long("trace-depth")
+                 .value_name("N")
+                 .help("Only trace instructions at most N calls deep")
+                 .takes_value(true))
+        .arg(Arg::with_name("engine")
+                 .long("engine")
+                 .value_name("ENGINE")
+                 .help("interpreter (default), or blocks to compile straight-line code into blocks")
+                 .takes_value(true))
+        .arg(Arg::with_name("strictness")
+                 .long("strictness")
+                 .value_name("PRESET")
+                 .help("How to treat the corners of the spec: strict, lenient, or legacy (default)")
+                 .takes_value(true))
+        .arg(Arg::with_name("profile")
+                 .long("profile")
+                 .value_name("FILE")
+                 .help("Count executed instructions, writing collapsed call stacks for flamegraph tools to FILE and a report of hot spots when the program stops")
+                 .takes_value(true))
+        .arg(Arg::with_name("profile-top")
+                 .long("profile-top")
+                 .value_name("N")
+                 .help("How many entries to show in each table of the profile report, defaults to 20")
+                 .takes_value(true))
+        .arg(Arg::with_name("detect-loops")
+                 .long("detect-loops")
+                 .help("Stop the program if it comes back round to exactly the same state"))
+        .arg(Arg::with_name("no-progress")
+                 .long("no-progress")
+                 .value_name("STEPS")
+                 .help("Stop the program if it runs this many steps without changing memory, reading input or printing anything")
+                 .takes_value(true))
+        .arg(Arg::with_name("record")
+                 .long("record")
+                 .value_name("FILE")
+                 .help("Record every byte of input, with the step it was read at, and all output to a session FILE")
+                 .takes_value(true))
+        .arg(Arg::with_name("replay")
+                 .long("replay")
+                 .value_name("FILE")
+                 .help("Feed the program the input recorded in a session FILE, checking it does exactly the same")
+                 .takes_value(true))
+        .arg(Arg::with_name("stop-at")
+                 .long("stop-at")
+                 .value_name("STEP")
+                 .help("Stop the program once it has run this many steps")
+                 .conflicts_with("trace")
+                 .takes_value(true))
+        .arg(Arg::with_name("input-script")
+                 .long("input-script")
+                 .value_name("FILE")
+                 .help("Play the lines in FILE before anything else, skipping blanks and `#' comments and obeying `#pause' and `#snapshot NAME'")
+                 .conflicts_with("replay")
+                 .takes_value(true))
+        .arg(Arg::with_name("then-interactive")
+                 .long("then-interactive")
+                 .help("Read input from stdin once the input script is done")
+                 .requires("input-script"))
+        .arg(Arg::with_name("meta-prefix")
+                 .long("meta-prefix")
+                 .value_name("PREFIX")
+                 .help("Lines of input starting with this are commands for syn-vm rather than the program, `!' by default. Try `!help'")
+                 .takes_value(true))
+        .arg(Arg::with_name("echo")
+                 .long("echo")
+                 .help("Print each line of the input script as it's played")
+                 .requires("input-script"))
         .get_matches();
 
 
-    let bin_path = String::from(args.value_of("bin").expect("Must provide ``--bin FILE''"));
-    let offset = parse_as::<u16>(&String::from(args.value_of("offset").unwrap_or("0")));
-    let mut b = Binary::new(&bin_path);
-
-    println!("Parsing `{}'", bin_path);
-    b.parse();
-
     println!("Initializing VM");
     let mut vm = VM::init();
 
-    println!("Loading Program: `{}'", bin_path);
-    vm.load_program(Address::new(0), b.binary());
+    if let Some(engine) = args.value_of("engine") {
+        vm.set_engine(engine.parse::<Engine>().unwrap_or_else(|e| panic!("{}", e)));
+    }
 
-    println!("Running...");
-    println!("");
+    if let Some(strictness) = args.value_of("strictness") {
+        vm.set_config(strictness.parse::<VMConfig>().unwrap_or_else(|e| panic!("{}", e)));
+    }
+
+    if let Some(snapshot_path) = args.value_of("load") {
+        println!("Loading Snapshot: `{}'", snapshot_path);
+        let snapshot = match Snapshot::load(snapshot_path) {
+            Ok(s) => s,
+            Err(e) => panic!("Could not load snapshot `{}': {}", snapshot_path, e)
+        };
+        if let Err(e) = vm.restore(&snapshot) {
+            panic!("Could not restore snapshot `{}': {}", snapshot_path, e);
+        }
+    } else {
+        let bin_path = String::from(args.value_of("bin").expect("Must provide ``--bin FILE'' or ``--load FILE''"));
+        let offset = parse_as::<u16>(&String::from(args.value_of("offset").unwrap_or("0")));
+        let mut b = Binary::new(&bin_path);
+
+        println!("Parsing `{}'", bin_path);
+        b.parse();
+
+        println!("Loading Program: `{}'", bin_path);
+        if let Err(e) = vm.load_program(Address::new(0), b.binary()) {
+            panic!("Could not load `{}': {}", bin_path, e);
+        }
+        vm.start(Address::new(offset));
+    }
+
+    let symbols = match args.value_of("symbols") {
+        Some(path) => SymbolTable::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => SymbolTable::new()
+    };
 
-    match vm.run(Address::new(offset)) {
-        Ok(state) => println!("SUCCESS: Program Finished with: {:?}", state),
-        Err(e) => println!("ERROR: Program Finished with: {:?}", e),
+    let no_progress = args.value_of("no-progress").map(|n| parse_as::<u64>(&String::from(n)));
+    if args.is_present("detect-loops") || no_progress.is_some() {
+        let mut detector = LoopDetector::new();
+        if !args.is_present("detect-loops") { detector = detector.without_repeated_state(); }
+        if let Some(n) = no_progress { detector = detector.with_no_progress_limit(n); }
+        vm.set_loop_detector(Some(detector));
+    }
+
+    let mut status = 0;
+    if args.is_present("debug") {
+        println!("Debugging, type `help' for commands");
+        println!("");
+
+        debug(&mut vm, &mut Debugger::new(symbols));
+    } else {
+        println!("Running...");
+        println!("");
+
+        let trace_format = args.value_of("trace-format").unwrap_or("text").parse::<TraceFormat>().unwrap_or_else(|e| panic!("{}", e));
+        let trace_filter = TraceFilter {
+            range: args.value_of("trace-range").map(parse_range),
+            max_depth: args.value_of("trace-depth").map(|d| parse_as::<usize>(&String::from(d))),
+        };
+        let mut controls = Controls::new(args.value_of("meta-prefix").unwrap_or("!"), Debugger::new(symbols.clone()), trace_format, trace_filter);
+        if let Some(path) = args.value_of("trace") {
+            controls.start_trace(path).unwrap_or_else(|e| panic!("Could not create trace `{}': {}", path, e));
+        }
+
+        let profile = args.value_of("profile").map(|_| {
+            let profiler = Profiler::new();
+            let profile = profiler.profile();
+            vm.add_observer(Box::new(profiler));
+            profile
+        });
+
+        let recording = args.value_of("record").map(|_| {
+            let recorder = Recorder::new(&vm);
+            let session = recorder.session();
+            vm.add_observer(Box::new(recorder));
+            session
+        });
+
+        let replay = args.value_of("replay").map(|path| {
+            let session = Session::load(path).unwrap_or_else(|e| panic!("Could not load session `{}': {}", path, e));
+            if !session.starts_from(&vm) {
+                println!("ERROR: `{}' was recorded from a different starting state", path);
+                process::exit(13);
+            }
+            let replayer = Replayer::new(session.clone());
+            let progress = replayer.status();
+            vm.add_observer(Box::new(replayer));
+            (session, progress)
+        });
+
+        let stop_at = args.value_of("stop-at").map(|n| parse_as::<u64>(&String::from(n)));
+        controls.set_stop_at(stop_at);
+        controls.set_recording(recording.is_some());
+
+        let script = args.value_of("input-script").map(|path| InputScript::load(path).unwrap_or_else(|e| panic!("{}", e)));
+        let mut lines = Lines {
+            interactive: if script.is_some() { args.is_present("then-interactive") } else { replay.is_none() },
+            script,
+            echo: args.is_present("echo"),
+        };
+
+        match play(&mut vm, &mut controls, &mut lines) {
+            Ok(VMState::BudgetExhausted) => println!("Stopped at step {}", vm.step_count()),
+            Ok(VMState::AwaitingInput) => println!("Out of input at step {}, the program is waiting for more", vm.step_count()),
+            Ok(state) => println!("SUCCESS: Program Finished with: {:?}", state),
+            Err(VMFault { error: VMError::EndOfInput, .. }) => {
+                println!("Program Finished: ran out of input");
+                status = exit_code(&VMError::EndOfInput);
+            },
+            Err(fault) => {
+                println!("ERROR: Program crashed");
+                println!("{}", fault.report(&symbols));
+                println!("Backtrace:");
+                println!("{}", vm.call_stack().backtrace(fault.context.address, &symbols));
+                status = exit_code(&fault.error);
+            }
+        }
+
+        if let (Some(session), Some(path)) = (recording, args.value_of("record")) {
+            let session = session.lock().unwrap();
+            match session.save(path) {
+                Ok(()) => println!("Recorded {} steps to `{}'", session.steps, path),
+                Err(e) => println!("Could not save session to `{}': {}", path, e)
+            }
+        }
+
+        if let Some((session, progress)) = replay {
+            let progress = progress.lock().unwrap();
+            if let Some(divergence) = progress.divergence {
+                println!("REPLAY DIVERGED: {}", divergence);
+                status = 13;
+            } else if progress.matches(&session) {
+                println!("Replay matched all {} steps", session.steps);
+            } else if stop_at.is_some() && progress.step < session.steps {
+                println!("Replay matched the first {} of {} steps", progress.step, session.steps);
+            } else {
+                println!("REPLAY DIVERGED: ran {} of {} steps, used {} of {} input bytes and matched {} of {} output bytes",
+                         progress.step, session.steps, progress.input_used, session.input.len(), progress.output_matched, session.output.len());
+                status = 13;
+            }
+        }
+
+        if let (Some(profile), Some(path)) = (profile, args.value_of("profile")) {
+            let profile = profile.snapshot();
+            let top = parse_as::<usize>(&String::from(args.value_of("profile-top").unwrap_or("20")));
+
+            println!("");
+            print!("{}", profile.report(&symbols, top));
+            match File::create(path).and_then(|mut f| profile.write_collapsed(&mut f, &symbols)) {
+                Ok(()) => println!("Wrote call stacks to `{}'", path),
+                Err(e) => println!("Could not write call stacks to `{}': {}", path, e)
+            }
+        }
+    }
+
+    if let Some(snapshot_path) = args.value_of("save") {
+        match vm.snapshot().save(snapshot_path) {
+            Ok(()) => println!("Saved snapshot to `{}'", snapshot_path),
+            Err(e) => println!("Could not save snapshot to `{}': {}", snapshot_path, e)
+        }
     }
 
     println!("");
     println!("Ended on instruction: {}", vm.instruction_pointer());
+    process::exit(status);
 }
-
@@ -0,0 +1,254 @@
+use std::str::FromStr;
+
+use address::Address;
+use argument::Argument;
+use instruction::Instruction;
+use vm::VM;
+
+/// The longest run of instructions we'll put in one block
+pub const MAX_BLOCK_LENGTH : usize = 64;
+
+/// How the VM executes instructions
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Engine {
+    /// Decode and execute one instruction at a time
+    Interpreter,
+    /// Translate straight-line code into blocks and execute a block at a time, falling back to
+    /// the interpreter for anything with side effects
+    Blocks
+}
+
+impl FromStr for Engine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Engine, String> {
+        match s {
+            "interpreter" | "interp" => Ok(Engine::Interpreter),
+            "blocks" => Ok(Engine::Blocks),
+            _ => Err(format!("Unknown engine `{}', expected interpreter or blocks", s))
+        }
+    }
+}
+
+/// An operand with the register lookup already worked out
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Src {
+    Lit(u16),
+    Reg(usize)
+}
+
+impl Src {
+    fn from(a: Argument) -> Src {
+        match a {
+            Argument::Literal(v) => Src::Lit(v.0),
+            Argument::Register(r) => Src::Reg(r.as_index())
+        }
+    }
+}
+
+/// A straight-line instruction, with registers resolved to indices
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Op {
+    Set(usize, Src),
+    Eq(usize, Src, Src),
+    Gt(usize, Src, Src),
+    Add(usize, Src, Src),
+    Mult(usize, Src, Src),
+    Mod(usize, Src, Src),
+    And(usize, Src, Src),
+    Or(usize, Src, Src),
+    Not(usize, Src),
+    Push(Src),
+    /// the register, and the address after the `POP` in case the stack is empty
+    Pop(usize, Address),
+    Noop
+}
+
+/// How a block ends
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Exit {
+    Jmp(Src),
+    Jt(Src, Src),
+    Jf(Src, Src),
+    /// target, the address of the `CALL` itself, and the return address
+    Call(Src, Address, Address),
+    Ret,
+    /// Superinstruction for `EQ`/`GT` into a register, then `JT`/`JF` on it: the compare, whether
+    /// to jump when it's true, and the target
+    CompareBranch(Op, bool, Src),
+    /// Hand the instruction at this address to the interpreter
+    Step(Address)
+}
+
+impl Exit {
+    /// How many words the instruction ending the block takes up
+    pub fn size(&self) -> u16 {
+        match *self {
+            Exit::Ret => 1,
+            Exit::Jmp(_) | Exit::Call(_, _, _) => 2,
+            Exit::Jt(_, _) | Exit::Jf(_, _) | Exit::CompareBranch(_, _, _) => 3,
+            Exit::Step(_) => 0
+        }
+    }
+}
+
+/// A run of straight-line instructions and whatever ends it
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Block {
+    pub start: Address,
+    /// the address just past the last word of the block, including its exit
+    pub end: Address,
+    pub ops: Vec<Op>,
+    pub exit: Exit,
+    /// how many guest instructions running the whole block amounts to
+    pub length: u64,
+}
+
+fn translate(instruction: &Instruction, next: Address) -> Option<Op> {
+    match *instruction {
+        Instruction::SET(r, a) => Some(Op::Set(r.as_index(), Src::from(a))),
+        Instruction::EQ(r, a, b) => Some(Op::Eq(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::GT(r, a, b) => Some(Op::Gt(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::ADD(r, a, b) => Some(Op::Add(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::MULT(r, a, b) => Some(Op::Mult(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::MOD(r, a, b) => Some(Op::Mod(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::AND(r, a, b) => Some(Op::And(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::OR(r, a, b) => Some(Op::Or(r.as_index(), Src::from(a), Src::from(b))),
+        Instruction::NOT(r, a) => Some(Op::Not(r.as_index(), Src::from(a))),
+        Instruction::PUSH(a) => Some(Op::Push(Src::from(a))),
+        Instruction::POP(r) => Some(Op::Pop(r.as_index(), next)),
+        Instruction::NOOP => Some(Op::Noop),
+        _ => None
+    }
+}
+
+impl Block {
+    /// Translate the code at `start` into a block. Anything which touches memory or the devices,
+    /// or doesn't decode, ends the block and is left for the interpreter, so a block never
+    /// changes the code it was made from.
+    pub fn compile(vm: &VM, start: Address) -> Block {
+        let mut ops = vec![];
+        let mut addr = start;
+
+        let exit = loop {
+            if ops.len() == MAX_BLOCK_LENGTH { break Exit::Step(addr); }
+
+            let instruction = match vm.decode_at(addr) {
+                Ok(i) => i,
+                Err(_) => break Exit::Step(addr)
+            };
+            let here = addr;
+            let next = Address::new(addr.value() + instruction.size());
+
+            if let Some(op) = translate(&instruction, next) {
+                ops.push(op);
+                addr = next;
+                continue;
+            }
+
+            addr = next;
+            break match instruction {
+                Instruction::JMP(a) => Exit::Jmp(Src::from(a)),
+                Instruction::JT(c, t) => Exit::Jt(Src::from(c), Src::from(t)),
+                Instruction::JF(c, t) => Exit::Jf(Src::from(c), Src::from(t)),
+                Instruction::CALL(a) => Exit::Call(Src::from(a), here, next),
+                Instruction::RET => Exit::Ret,
+                _ => { addr = here; Exit::Step(here) }
+            };
+        };
+
+        // counted before fusing, a compare and branch is still two instructions
+        let length = match exit {
+            Exit::Step(_) => ops.len() as u64,
+            _ => ops.len() as u64 + 1
+        };
+        let exit = fuse(&mut ops, exit);
+
+        Block { start, end: addr, ops, exit, length }
+    }
+}
+
+/// Fold a compare into the conditional jump that tests its result
+fn fuse(ops: &mut Vec<Op>, exit: Exit) -> Exit {
+    let (condition, jump_if, target) = match exit {
+        Exit::Jt(Src::Reg(c), t) => (c, true, t),
+        Exit::Jf(Src::Reg(c), t) => (c, false, t),
+        _ => return exit
+    };
+
+    match ops.last().cloned() {
+        Some(op @ Op::Eq(r, _, _)) | Some(op @ Op::Gt(r, _, _)) if r == condition => {
+            ops.pop();
+            Exit::CompareBranch(op, jump_if, target)
+        },
+        _ => exit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use register::Register;
+    use device::{BufferInput, BufferOutput};
+    use constants::*;
+
+    fn vm(program: Vec<Instruction>) -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &program).unwrap();
+        return vm;
+    }
+
+    #[test]
+    fn straight_line_until_a_jump() {
+        let vm = vm(vec![
+            Instruction::SET(Register::R0, Argument::new(5)),
+            Instruction::ADD(Register::R1, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::JMP(Argument::new(0)),
+            Instruction::HALT
+        ]);
+        let b = Block::compile(&vm, Address::new(0));
+
+        assert_eq!(b.ops, vec![Op::Set(0, Src::Lit(5)), Op::Add(1, Src::Reg(0), Src::Lit(1))]);
+        assert_eq!(b.exit, Exit::Jmp(Src::Lit(0)));
+        assert_eq!(b.end, Address::new(9));
+        assert_eq!(b.length, 3);
+    }
+
+    #[test]
+    fn side_effects_are_left_to_the_interpreter() {
+        let vm = vm(vec![
+            Instruction::PUSH(Argument::new(1)),
+            Instruction::WMEM(Argument::new(100), Argument::new(1)),
+        ]);
+
+        let b = Block::compile(&vm, Address::new(0));
+        assert_eq!(b.ops, vec![Op::Push(Src::Lit(1))]);
+        assert_eq!(b.exit, Exit::Step(Address::new(2)));
+        assert_eq!(b.length, 1);
+
+        let b = Block::compile(&vm, Address::new(2));
+        assert!(b.ops.is_empty());
+        assert_eq!(b.exit, Exit::Step(Address::new(2)));
+        assert_eq!(b.length, 0);
+    }
+
+    #[test]
+    fn compare_and_branch_are_fused() {
+        let vm = vm(vec![
+            Instruction::EQ(Register::R1, Argument::new(REGISTER_0), Argument::new(6)),
+            Instruction::JF(Argument::new(REGISTER_1), Argument::new(0)),
+        ]);
+        let b = Block::compile(&vm, Address::new(0));
+
+        assert!(b.ops.is_empty());
+        assert_eq!(b.exit, Exit::CompareBranch(Op::Eq(1, Src::Reg(0), Src::Lit(6)), false, Src::Lit(0)));
+        assert_eq!(b.length, 2);
+    }
+
+    #[test]
+    fn engine_names() {
+        assert_eq!("blocks".parse(), Ok(Engine::Blocks));
+        assert_eq!("interp".parse(), Ok(Engine::Interpreter));
+        assert!("jit".parse::<Engine>().is_err());
+    }
+}
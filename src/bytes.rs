@@ -0,0 +1,86 @@
+//! Little endian reads and writes, shared by the snapshot, trace and session file formats
+
+use std::io;
+use std::io::prelude::*;
+
+pub fn write_u16<W: Write + ?Sized>(w: &mut W, v: u16) -> io::Result<()> {
+    w.write_all(&[(v & 0xff) as u8, (v >> 8) as u8])
+}
+
+pub fn write_u32<W: Write + ?Sized>(w: &mut W, v: u32) -> io::Result<()> {
+    write_u16(w, (v & 0xffff) as u16)?;
+    write_u16(w, (v >> 16) as u16)
+}
+
+pub fn write_u64<W: Write + ?Sized>(w: &mut W, v: u64) -> io::Result<()> {
+    write_u32(w, (v & 0xffff_ffff) as u32)?;
+    write_u32(w, (v >> 32) as u32)
+}
+
+pub fn read_u16<R: Read + ?Sized>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(((buf[1] as u16) << 8) | (buf[0] as u16))
+}
+
+pub fn read_u32<R: Read + ?Sized>(r: &mut R) -> io::Result<u32> {
+    let low = read_u16(r)? as u32;
+    let high = read_u16(r)? as u32;
+    Ok((high << 16) | low)
+}
+
+pub fn read_u64<R: Read + ?Sized>(r: &mut R) -> io::Result<u64> {
+    let low = read_u32(r)? as u64;
+    let high = read_u32(r)? as u64;
+    Ok((high << 32) | low)
+}
+
+/// Like `read_u64`, but `None` if the input ends before the first byte, for files made of
+/// records back to back. Ending part way through is still an error.
+pub fn read_u64_or_eof<R: Read + ?Sized>(r: &mut R) -> io::Result<Option<u64>> {
+    let mut buf = [0u8; 8];
+    let mut filled = 0;
+    while filled < buf.len() {
+        match r.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "ended part way through a u64")),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e)
+        }
+    }
+    read_u64(&mut &buf[..]).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = vec![];
+        write_u16(&mut bytes, 0x1234).unwrap();
+        write_u32(&mut bytes, 0x89ab_cdef).unwrap();
+        write_u64(&mut bytes, 0x0102_0304_0506_0708).unwrap();
+        assert_eq!(&bytes[..6], &[0x34, 0x12, 0xef, 0xcd, 0xab, 0x89]);
+
+        let mut r = &bytes[..];
+        assert_eq!(read_u16(&mut r).unwrap(), 0x1234);
+        assert_eq!(read_u32(&mut r).unwrap(), 0x89ab_cdef);
+        assert_eq!(read_u64(&mut r).unwrap(), 0x0102_0304_0506_0708);
+        assert!(read_u16(&mut r).is_err());
+    }
+
+    #[test]
+    fn u64_or_eof() {
+        let mut bytes = vec![];
+        write_u64(&mut bytes, 0x0102_0304_0506_0708).unwrap();
+
+        let mut r = &bytes[..];
+        assert_eq!(read_u64_or_eof(&mut r).unwrap(), Some(0x0102_0304_0506_0708));
+        assert_eq!(read_u64_or_eof(&mut r).unwrap(), None);
+
+        let mut cut = &bytes[..5];
+        assert_eq!(read_u64_or_eof(&mut cut).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,219 @@
+use std::fmt;
+
+use address::Address;
+use symbols::SymbolTable;
+
+/// A guest `CALL` which hasn't returned yet
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame {
+    /// the address of the `CALL`
+    pub call_site: Address,
+    /// the function called
+    pub target: Address,
+    pub return_address: Address,
+    /// how deep the VM stack was with the return address pushed
+    pub stack_depth: usize,
+}
+
+/// A `RET` which didn't go back where the matching `CALL` left off, because the guest changed
+/// the stack under it
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Mismatch {
+    /// the address of the `RET`
+    pub at: Address,
+    /// where the innermost call should have returned to
+    pub expected: Address,
+    /// what was actually popped
+    pub actual: u16,
+    /// how many frames the return left
+    pub unwound: usize,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RET at {} went to @{}, expected {}, leaving {} frames", self.at, self.actual, self.expected, self.unwound)
+    }
+}
+
+/// The calls the guest is in the middle of, kept alongside the VM stack, which holds return
+/// addresses and data alike.
+///
+/// Frames are tied to the depth of the VM stack their return address was pushed at. A `RET`
+/// which pops that deep returns from the frames above it, and it's a mismatch if it goes
+/// somewhere other than the innermost return address or unwinds more than one frame. A `RET` to
+/// an address the guest pushed itself, above every frame, is a computed jump and leaves the frames
+/// alone.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+    mismatches: u64,
+    last_mismatch: Option<Mismatch>,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack { frames: vec![], mismatches: 0, last_mismatch: None }
+    }
+
+    /// Every frame, outermost first
+    pub fn frames(&self) -> &Vec<Frame> {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// How many mismatched returns we've seen
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+
+    pub fn last_mismatch(&self) -> Option<Mismatch> {
+        self.last_mismatch
+    }
+
+    /// Forget every frame, eg when the VM stack is replaced wholesale
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn call(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+
+    /// A `RET` at `at` popped `value`, leaving the VM stack `stack_depth` deep. The frames it
+    /// returns from are added to `removed`, outermost first, if given.
+    pub fn ret(&mut self, at: Address, value: u16, stack_depth: usize, removed: Option<&mut Vec<Frame>>) {
+        let expected = match self.frames.last() {
+            Some(f) if f.stack_depth > stack_depth => f.return_address,
+            _ => return
+        };
+
+        let keep = self.live(stack_depth);
+        let unwound = self.frames.len() - keep;
+        if unwound > 1 || expected.value() != value {
+            self.mismatches += 1;
+            self.last_mismatch = Some(Mismatch { at, expected, actual: value, unwound });
+        }
+
+        if let Some(r) = removed { r.extend_from_slice(&self.frames[keep..]); }
+        self.frames.truncate(keep);
+    }
+
+    /// Drop the frames whose return address is no longer on a VM stack `stack_depth` deep,
+    /// returning them outermost first
+    pub fn truncate(&mut self, stack_depth: usize) -> Vec<Frame> {
+        let keep = self.live(stack_depth);
+        self.frames.split_off(keep)
+    }
+
+    /// How many frames, from the bottom, are still within a VM stack `stack_depth` deep
+    fn live(&self, stack_depth: usize) -> usize {
+        let mut keep = self.frames.len();
+        while keep > 0 && self.frames[keep - 1].stack_depth > stack_depth { keep -= 1; }
+        keep
+    }
+
+    /// Put back frames taken off by `ret` or `truncate`
+    pub fn extend(&mut self, frames: &[Frame]) {
+        self.frames.extend_from_slice(frames);
+    }
+
+    /// Describe the calls leading to `address`, innermost first, with symbol names where we have
+    /// them, eg:
+    ///
+    /// ```text
+    /// #0 @6035 <confirm+8> in confirm
+    /// #1 @6047 <confirm+20> in confirm
+    /// #2 @5489
+    /// ```
+    pub fn backtrace(&self, address: Address, symbols: &SymbolTable) -> String {
+        let label = |a: Address| match symbols.describe(a) {
+            Some(name) => format!("{} <{}>", a, name),
+            None => format!("{}", a)
+        };
+        let function = |a: Address| symbols.name_of(a).map(String::from).unwrap_or_else(|| format!("{}", a));
+
+        let mut lines = vec![];
+        let mut at = address;
+        for (i, frame) in self.frames.iter().rev().enumerate() {
+            lines.push(format!("#{} {} in {}", i, label(at), function(frame.target)));
+            at = frame.call_site;
+        }
+        lines.push(format!("#{} {}", self.frames.len(), label(at)));
+
+        if let Some(m) = self.last_mismatch {
+            lines.push(format!("({} mismatched returns, the last: {})", self.mismatches, m));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(call_site: u16, target: u16, stack_depth: usize) -> Frame {
+        Frame { call_site: Address::new(call_site), target: Address::new(target), return_address: Address::new(call_site + 2), stack_depth }
+    }
+
+    #[test]
+    fn matched_returns() {
+        let mut calls = CallStack::new();
+        calls.call(frame(0, 10, 1));
+        calls.call(frame(12, 20, 3));
+
+        let mut removed = vec![];
+        calls.ret(Address::new(25), 14, 2, Some(&mut removed));
+        calls.ret(Address::new(15), 2, 0, Some(&mut removed));
+        assert_eq!(removed, vec![frame(12, 20, 3), frame(0, 10, 1)]);
+        assert_eq!(calls.depth(), 0);
+        assert_eq!(calls.mismatches(), 0);
+    }
+
+    #[test]
+    fn computed_jumps_are_not_returns() {
+        let mut calls = CallStack::new();
+        calls.call(frame(0, 10, 1));
+
+        // PUSH 30; RET inside the call
+        calls.ret(Address::new(12), 30, 1, None);
+        assert_eq!(calls.depth(), 1);
+        assert_eq!(calls.mismatches(), 0);
+    }
+
+    #[test]
+    fn mismatched_returns() {
+        let mut calls = CallStack::new();
+        calls.call(frame(0, 10, 1));
+        calls.call(frame(12, 20, 2));
+
+        // the inner call's return address was popped and something else pushed in its place
+        calls.ret(Address::new(25), 99, 1, None);
+        assert_eq!(calls.frames(), &vec![frame(0, 10, 1)]);
+        assert_eq!(calls.mismatches(), 1);
+        assert_eq!(calls.last_mismatch(), Some(Mismatch { at: Address::new(25), expected: Address::new(14), actual: 99, unwound: 1 }));
+
+        // both return addresses were popped
+        calls.call(frame(12, 20, 2));
+        let mut removed = vec![];
+        calls.ret(Address::new(25), 2, 0, Some(&mut removed));
+        assert_eq!(removed.len(), 2);
+        assert_eq!(calls.mismatches(), 2);
+    }
+
+    #[test]
+    fn backtrace() {
+        let mut calls = CallStack::new();
+        calls.call(frame(0, 10, 1));
+        calls.call(frame(12, 10, 2));
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("f", Address::new(10));
+
+        assert_eq!(calls.backtrace(Address::new(11), &symbols),
+                   "#0 @11 <f+1> in f\n#1 @12 <f+2> in f\n#2 @0");
+    }
+}
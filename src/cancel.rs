@@ -0,0 +1,31 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Asks a running VM to stop, from any thread. The VM checks between instructions and stops with
+/// `VMState::Paused`, after which it can be resumed as normal. Get one from `VM::cancel_handle`;
+/// every handle from the same VM shares the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle { flag: Arc::new(AtomicBool::new(false)) }
+    }
+
+    /// Stop the VM at the next instruction boundary. Cancelling a VM which isn't running stops it
+    /// as soon as it's next run.
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+
+    /// Clear the request, returning whether there was one
+    pub fn reset(&self) -> bool {
+        self.flag.swap(false, Ordering::SeqCst)
+    }
+}
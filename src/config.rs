@@ -0,0 +1,198 @@
+use std::str::FromStr;
+
+/// What `OUT` does with a value which isn't an ASCII character
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NonAsciiOutput {
+    /// Anything over 127 is an `InvalidCharacterArgument`
+    Error,
+    /// Keep the low byte, and fail if that isn't ASCII
+    Truncate,
+    /// Write the low byte, whatever it is
+    Raw
+}
+
+/// What `RET` does when the stack is empty
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EmptyReturn {
+    Halt,
+    /// Fail with `StackUnderflow`, like `POP`
+    Error
+}
+
+/// What `IN` does when its operand is a literal rather than a register
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LiteralInput {
+    /// Write the character to memory at that address
+    WriteMemory,
+    /// Fail with `MalformedInstruction`
+    Error
+}
+
+/// What `IN` does when the input device has nothing more to give
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EndOfInput {
+    /// Fail with `EndOfInput`
+    Error,
+    Halt
+}
+
+/// How the VM behaves where the spec is vague, or where we've historically done something odd.
+/// Set it with `VM::set_config`. An instruction with a literal where it needs a register is a
+/// `MalformedInstruction` whatever the config.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct VMConfig {
+    pub non_ascii_output: NonAsciiOutput,
+    pub empty_return: EmptyReturn,
+    pub literal_input: LiteralInput,
+    pub end_of_input: EndOfInput,
+}
+
+impl VMConfig {
+    /// Only what the spec allows: characters are ASCII, `IN` writes to a register and bad
+    /// instructions are errors. An empty `RET` halts, as the spec says.
+    pub fn strict() -> VMConfig {
+        VMConfig {
+            non_ascii_output: NonAsciiOutput::Error,
+            empty_return: EmptyReturn::Halt,
+            literal_input: LiteralInput::Error,
+            end_of_input: EndOfInput::Error,
+        }
+    }
+
+    /// Keep going wherever we reasonably can, and stop quietly when the input runs out
+    pub fn lenient() -> VMConfig {
+        VMConfig {
+            non_ascii_output: NonAsciiOutput::Raw,
+            empty_return: EmptyReturn::Halt,
+            literal_input: LiteralInput::WriteMemory,
+            end_of_input: EndOfInput::Halt,
+        }
+    }
+
+    /// What the VM has always done, and the default
+    pub fn legacy() -> VMConfig {
+        VMConfig {
+            non_ascii_output: NonAsciiOutput::Truncate,
+            empty_return: EmptyReturn::Halt,
+            literal_input: LiteralInput::WriteMemory,
+            end_of_input: EndOfInput::Error,
+        }
+    }
+}
+
+impl Default for VMConfig {
+    fn default() -> VMConfig {
+        VMConfig::legacy()
+    }
+}
+
+impl FromStr for VMConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VMConfig, String> {
+        match s {
+            "strict" => Ok(VMConfig::strict()),
+            "lenient" => Ok(VMConfig::lenient()),
+            "legacy" => Ok(VMConfig::legacy()),
+            _ => Err(format!("Unknown strictness `{}', expected strict, lenient or legacy", s))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address::Address;
+    use argument::Argument;
+    use register::Register;
+    use instruction::Instruction;
+    use device::{BufferInput, BufferOutput};
+    use vm::{VM, VMState, VMError};
+    use constants::*;
+
+    fn configured(config: VMConfig, program: Vec<u16>) -> (VM, BufferOutput) {
+        let out = BufferOutput::new();
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(out.clone()));
+        vm.set_config(config);
+        vm.load_program(Address::new(0), &program).unwrap();
+        return (vm, out);
+    }
+
+    #[test]
+    fn presets() {
+        assert_eq!("strict".parse(), Ok(VMConfig::strict()));
+        assert_eq!("lenient".parse(), Ok(VMConfig::lenient()));
+        assert_eq!("legacy".parse(), Ok(VMConfig::legacy()));
+        assert_eq!(VMConfig::default(), VMConfig::legacy());
+        assert!("loose".parse::<VMConfig>().is_err());
+    }
+
+    // OUT 321 is 'A' if you only look at the low byte
+    #[test]
+    fn non_ascii_output() {
+        let program = Instruction::OUT(Argument::new(321)).to_u16_sequence();
+        let (mut strict, _) = configured(VMConfig::strict(), program.clone());
+        assert_eq!(strict.run(Address::new(0)).map_err(|f| f.error), Err(VMError::InvalidCharacterArgument(Argument::new(321))));
+
+        let (mut legacy, out) = configured(VMConfig::legacy(), program);
+        assert_eq!(legacy.run(Address::new(0)), Ok(VMState::HALT));
+        assert_eq!(out.contents(), "A");
+
+        let program = Instruction::OUT(Argument::new(200)).to_u16_sequence();
+        let (mut legacy, _) = configured(VMConfig::legacy(), program.clone());
+        assert_eq!(legacy.run(Address::new(0)).map_err(|f| f.error), Err(VMError::InvalidCharacterArgument(Argument::new(200))));
+
+        let (mut lenient, out) = configured(VMConfig::lenient(), program);
+        assert_eq!(lenient.run(Address::new(0)), Ok(VMState::HALT));
+        assert_eq!(out.bytes(), vec![200]);
+    }
+
+    #[test]
+    fn empty_return() {
+        for config in &[VMConfig::strict(), VMConfig::lenient(), VMConfig::legacy()] {
+            let (mut vm, _) = configured(*config, vec![18]);
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+        }
+
+        let config = VMConfig { empty_return: EmptyReturn::Error, ..VMConfig::legacy() };
+        let (mut vm, _) = configured(config, vec![18]);
+        assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::StackUnderflow));
+    }
+
+    #[test]
+    fn literal_input() {
+        let program = Instruction::IN(Argument::new(100)).to_u16_sequence();
+        let (mut strict, _) = configured(VMConfig::strict(), program.clone());
+        strict.push_input("x");
+        assert_eq!(strict.run(Address::new(0)).map_err(|f| f.error), Err(VMError::MalformedInstruction(vec![20, 100])));
+
+        let (mut legacy, _) = configured(VMConfig::legacy(), program);
+        legacy.push_input("x");
+        assert_eq!(legacy.run(Address::new(0)), Ok(VMState::HALT));
+        assert_eq!(legacy.peek(Address::new(100)), Ok(120));
+    }
+
+    // SET 5 1 and ADD 5 R0 1 have a literal where the register should be
+    #[test]
+    fn invalid_argument() {
+        for config in &[VMConfig::strict(), VMConfig::lenient(), VMConfig::legacy()] {
+            let (mut vm, _) = configured(*config, vec![1, 5, 1]);
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::MalformedInstruction(vec![1, 5, 1])));
+
+            let (mut vm, _) = configured(*config, vec![9, 5, REGISTER_0, 1]);
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::MalformedInstruction(vec![9, 5, REGISTER_0, 1])));
+        }
+    }
+
+    #[test]
+    fn end_of_input() {
+        let program = Instruction::IN(Argument::Register(Register::R0)).to_u16_sequence();
+        for config in &[VMConfig::strict(), VMConfig::legacy()] {
+            let (mut vm, _) = configured(*config, program.clone());
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::EndOfInput));
+        }
+
+        let (mut lenient, _) = configured(VMConfig::lenient(), program);
+        assert_eq!(lenient.run(Address::new(0)), Ok(VMState::HALT));
+    }
+}
@@ -0,0 +1,396 @@
+use std::io;
+
+use address::Address;
+use debugger::{Debugger, Command};
+use fault::VMFault;
+use snapshot::Snapshot;
+use trace::{Tracer, TraceFormat, TraceFilter};
+use vm::{VM, VMState};
+
+/// How many lines of input `!undo` can take back
+pub const UNDO_LIMIT : usize = 100;
+
+pub const META_HELP : &str = "\
+!save FILE          write a snapshot of the VM to FILE
+!load FILE          replace the VM state with the snapshot in FILE
+!regs               show the registers
+!set REG|LOC VALUE  change a register or a word of memory
+!peek LOC [N]       show N words of memory, default 8
+!undo               go back to before the last line of input or `!load'
+!break [LOC]        set a breakpoint, or stop in the debugger right now
+!trace on [FILE]    start tracing to FILE, by default the `--trace' file, overwriting it
+!trace off          stop tracing
+!help               this message
+
+While recording, `!load', `!set' and `!undo' are refused, along with the debugger's `set',
+`jump' and reverse execution: they change the VM outside of its input, so the recording
+couldn't be replayed.";
+
+/// What the player should see after a meta-command
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Reply {
+    Message(String),
+    /// Show the message, then hand over to the debugger until the player quits it
+    Debug(String),
+}
+
+/// Why `Controls::resume` stopped
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stop {
+    /// The VM stopped by itself, or at the `--stop-at` step with `BudgetExhausted`
+    State(VMState),
+    /// The next instruction has a breakpoint on it
+    Breakpoint(Address),
+}
+
+/// What lines of input starting with the meta-command prefix, `!` by default, work with
+/// instead of going to the program
+pub struct Controls {
+    prefix: String,
+    debugger: Debugger,
+    /// snapshots from before each line of input, with the line
+    undo: Vec<(String, Snapshot)>,
+    tracer: Option<Tracer>,
+    trace_path: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    /// the step the program is stopped at, if it's stopped early
+    stop_at: Option<u64>,
+    /// whether the session is being recorded for replay
+    recording: bool,
+}
+
+impl Controls {
+    pub fn new(prefix: &str, debugger: Debugger, trace_format: TraceFormat, trace_filter: TraceFilter) -> Controls {
+        Controls {
+            prefix: prefix.to_owned(),
+            debugger,
+            undo: vec![],
+            tracer: None,
+            trace_path: None,
+            trace_format,
+            trace_filter,
+            stop_at: None,
+            recording: false,
+        }
+    }
+
+    pub fn debugger(&mut self) -> &mut Debugger {
+        &mut self.debugger
+    }
+
+    pub fn tracer(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Trace to the file at `path`, replacing it, and go back to it for `!trace on`
+    pub fn start_trace(&mut self, path: &str) -> io::Result<()> {
+        self.tracer = Some(Tracer::create(path, self.trace_format, self.trace_filter)?);
+        self.trace_path = Some(path.to_owned());
+        Ok(())
+    }
+
+    pub fn stop_at(&self) -> Option<u64> {
+        self.stop_at
+    }
+
+    pub fn set_stop_at(&mut self, step: Option<u64>) {
+        self.stop_at = step;
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        self.debugger.set_recording(recording);
+    }
+
+    /// Run until the program halts, wants input, reaches the `--stop-at` step or a breakpoint,
+    /// tracing as it goes. With a tracer or breakpoints every instruction is stepped through the
+    /// debugger, so its history goes back over them, otherwise the VM runs flat out.
+    pub fn resume(&mut self, vm: &mut VM) -> Result<Stop, VMFault> {
+        vm.set_yield_on_input(true);
+        if self.tracer.is_none() && self.debugger.breakpoints().is_empty() {
+            let result = match self.stop_at {
+                Some(step) => vm.run_for(step.saturating_sub(vm.step_count())),
+                None => vm.resume()
+            };
+            return result.map(Stop::State);
+        }
+
+        let result = self.step_until_stopped(vm);
+        if let Some(ref mut t) = self.tracer { let _ = t.flush(); }
+        result
+    }
+
+    fn step_until_stopped(&mut self, vm: &mut VM) -> Result<Stop, VMFault> {
+        loop {
+            if let Some(step) = self.stop_at {
+                if vm.step_count() >= step { return Ok(Stop::State(VMState::BudgetExhausted)); }
+            }
+
+            let debugger = &mut self.debugger;
+            let result = match self.tracer {
+                Some(ref mut t) => t.step_with(vm, |vm| debugger.record_step(vm)),
+                None => debugger.record_step(vm)
+            };
+            match result {
+                Ok(VMState::RUN) => (),
+                Ok(state) => return Ok(Stop::State(state)),
+                Err(e) => return Err(e)
+            }
+
+            let ip = vm.instruction_pointer();
+            if self.debugger.has_breakpoint(ip) { return Ok(Stop::Breakpoint(ip)); }
+        }
+    }
+
+    pub fn is_meta(&self, line: &str) -> bool {
+        !self.prefix.is_empty() && line.starts_with(&self.prefix)
+    }
+
+    /// Note the VM's state from before `line`, for `!undo`
+    pub fn remember(&mut self, line: &str, snapshot: Snapshot) {
+        self.undo.push((line.trim().to_owned(), snapshot));
+        if self.undo.len() > UNDO_LIMIT { self.undo.remove(0); }
+    }
+
+    /// Run a debugger command, returning what it says
+    fn debugger_command(&mut self, vm: &mut VM, line: &str) -> Reply {
+        match Command::parse(line).and_then(|c| self.debugger.execute(vm, c)) {
+            Ok(out) => Reply::Message(out),
+            Err(e) => Reply::Message(e)
+        }
+    }
+
+    /// Why `command` can't be used right now, if it can't
+    fn refusal(&self, command: &str) -> Option<String> {
+        let changes_state = command == "load" || command == "set" || command == "undo";
+        if self.recording && changes_state {
+            return Some(format!("Can't `{}{}' while recording, the session couldn't be replayed", self.prefix, command));
+        }
+        None
+    }
+
+    /// Handle a meta-command, see `META_HELP`
+    pub fn execute(&mut self, vm: &mut VM, line: &str) -> Reply {
+        let words : Vec<&str> = line[self.prefix.len()..].split_whitespace().collect();
+        let command = words.first().cloned().unwrap_or("");
+        let rest = if words.is_empty() { &words[..] } else { &words[1..] };
+
+        if let Some(refusal) = self.refusal(command) {
+            return Reply::Message(refusal);
+        }
+
+        match (command, rest) {
+            ("save", [path]) => match vm.snapshot().save(path) {
+                Ok(()) => Reply::Message(format!("Saved `{}'", path)),
+                Err(e) => Reply::Message(format!("Could not save `{}': {}", path, e))
+            },
+            ("load", [path]) => {
+                let before = vm.snapshot();
+                match Snapshot::load(path).map(|s| vm.restore(&s)) {
+                    Ok(Ok(())) => {
+                        self.remember(line, before);
+                        Reply::Message(format!("Loaded `{}'", path))
+                    },
+                    Ok(Err(e)) => Reply::Message(format!("Could not restore `{}': {}", path, e)),
+                    Err(e) => Reply::Message(format!("Could not load `{}': {}", path, e))
+                }
+            },
+            ("regs", []) => self.debugger_command(vm, "registers"),
+            ("set", _) => self.debugger_command(vm, &format!("set {}", rest.join(" "))),
+            ("peek", _) => self.debugger_command(vm, &format!("mem {}", rest.join(" "))),
+            ("undo", []) => match self.undo.pop() {
+                Some((line, snapshot)) => match vm.restore(&snapshot) {
+                    Ok(()) => Reply::Message(format!("Undid `{}'", line)),
+                    Err(e) => Reply::Message(format!("Could not undo `{}': {}", line, e))
+                },
+                None => Reply::Message(String::from("Nothing to undo"))
+            },
+            ("break", []) => Reply::Debug(String::from("Debugging, type `help' for commands and `quit' to go back to playing")),
+            ("break", [location]) => self.debugger_command(vm, &format!("break {}", location)),
+            ("trace", ["on"]) | ("trace", ["on", _]) => {
+                let path = match rest.get(1).map(|p| p.to_string()).or_else(|| self.trace_path.clone()) {
+                    Some(path) => path,
+                    None => return Reply::Message(format!("No trace file, use `{}trace on FILE'", self.prefix))
+                };
+                match self.start_trace(&path) {
+                    Ok(()) => Reply::Message(format!("Tracing to `{}'", path)),
+                    Err(e) => Reply::Message(format!("Could not create trace `{}': {}", path, e))
+                }
+            },
+            ("trace", ["off"]) => match self.tracer.take() {
+                Some(_) => Reply::Message(String::from("Stopped tracing")),
+                None => Reply::Message(String::from("Not tracing"))
+            },
+            ("help", []) => Reply::Message(META_HELP.replace('!', &self.prefix)),
+            _ => Reply::Message(format!("Unknown command `{}', try `{}help'", line, self.prefix))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use address::Address;
+    use instruction::Instruction;
+    use argument::Argument;
+    use register::Register;
+    use symbols::SymbolTable;
+    use device::{BufferInput, BufferOutput};
+    use debugger::StopReason;
+
+    fn controls(prefix: &str) -> Controls {
+        Controls::new(prefix, Debugger::new(SymbolTable::new()), TraceFormat::Text, TraceFilter { range: None, max_depth: None })
+    }
+
+    fn vm() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![Instruction::OUT(Argument::new(97)), Instruction::HALT]).unwrap();
+        vm
+    }
+
+    fn message(reply: Reply) -> String {
+        match reply {
+            Reply::Message(m) => m,
+            Reply::Debug(m) => panic!("expected a message, got a debug session after `{}'", m)
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("synacor-controls-test-{}-{}", ::std::process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn prefix() {
+        let mut vm = vm();
+        let mut bang = controls("!");
+        assert!(bang.is_meta("!regs"));
+        assert!(!bang.is_meta("look"));
+        assert!(message(bang.execute(&mut vm, "!regs")).starts_with("R0 = 0"));
+
+        let mut colons = controls("::");
+        assert!(colons.is_meta("::regs"));
+        assert!(!colons.is_meta("!regs"));
+        assert!(message(colons.execute(&mut vm, "::regs")).starts_with("R0 = 0"));
+        assert_eq!(message(colons.execute(&mut vm, "::nope")), "Unknown command `::nope', try `::help'");
+        assert!(message(colons.execute(&mut vm, "::help")).contains("::undo"));
+
+        assert!(!controls("").is_meta("!regs"));
+    }
+
+    #[test]
+    fn set_and_peek() {
+        let mut vm = vm();
+        let mut controls = controls("!");
+
+        assert_eq!(message(controls.execute(&mut vm, "!set r1 5")), "R1 = 5");
+        assert_eq!(vm.register(Register::R1), 5);
+
+        controls.execute(&mut vm, "!set 100 7");
+        assert_eq!(vm.peek(Address::new(100)), Ok(7));
+        assert_eq!(message(controls.execute(&mut vm, "!peek 99 3")), "@99: 0 7 0");
+    }
+
+    #[test]
+    fn undo_limit() {
+        let mut vm = vm();
+        let mut controls = controls("!");
+
+        for i in 0..UNDO_LIMIT as u16 + 5 {
+            vm.poke(Address::new(100), i).unwrap();
+            controls.remember(&format!("line {}\n", i), vm.snapshot());
+        }
+        assert_eq!(controls.undo.len(), UNDO_LIMIT);
+        assert_eq!(controls.undo[0].0, "line 5");
+
+        assert_eq!(message(controls.execute(&mut vm, "!undo")), format!("Undid `line {}'", UNDO_LIMIT + 4));
+        assert_eq!(controls.undo.len(), UNDO_LIMIT - 1);
+    }
+
+    #[test]
+    fn trace_on_uses_the_trace_file() {
+        let mut vm = vm();
+        let mut controls = controls("!");
+        assert_eq!(message(controls.execute(&mut vm, "!trace on")), "No trace file, use `!trace on FILE'");
+
+        let path = temp_path("trace");
+        controls.start_trace(&path).unwrap();
+        assert_eq!(message(controls.execute(&mut vm, "!trace off")), "Stopped tracing");
+        assert!(controls.tracer().is_none());
+
+        assert_eq!(message(controls.execute(&mut vm, "!trace on")), format!("Tracing to `{}'", path));
+        assert!(controls.tracer().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn breakpoints_with_tracing_and_stopping() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::OUT(Argument::new(97)),
+            Instruction::OUT(Argument::new(98)),
+            Instruction::OUT(Argument::new(99)),
+            Instruction::HALT
+        ]).unwrap();
+        vm.start(Address::new(0));
+
+        let mut controls = controls("!");
+        let path = temp_path("breakpoints");
+        controls.start_trace(&path).unwrap();
+        controls.set_stop_at(Some(1));
+        assert_eq!(message(controls.execute(&mut vm, "!break 4")), "Breakpoint set at @4");
+
+        assert_eq!(controls.resume(&mut vm), Ok(Stop::State(VMState::BudgetExhausted)));
+        assert_eq!(vm.step_count(), 1);
+
+        controls.set_stop_at(None);
+        assert_eq!(controls.resume(&mut vm), Ok(Stop::Breakpoint(Address::new(4))));
+        assert_eq!(vm.drain_output(), "ab");
+
+        // the instruction under the breakpoint runs when we carry on
+        assert_eq!(controls.resume(&mut vm), Ok(Stop::State(VMState::HALT)));
+        assert_eq!(vm.drain_output(), "c");
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(controls.debugger().reverse_step(&mut vm), StopReason::Stepped);
+        assert_eq!(vm.instruction_pointer(), Address::new(6));
+    }
+
+    #[test]
+    fn recording_refuses_state_changes() {
+        let mut vm = vm();
+        let mut controls = controls("!");
+        controls.set_recording(true);
+        controls.remember("look", vm.snapshot());
+
+        assert_eq!(message(controls.execute(&mut vm, "!set r1 5")), "Can't `!set' while recording, the session couldn't be replayed");
+        assert_eq!(vm.register(Register::R1), 0);
+        assert!(message(controls.execute(&mut vm, "!undo")).starts_with("Can't `!undo'"));
+        assert!(message(controls.execute(&mut vm, "!load x.snap")).starts_with("Can't `!load'"));
+        assert_eq!(controls.undo.len(), 1);
+
+        assert!(message(controls.execute(&mut vm, "!regs")).starts_with("R0 = 0"));
+        assert_eq!(controls.execute(&mut vm, "!break"), Reply::Debug(String::from("Debugging, type `help' for commands and `quit' to go back to playing")));
+    }
+
+    #[test]
+    fn recording_refuses_state_changes_in_the_debugger() {
+        let mut vm = vm();
+        let mut controls = controls("!");
+        controls.set_recording(true);
+
+        assert_eq!(controls.execute(&mut vm, "!break"), Reply::Debug(String::from("Debugging, type `help' for commands and `quit' to go back to playing")));
+        for command in &["set r1 5", "set 100 7", "jump 2", "reverse-step"] {
+            let command = Command::parse(command).unwrap();
+            assert_eq!(controls.debugger().execute(&mut vm, command), Err(String::from("Can't change the VM by hand while recording, the session couldn't be replayed")));
+        }
+        assert_eq!(vm.register(Register::R1), 0);
+        assert_eq!(vm.peek(Address::new(100)), Ok(0));
+        assert_eq!(vm.instruction_pointer(), Address::new(0));
+
+        assert!(controls.debugger().execute(&mut vm, Command::parse("registers").unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,871 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use address::Address;
+use register::Register;
+use instruction::Instruction;
+use symbols::SymbolTable;
+use history::History;
+use vm::{VM, VMState};
+use fault::VMFault;
+use watch::{Watchpoint, WatchTarget, WatchKind, WatchAction, Condition};
+
+/// Somewhere in memory, either a raw address or a label from the symbol table
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Location {
+    At(Address),
+    Label(String)
+}
+
+/// Something a watchpoint can be set on
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Watched {
+    Register(Register),
+    Memory(Location)
+}
+
+/// A parsed debugger command
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Command {
+    Break(Location),
+    Delete(Location),
+    Breakpoints,
+    Step(usize),
+    Next,
+    Finish,
+    Continue,
+    Registers,
+    Stack,
+    Backtrace,
+    Memory(Location, u16),
+    SetRegister(Register, u16),
+    SetMemory(Location, u16),
+    Jump(Location),
+    Disassemble(Option<Location>, u16),
+    Input(String),
+    Watch(Watched, WatchKind, Option<Condition>, WatchAction),
+    Unwatch(usize),
+    Watches,
+    ReverseStep(usize),
+    ReverseContinue,
+    LastWrite(Watched),
+    Help,
+    Quit
+}
+
+/// Why the debugger handed control back
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StopReason {
+    Stepped,
+    Breakpoint(Address),
+    Returned,
+    Halted,
+    AwaitingInput,
+    Watchpoint,
+    /// stopped by a cancel handle or the VM's deadline
+    Interrupted,
+    /// went back as far as the recorded history goes
+    StartOfHistory,
+    Error(VMFault)
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Stepped => write!(f, "Stepped"),
+            StopReason::Breakpoint(a) => write!(f, "Breakpoint at {}", a),
+            StopReason::Returned => write!(f, "Returned"),
+            StopReason::Halted => write!(f, "Program halted"),
+            StopReason::AwaitingInput => write!(f, "Program is waiting for input, use `input TEXT'"),
+            StopReason::Watchpoint => write!(f, "Watchpoint triggered"),
+            StopReason::Interrupted => write!(f, "Interrupted"),
+            StopReason::StartOfHistory => write!(f, "Reached the start of the recorded history"),
+            StopReason::Error(ref e) => write!(f, "Program faulted: {}", e),
+        }
+    }
+}
+
+pub const HELP : & str = "\
+break LOC         (b)   set a breakpoint at an address or label
+delete LOC        (d)   remove a breakpoint
+breakpoints       (bs)  list breakpoints
+step [N]          (s)   execute N instructions, default 1
+next              (n)   step, treating a CALL as a single instruction
+finish            (f)   run until the current function returns
+continue          (c)   run until a breakpoint, halt, or the program wants input
+registers         (r)   show the registers
+stack                   show the stack, top first
+backtrace         (bt)  show the calls leading to the instruction pointer
+mem LOC [N]       (x)   show N words of memory, default 8
+set REG|LOC VALUE       change a register or a word of memory
+jump LOC                move the instruction pointer
+dis [LOC] [N]           disassemble N instructions, default around the instruction pointer
+input TEXT        (i)   queue a line of input for the program
+watch TGT [KIND] [OP N] (w) stop when a register or memory is accessed. KIND is read, write or
+                        access (the default), OP is one of == != > <
+logwatch TGT [KIND] [OP N]  like watch, but just log the access and keep going
+unwatch ID              remove a watchpoint
+watches                 list watchpoints
+reverse-step [N]  (rs)  undo N instructions, default 1
+reverse-continue  (rc)  run backwards to the previous breakpoint
+last-write TGT    (lw)  find the instruction which last wrote to a register or memory
+help              (h)   this message
+quit              (q)   leave the debugger";
+
+/// Parse a number, allowing an `@` in front for addresses
+fn parse_number(s: &str) -> Result<u16, String> {
+    s.trim_start_matches('@').parse::<u16>().map_err(|_| format!("`{}' is not a number", s))
+}
+
+fn parse_register(s: &str) -> Option<Register> {
+    match s.to_uppercase().as_str() {
+        "R0" => Some(Register::R0),
+        "R1" => Some(Register::R1),
+        "R2" => Some(Register::R2),
+        "R3" => Some(Register::R3),
+        "R4" => Some(Register::R4),
+        "R5" => Some(Register::R5),
+        "R6" => Some(Register::R6),
+        "R7" => Some(Register::R7),
+        _ => None
+    }
+}
+
+fn parse_watch(words: &[&str], action: WatchAction) -> Result<Command, String> {
+    let target = match words.get(1) {
+        Some(t) => match parse_register(t) {
+            Some(r) => Watched::Register(r),
+            None => Watched::Memory(parse_location(t))
+        },
+        None => return Err(format!("`{}' needs something to watch", words[0]))
+    };
+
+    let mut rest = &words[2..];
+    let kind = match rest.first() {
+        Some(&"read") => { rest = &rest[1..]; WatchKind::Read },
+        Some(&"write") => { rest = &rest[1..]; WatchKind::Write },
+        Some(&"access") => { rest = &rest[1..]; WatchKind::ReadWrite },
+        _ => WatchKind::ReadWrite
+    };
+
+    let condition = match rest.len() {
+        0 => None,
+        2 => {
+            let v = parse_number(rest[1])?;
+            match rest[0] {
+                "==" => Some(Condition::Equal(v)),
+                "!=" => Some(Condition::NotEqual(v)),
+                ">" => Some(Condition::GreaterThan(v)),
+                "<" => Some(Condition::LessThan(v)),
+                op => return Err(format!("Unknown comparison `{}'", op))
+            }
+        },
+        _ => return Err(String::from("Expected a condition like `== 5'"))
+    };
+
+    Ok(Command::Watch(target, kind, condition, action))
+}
+
+fn parse_location(s: &str) -> Location {
+    match parse_number(s) {
+        Ok(n) => Location::At(Address::new(n)),
+        Err(_) => Location::Label(s.to_owned())
+    }
+}
+
+impl Command {
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() { return Err(String::from("No command given")); }
+
+        let arg = |i: usize| -> Result<&str, String> {
+            words.get(i).cloned().ok_or_else(|| format!("`{}' needs more arguments, see `help'", words[0]))
+        };
+
+        match words[0] {
+            "break" | "b" => Ok(Command::Break(parse_location(arg(1)?))),
+            "delete" | "d" => Ok(Command::Delete(parse_location(arg(1)?))),
+            "breakpoints" | "bs" => Ok(Command::Breakpoints),
+            "step" | "s" => match words.get(1) {
+                Some(n) => parse_number(n).map(|n| Command::Step(n as usize)),
+                None => Ok(Command::Step(1))
+            },
+            "next" | "n" => Ok(Command::Next),
+            "finish" | "f" => Ok(Command::Finish),
+            "continue" | "c" => Ok(Command::Continue),
+            "registers" | "r" => Ok(Command::Registers),
+            "stack" => Ok(Command::Stack),
+            "backtrace" | "bt" => Ok(Command::Backtrace),
+            "mem" | "x" => {
+                let count = match words.get(2) {
+                    Some(n) => parse_number(n)?,
+                    None => 8
+                };
+                Ok(Command::Memory(parse_location(arg(1)?), count))
+            },
+            "set" => {
+                let target = arg(1)?;
+                let value = parse_number(arg(2)?)?;
+                match parse_register(target) {
+                    Some(r) => Ok(Command::SetRegister(r, value)),
+                    None => Ok(Command::SetMemory(parse_location(target), value))
+                }
+            },
+            "jump" => Ok(Command::Jump(parse_location(arg(1)?))),
+            "dis" => {
+                let location = words.get(1).map(|l| parse_location(l));
+                let count = match words.get(2) {
+                    Some(n) => parse_number(n)?,
+                    None => 8
+                };
+                Ok(Command::Disassemble(location, count))
+            },
+            "input" | "i" => {
+                let text = line.trim_start().splitn(2, char::is_whitespace).nth(1).unwrap_or("");
+                Ok(Command::Input(text.to_owned()))
+            },
+            "watch" | "w" => parse_watch(&words, WatchAction::Break),
+            "logwatch" => parse_watch(&words, WatchAction::Log),
+            "unwatch" => parse_number(arg(1)?).map(|id| Command::Unwatch(id as usize)),
+            "watches" => Ok(Command::Watches),
+            "reverse-step" | "rs" => match words.get(1) {
+                Some(n) => parse_number(n).map(|n| Command::ReverseStep(n as usize)),
+                None => Ok(Command::ReverseStep(1))
+            },
+            "reverse-continue" | "rc" => Ok(Command::ReverseContinue),
+            "last-write" | "lw" => {
+                let t = arg(1)?;
+                match parse_register(t) {
+                    Some(r) => Ok(Command::LastWrite(Watched::Register(r))),
+                    None => Ok(Command::LastWrite(Watched::Memory(parse_location(t))))
+                }
+            },
+            "help" | "h" => Ok(Command::Help),
+            "quit" | "q" => Ok(Command::Quit),
+            other => Err(format!("Unknown command `{}', see `help'", other))
+        }
+    }
+}
+
+/// How many instructions `dis` shows ahead of the instruction pointer, when there's no location
+const CONTEXT_BEFORE : u16 = 3;
+
+/// The longest instruction, an opcode and three arguments
+const MAX_INSTRUCTION_SIZE : u16 = 4;
+
+/// How many instructions the debugger remembers for reverse execution
+pub const HISTORY_LIMIT : usize = 100_000;
+
+/// Interactive debugging on top of `VM::step`. The debugger doesn't own the VM, it's handed one
+/// for each command.
+///
+/// Every instruction run through the debugger is recorded, so it can be undone again with
+/// `reverse_step`. Changing the VM by hand (`set`, `jump`) forgets the recorded history.
+pub struct Debugger {
+    breakpoints: BTreeSet<Address>,
+    symbols: SymbolTable,
+    history: History,
+    /// whether the session is being recorded for replay, see `set_recording`
+    recording: bool,
+}
+
+/// How far to run before handing control back
+enum RunMode {
+    Continue,
+    /// run until the call depth, relative to where we started, reaches this
+    UntilDepth(isize)
+}
+
+impl Debugger {
+    pub fn new(symbols: SymbolTable) -> Debugger {
+        Debugger { breakpoints: BTreeSet::new(), symbols, history: History::new(HISTORY_LIMIT), recording: false }
+    }
+
+    /// While recording, refuse the commands which change the VM other than by running it
+    /// (`set`, `jump` and reverse execution), so the recorded session can still be replayed
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    pub fn resolve(&self, location: &Location) -> Result<Address, String> {
+        match *location {
+            Location::At(a) => Ok(a),
+            Location::Label(ref name) => self.symbols.lookup(name).ok_or_else(|| format!("No symbol named `{}'", name))
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: Address) {
+        self.breakpoints.insert(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: Address) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> Vec<Address> {
+        self.breakpoints.iter().cloned().collect()
+    }
+
+    pub fn has_breakpoint(&self, address: Address) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    /// Step the VM once, recording it for `reverse_step`
+    pub fn record_step(&mut self, vm: &mut VM) -> Result<VMState, VMFault> {
+        self.history.step(vm)
+    }
+
+    /// Execute a single instruction, unless the program has halted
+    pub fn step(&mut self, vm: &mut VM) -> StopReason {
+        if vm.state() == VMState::HALT { return StopReason::Halted; }
+        vm.set_yield_on_input(true);
+        match self.record_step(vm) {
+            Ok(VMState::RUN) => StopReason::Stepped,
+            Ok(VMState::HALT) => StopReason::Halted,
+            Ok(VMState::AwaitingInput) => StopReason::AwaitingInput,
+            Ok(VMState::WatchpointHit) => StopReason::Watchpoint,
+            Ok(VMState::Paused) | Ok(VMState::BudgetExhausted) => StopReason::Interrupted,
+            Err(e) => StopReason::Error(e)
+        }
+    }
+
+    /// Undo the last instruction executed through the debugger
+    pub fn reverse_step(&mut self, vm: &mut VM) -> StopReason {
+        if self.history.reverse_step(vm) { StopReason::Stepped } else { StopReason::StartOfHistory }
+    }
+
+    /// Run backwards until we reach a breakpoint, or run out of history
+    pub fn reverse_continue(&mut self, vm: &mut VM) -> StopReason {
+        loop {
+            if !self.history.reverse_step(vm) { return StopReason::StartOfHistory; }
+
+            let ip = vm.instruction_pointer();
+            if self.breakpoints.contains(&ip) { return StopReason::Breakpoint(ip); }
+        }
+    }
+
+    /// Run until a breakpoint, or until the program stops on its own
+    pub fn continue_execution(&mut self, vm: &mut VM) -> StopReason {
+        self.run(vm, RunMode::Continue)
+    }
+
+    /// Step over the current instruction, running any CALL to completion
+    pub fn next(&mut self, vm: &mut VM) -> StopReason {
+        match vm.decode_at(vm.instruction_pointer()) {
+            Ok(Instruction::CALL(_)) => self.run(vm, RunMode::UntilDepth(0)),
+            _ => self.step(vm)
+        }
+    }
+
+    /// Run until the function we're currently in returns
+    pub fn finish(&mut self, vm: &mut VM) -> StopReason {
+        self.run(vm, RunMode::UntilDepth(-1))
+    }
+
+    fn run(&mut self, vm: &mut VM, mode: RunMode) -> StopReason {
+        let mut depth : isize = 0;
+
+        loop {
+            let instruction = vm.decode_at(vm.instruction_pointer());
+            let stack_len = vm.stack().len();
+
+            let reason = self.step(vm);
+            if reason != StopReason::Stepped { return reason; }
+
+            match instruction {
+                // a CALL to a native routine returns straight away
+                Ok(Instruction::CALL(_)) if vm.stack().len() > stack_len => depth += 1,
+                Ok(Instruction::RET) => depth -= 1,
+                _ => ()
+            }
+
+            if let RunMode::UntilDepth(target) = mode {
+                if depth == target { return StopReason::Returned; }
+            }
+
+            let ip = vm.instruction_pointer();
+            if self.breakpoints.contains(&ip) { return StopReason::Breakpoint(ip); }
+        }
+    }
+
+    /// Name an address for display, eg `@6030 <confirm+3>`
+    pub fn label(&self, address: Address) -> String {
+        match self.symbols.describe(address) {
+            Some(name) => format!("{} <{}>", address, name),
+            None => format!("{}", address)
+        }
+    }
+
+    /// Disassemble `count` instructions starting at `start`. Anything that doesn't decode is shown
+    /// as a raw word.
+    pub fn disassemble(&self, vm: &VM, start: Address, count: u16) -> String {
+        let mut lines = vec![];
+        let mut addr = start;
+
+        for _ in 0..count {
+            if !addr.is_memory() { break; }
+
+            let marker = if addr == vm.instruction_pointer() { "=>" } else { "  " };
+            let bp = if self.breakpoints.contains(&addr) { "*" } else { " " };
+
+            let (text, size) = match vm.decode_at(addr) {
+                Ok(i) => { let size = i.size(); (format!("{}", i), size) },
+                Err(_) => (format!(".word {}", vm.peek(addr).unwrap_or(0)), 1)
+            };
+
+            lines.push(format!("{}{} {}: {}", marker, bp, self.label(addr), text));
+            addr = Address::new(addr.value() + size);
+        }
+
+        lines.join("\n")
+    }
+
+    /// Where to start disassembling to show up to `before` instructions ahead of `address`.
+    /// Code can't be decoded backwards, so we look for the furthest start, no more than the
+    /// longest instructions away, which decodes cleanly forwards and lands on `address`.
+    fn back_up(&self, vm: &VM, address: Address, before: u16) -> (Address, u16) {
+        let end = address.value();
+        for back in (1..=before * MAX_INSTRUCTION_SIZE).rev() {
+            if back > end { continue; }
+
+            let mut addr = end - back;
+            let mut count = 0;
+            while addr < end && count < before {
+                match vm.decode_at(Address::new(addr)) {
+                    Ok(i) => addr += i.size(),
+                    Err(_) => break
+                }
+                count += 1;
+            }
+            if addr == end { return (Address::new(end - back), count); }
+        }
+        (address, 0)
+    }
+
+    /// Where the VM is right now
+    pub fn location(&self, vm: &VM) -> String {
+        self.disassemble(vm, vm.instruction_pointer(), 1)
+    }
+
+    /// Run a command against the VM, producing something to show the user. Program output
+    /// produced while running is included.
+    pub fn execute(&mut self, vm: &mut VM, command: Command) -> Result<String, String> {
+        if self.recording {
+            match command {
+                Command::SetRegister(..) | Command::SetMemory(..) | Command::Jump(_) | Command::ReverseStep(_) | Command::ReverseContinue =>
+                    return Err(String::from("Can't change the VM by hand while recording, the session couldn't be replayed")),
+                _ => ()
+            }
+        }
+
+        match command {
+            Command::Break(loc) => {
+                let a = self.resolve(&loc)?;
+                self.add_breakpoint(a);
+                Ok(format!("Breakpoint set at {}", self.label(a)))
+            },
+            Command::Delete(loc) => {
+                let a = self.resolve(&loc)?;
+                if self.remove_breakpoint(a) {
+                    Ok(format!("Breakpoint removed at {}", self.label(a)))
+                } else {
+                    Err(format!("No breakpoint at {}", self.label(a)))
+                }
+            },
+            Command::Breakpoints => {
+                if self.breakpoints.is_empty() { return Ok(String::from("No breakpoints")); }
+                let lines : Vec<String> = self.breakpoints.iter().map(|a| self.label(*a)).collect();
+                Ok(lines.join("\n"))
+            },
+            Command::Step(n) => {
+                let mut reason = StopReason::Stepped;
+                for _ in 0..n {
+                    reason = self.step(vm);
+                    if reason != StopReason::Stepped { break; }
+                }
+                Ok(self.report(vm, reason))
+            },
+            Command::Next => { let r = self.next(vm); Ok(self.report(vm, r)) },
+            Command::Finish => { let r = self.finish(vm); Ok(self.report(vm, r)) },
+            Command::Continue => { let r = self.continue_execution(vm); Ok(self.report(vm, r)) },
+            Command::Registers => {
+                let regs = vm.registers();
+                let mut lines : Vec<String> = regs.iter().enumerate().map(|(i, v)| format!("R{} = {}", i, v)).collect();
+                lines.push(format!("IP = {}", self.label(vm.instruction_pointer())));
+                Ok(lines.join("\n"))
+            },
+            Command::Stack => {
+                if vm.stack().is_empty() { return Ok(String::from("Stack is empty")); }
+                let lines : Vec<String> = vm.stack().iter().rev().enumerate().map(|(i, v)| format!("#{} {}", i, v)).collect();
+                Ok(lines.join("\n"))
+            },
+            Command::Backtrace => Ok(self.backtrace(vm)),
+            Command::Memory(loc, count) => {
+                let start = self.resolve(&loc)?;
+                let mut words = vec![];
+                for i in 0..count {
+                    match vm.peek(Address::new(start.value().saturating_add(i))) {
+                        Ok(v) => words.push(format!("{}", v)),
+                        Err(_) => break
+                    }
+                }
+                if words.is_empty() { return Err(format!("{} is not in memory", start)); }
+                Ok(format!("{}: {}", self.label(start), words.join(" ")))
+            },
+            Command::SetRegister(r, v) => {
+                vm.set_register(r, v).map_err(|e| e.to_string())?;
+                self.history.clear();
+                Ok(format!("{} = {}", r, v))
+            },
+            Command::SetMemory(loc, v) => {
+                let a = self.resolve(&loc)?;
+                vm.poke(a, v).map_err(|e| e.to_string())?;
+                self.history.clear();
+                Ok(format!("{} = {}", self.label(a), v))
+            },
+            Command::Jump(loc) => {
+                let a = self.resolve(&loc)?;
+                vm.set_instruction_pointer(a).map_err(|e| e.to_string())?;
+                self.history.clear();
+                Ok(self.location(vm))
+            },
+            Command::Disassemble(loc, count) => {
+                let (start, before) = match loc {
+                    Some(l) => (self.resolve(&l)?, 0),
+                    None => self.back_up(vm, vm.instruction_pointer(), CONTEXT_BEFORE)
+                };
+                Ok(self.disassemble(vm, start, count + before))
+            },
+            Command::Input(text) => {
+                vm.push_input(&text);
+                vm.push_input("\n");
+                Ok(format!("Queued `{}'", text))
+            },
+            Command::Watch(watched, kind, condition, action) => {
+                let target = match watched {
+                    Watched::Register(r) => WatchTarget::Register(r),
+                    Watched::Memory(loc) => WatchTarget::Memory(self.resolve(&loc)?)
+                };
+                let w = Watchpoint { target, kind, condition, action };
+                let id = vm.watchpoints().add(w);
+                Ok(format!("Watchpoint {}: {}", id, w))
+            },
+            Command::Unwatch(id) => match vm.watchpoints().remove(id) {
+                Some(w) => Ok(format!("Removed watchpoint {}: {}", id, w)),
+                None => Err(format!("No watchpoint {}", id))
+            },
+            Command::Watches => {
+                let lines : Vec<String> = vm.watchpoints().list().iter().map(|&(id, ref w)| format!("{}: {}", id, w)).collect();
+                if lines.is_empty() { return Ok(String::from("No watchpoints")); }
+                Ok(lines.join("\n"))
+            },
+            Command::ReverseStep(n) => {
+                let mut reason = StopReason::Stepped;
+                for _ in 0..n {
+                    reason = self.reverse_step(vm);
+                    if reason != StopReason::Stepped { break; }
+                }
+                Ok(self.report(vm, reason))
+            },
+            Command::ReverseContinue => { let r = self.reverse_continue(vm); Ok(self.report(vm, r)) },
+            Command::LastWrite(watched) => {
+                let target = match watched {
+                    Watched::Register(r) => WatchTarget::Register(r),
+                    Watched::Memory(loc) => WatchTarget::Memory(self.resolve(&loc)?)
+                };
+                match self.history.last_write(target) {
+                    Some(record) => Ok(format!("{} last written at step {} by\n{}",
+                                               target, record.step, self.disassemble(vm, record.instruction_pointer, 1))),
+                    None => Err(format!("{} hasn't been written in the last {} steps", target, self.history.len()))
+                }
+            },
+            Command::Help => Ok(String::from(HELP)),
+            Command::Quit => Ok(String::new())
+        }
+    }
+
+    /// Describe where we stopped, along with anything the program printed on the way
+    fn report(&self, vm: &mut VM, reason: StopReason) -> String {
+        let output = vm.drain_output();
+        let mut report = String::new();
+        if !output.is_empty() {
+            report.push_str(&output);
+            if !output.ends_with('\n') { report.push('\n'); }
+        }
+        for hit in vm.watchpoints().take_hits() {
+            report.push_str(&format!("{}\n", hit));
+        }
+        report.push_str(&format!("{}\n{}", reason, self.location(vm)));
+        match reason {
+            StopReason::Breakpoint(_) | StopReason::Error(_) => report.push_str(&format!("\n{}", self.backtrace(vm))),
+            _ => ()
+        }
+        return report;
+    }
+
+    /// The calls leading to where the VM is, innermost first
+    pub fn backtrace(&self, vm: &VM) -> String {
+        vm.call_stack().backtrace(vm.instruction_pointer(), &self.symbols)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+    use device::{BufferInput, BufferOutput};
+    use constants::*;
+
+    // @0  CALL 5
+    // @2  OUT 'a'
+    // @4  HALT
+    // @5  CALL 9
+    // @7  RET
+    // @8  NOOP
+    // @9  SET R0 7
+    // @12 RET
+    fn vm() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::CALL(Argument::new(5)),
+            Instruction::OUT(Argument::new(97)),
+            Instruction::HALT,
+            Instruction::CALL(Argument::new(9)),
+            Instruction::RET,
+            Instruction::NOOP,
+            Instruction::SET(Register::R0, Argument::new(7)),
+            Instruction::RET
+        ]).unwrap();
+        vm.start(Address::new(0));
+        return vm;
+    }
+
+    fn debugger() -> Debugger {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("inner", Address::new(9));
+        Debugger::new(symbols)
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("b 6027"), Ok(Command::Break(Location::At(Address::new(6027)))));
+        assert_eq!(Command::parse("break confirm"), Ok(Command::Break(Location::Label(String::from("confirm")))));
+        assert_eq!(Command::parse("s"), Ok(Command::Step(1)));
+        assert_eq!(Command::parse("step 10"), Ok(Command::Step(10)));
+        assert_eq!(Command::parse("set r7 25734"), Ok(Command::SetRegister(Register::R7, 25734)));
+        assert_eq!(Command::parse("set @100 3"), Ok(Command::SetMemory(Location::At(Address::new(100)), 3)));
+        assert_eq!(Command::parse("x 2732 10"), Ok(Command::Memory(Location::At(Address::new(2732)), 10)));
+        assert_eq!(Command::parse("input use teleporter"), Ok(Command::Input(String::from("use teleporter"))));
+        assert!(Command::parse("break").is_err());
+        assert!(Command::parse("frobnicate").is_err());
+    }
+
+    #[test]
+    fn breakpoint_by_label() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        db.execute(&mut vm, Command::Break(Location::Label(String::from("inner")))).unwrap();
+        assert_eq!(db.continue_execution(&mut vm), StopReason::Breakpoint(Address::new(9)));
+        assert_eq!(vm.stack(), &[2, 7]);
+    }
+
+    #[test]
+    fn unknown_label() {
+        let mut vm = vm();
+        let mut db = debugger();
+        assert!(db.execute(&mut vm, Command::Break(Location::Label(String::from("nope")))).is_err());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        assert_eq!(db.next(&mut vm), StopReason::Returned);
+        assert_eq!(vm.instruction_pointer(), Address::new(2));
+        assert_eq!(vm.register(Register::R0), 7);
+    }
+
+    #[test]
+    fn finish_runs_to_return() {
+        let mut vm = vm();
+        let mut db = debugger();
+        db.add_breakpoint(Address::new(9));
+        db.continue_execution(&mut vm);
+
+        assert_eq!(db.finish(&mut vm), StopReason::Returned);
+        assert_eq!(vm.instruction_pointer(), Address::new(7));
+    }
+
+    #[test]
+    fn continue_to_halt_with_output() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        let report = db.execute(&mut vm, Command::Continue).unwrap();
+        assert!(report.starts_with("a\nProgram halted"));
+    }
+
+    #[test]
+    fn set_and_inspect() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        db.execute(&mut vm, Command::SetRegister(Register::R7, 25734)).unwrap();
+        assert_eq!(vm.register(Register::R7), 25734);
+        assert_eq!(db.execute(&mut vm, Command::SetRegister(Register::R7, 40000)), Err(String::from("invalid value 40000")));
+
+        db.execute(&mut vm, Command::SetMemory(Location::At(Address::new(8)), 0)).unwrap();
+        assert_eq!(vm.peek(Address::new(8)), Ok(0));
+
+        let mem = db.execute(&mut vm, Command::Memory(Location::At(Address::new(0)), 3)).unwrap();
+        assert_eq!(mem, "@0: 17 5 19");
+    }
+
+    #[test]
+    fn disassemble_marks_ip_and_labels() {
+        let vm = vm();
+        let db = debugger();
+
+        let dis = db.disassemble(&vm, Address::new(0), 2);
+        assert_eq!(dis, "=>  @0: CALL 5\n    @2: OUT 97");
+
+        let dis = db.disassemble(&vm, Address::new(9), 1);
+        assert_eq!(dis, "    @9 <inner>: SET R0 7");
+    }
+
+    #[test]
+    fn disassemble_around_ip() {
+        let mut vm = vm();
+        let mut db = debugger();
+        vm.set_instruction_pointer(Address::new(7)).unwrap();
+
+        let dis = db.execute(&mut vm, Command::Disassemble(None, 2)).unwrap();
+        assert_eq!(dis, "    @2: OUT 97\n    @4: HALT\n    @5: CALL 9\n=>  @7: RET\n    @8: NOOP");
+
+        vm.set_instruction_pointer(Address::new(2)).unwrap();
+        let dis = db.execute(&mut vm, Command::Disassemble(None, 1)).unwrap();
+        assert_eq!(dis, "    @0: CALL 5\n=>  @2: OUT 97");
+    }
+
+    #[test]
+    fn stepping_after_halt() {
+        let mut vm = vm();
+        let mut db = debugger();
+        assert_eq!(db.continue_execution(&mut vm), StopReason::Halted);
+        let ip = vm.instruction_pointer();
+
+        assert_eq!(db.step(&mut vm), StopReason::Halted);
+        assert_eq!(db.next(&mut vm), StopReason::Halted);
+        assert_eq!(vm.instruction_pointer(), ip);
+    }
+
+    #[test]
+    fn parse_watch_commands() {
+        assert_eq!(Command::parse("watch R7 read"), Ok(Command::Watch(Watched::Register(Register::R7), WatchKind::Read, None, WatchAction::Break)));
+        assert_eq!(Command::parse("logwatch 100 write == 3"), Ok(Command::Watch(Watched::Memory(Location::At(Address::new(100))), WatchKind::Write, Some(Condition::Equal(3)), WatchAction::Log)));
+        assert_eq!(Command::parse("w R0 > 5"), Ok(Command::Watch(Watched::Register(Register::R0), WatchKind::ReadWrite, Some(Condition::GreaterThan(5)), WatchAction::Break)));
+        assert!(Command::parse("watch R0 ~ 5").is_err());
+        assert!(Command::parse("watch").is_err());
+    }
+
+    #[test]
+    fn watch_stops_execution() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        db.execute(&mut vm, Command::parse("watch R0 write").unwrap()).unwrap();
+        let report = db.execute(&mut vm, Command::Continue).unwrap();
+
+        assert!(report.contains("watchpoint 1: write R0 7 at @9"));
+        assert!(report.contains("Watchpoint triggered"));
+        assert_eq!(vm.instruction_pointer(), Address::new(12));
+    }
+
+    #[test]
+    fn waits_for_input() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![Instruction::IN(Argument::new(REGISTER_0))]).unwrap();
+        vm.start(Address::new(0));
+        let mut db = debugger();
+
+        assert_eq!(db.continue_execution(&mut vm), StopReason::AwaitingInput);
+        db.execute(&mut vm, Command::Input(String::from("x"))).unwrap();
+        assert_eq!(db.step(&mut vm), StopReason::Stepped);
+        assert_eq!(vm.register(Register::R0), 120);
+    }
+
+    #[test]
+    fn reverse_step_and_continue() {
+        let mut vm = vm();
+        let mut db = debugger();
+
+        assert_eq!(db.continue_execution(&mut vm), StopReason::Halted);
+        assert_eq!(Command::parse("rs 2"), Ok(Command::ReverseStep(2)));
+        db.execute(&mut vm, Command::ReverseStep(2)).unwrap();
+        assert_eq!(vm.instruction_pointer(), Address::new(2));
+        assert_eq!(vm.state(), VMState::RUN);
+
+        db.add_breakpoint(Address::new(9));
+        assert_eq!(db.reverse_continue(&mut vm), StopReason::Breakpoint(Address::new(9)));
+        assert_eq!(vm.register(Register::R0), 0);
+        assert_eq!(vm.stack(), &[2, 7]);
+
+        assert_eq!(db.reverse_continue(&mut vm), StopReason::StartOfHistory);
+        assert_eq!(vm.instruction_pointer(), Address::new(0));
+        assert!(vm.stack().is_empty());
+    }
+
+    #[test]
+    fn last_write() {
+        let mut vm = vm();
+        let mut db = debugger();
+        db.continue_execution(&mut vm);
+
+        let found = db.execute(&mut vm, Command::parse("lw r0").unwrap()).unwrap();
+        assert_eq!(found, "R0 last written at step 2 by\n    @9 <inner>: SET R0 7");
+        assert!(db.execute(&mut vm, Command::parse("lw r1").unwrap()).is_err());
+    }
+
+    #[test]
+    fn backtrace_at_breakpoint() {
+        let mut vm = vm();
+        let mut db = debugger();
+        db.add_breakpoint(Address::new(12));
+
+        let report = db.execute(&mut vm, Command::Continue).unwrap();
+        assert!(report.ends_with("#0 @12 <inner+3> in inner\n#1 @5 in @5\n#2 @0"));
+        assert_eq!(db.execute(&mut vm, Command::parse("bt").unwrap()).unwrap(), "#0 @12 <inner+3> in inner\n#1 @5 in @5\n#2 @0");
+    }
+
+    #[test]
+    fn reverse_step_over_return() {
+        let mut vm = vm();
+        let mut db = debugger();
+        db.add_breakpoint(Address::new(7));
+        db.continue_execution(&mut vm);
+        assert_eq!(vm.call_stack().depth(), 1);
+
+        db.reverse_step(&mut vm);
+        assert_eq!(vm.call_stack().depth(), 2);
+        db.reverse_step(&mut vm);
+        db.reverse_step(&mut vm);
+        assert_eq!(vm.call_stack().depth(), 1);
+    }
+
+    #[test]
+    fn next_over_native_call() {
+        let mut vm = vm();
+        vm.set_native(Address::new(5), |vm| vm.set_register(Register::R0, 3));
+        let mut db = debugger();
+
+        assert_eq!(db.next(&mut vm), StopReason::Returned);
+        assert_eq!(vm.instruction_pointer(), Address::new(2));
+        assert_eq!(vm.register(Register::R0), 3);
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+
+/// Something the VM can read bytes from when it executes an `IN` instruction.
+pub trait InputDevice : Send {
+    /// Read a single byte, returns `Ok(None)` when the device has no more input to give.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+/// Something the VM can write bytes to when it executes an `OUT` instruction.
+pub trait OutputDevice : Send {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    /// Push any buffered output through to wherever it's going. The VM calls this before
+    /// it blocks waiting on input, so prompts show up.
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+/// Reads from the process' stdin
+#[derive(Default)]
+pub struct StdinDevice;
+
+impl StdinDevice {
+    pub fn new() -> StdinDevice { StdinDevice }
+}
+
+impl InputDevice for StdinDevice {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let stdin = io::stdin();
+        let mut handle = stdin.lock();
+        read_one(&mut handle)
+    }
+}
+
+/// Writes to the process' stdout, buffered until a newline or an explicit flush.
+pub struct StdoutDevice {
+    out: BufWriter<io::Stdout>
+}
+
+impl StdoutDevice {
+    pub fn new() -> StdoutDevice { StdoutDevice { out: BufWriter::new(io::stdout()) } }
+}
+
+impl Default for StdoutDevice {
+    fn default() -> StdoutDevice { StdoutDevice::new() }
+}
+
+impl OutputDevice for StdoutDevice {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.out.write_all(&[byte])?;
+        if byte == b'\n' { self.out.flush()?; }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.out.flush() }
+}
+
+/// An in-memory queue of input. Clones share the same queue, so a caller can hold on to a
+/// handle and keep feeding input to a VM which owns another.
+#[derive(Clone, Default)]
+pub struct BufferInput {
+    buffer: Arc<Mutex<VecDeque<u8>>>
+}
+
+impl BufferInput {
+    pub fn new() -> BufferInput {
+        BufferInput { buffer: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    pub fn containing(s: &str) -> BufferInput {
+        let b = BufferInput::new();
+        b.push_str(s);
+        return b;
+    }
+
+    pub fn push_str(&self, s: &str) {
+        self.buffer.lock().unwrap().extend(s.bytes());
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+}
+
+impl InputDevice for BufferInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.buffer.lock().unwrap().pop_front())
+    }
+}
+
+/// An in-memory sink for output. Clones share the same buffer, so the contents can be
+/// inspected after handing the device to a VM.
+#[derive(Clone, Default)]
+pub struct BufferOutput {
+    buffer: Arc<Mutex<Vec<u8>>>
+}
+
+impl BufferOutput {
+    pub fn new() -> BufferOutput {
+        BufferOutput { buffer: Arc::new(Mutex::new(vec![])) }
+    }
+
+    /// Everything written so far, lossily converted to a string.
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.lock().unwrap()).into_owned()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+
+    /// Take everything written so far, leaving the buffer empty.
+    pub fn drain(&self) -> String {
+        let bytes : Vec<u8> = self.buffer.lock().unwrap().drain(..).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl OutputDevice for BufferOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.buffer.lock().unwrap().push(byte);
+        Ok(())
+    }
+}
+
+/// Reads input from a file
+pub struct FileInput {
+    reader: BufReader<File>
+}
+
+impl FileInput {
+    pub fn open(path: &str) -> io::Result<FileInput> {
+        let f = File::open(path)?;
+        Ok(FileInput { reader: BufReader::new(f) })
+    }
+}
+
+impl InputDevice for FileInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        read_one(&mut self.reader)
+    }
+}
+
+/// Writes output to a file, truncating it if it already exists.
+pub struct FileOutput {
+    writer: BufWriter<File>
+}
+
+impl FileOutput {
+    pub fn create(path: &str) -> io::Result<FileOutput> {
+        let f = File::create(path)?;
+        Ok(FileOutput { writer: BufWriter::new(f) })
+    }
+}
+
+impl OutputDevice for FileOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> { self.writer.flush() }
+}
+
+/// Copies everything written to both of the wrapped devices.
+pub struct TeeOutput {
+    left: Box<dyn OutputDevice>,
+    right: Box<dyn OutputDevice>
+}
+
+impl TeeOutput {
+    pub fn new(left: Box<dyn OutputDevice>, right: Box<dyn OutputDevice>) -> TeeOutput {
+        TeeOutput { left, right }
+    }
+}
+
+impl OutputDevice for TeeOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.left.write_byte(byte)?;
+        self.right.write_byte(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.left.flush()?;
+        self.right.flush()
+    }
+}
+
+/// Reads from the wrapped input device, echoing every byte read to an output device. Handy for
+/// keeping a transcript of what was typed.
+pub struct TeeInput {
+    input: Box<dyn InputDevice>,
+    echo: Box<dyn OutputDevice>
+}
+
+impl TeeInput {
+    pub fn new(input: Box<dyn InputDevice>, echo: Box<dyn OutputDevice>) -> TeeInput {
+        TeeInput { input, echo }
+    }
+}
+
+impl InputDevice for TeeInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let byte = self.input.read_byte()?;
+        if let Some(b) = byte {
+            self.echo.write_byte(b)?;
+            if b == b'\n' { self.echo.flush()?; }
+        }
+        Ok(byte)
+    }
+}
+
+/// Read a single byte from a reader, mapping EOF to `None`
+fn read_one<R: Read>(reader: &mut R) -> io::Result<Option<u8>> {
+    let mut buf : [u8; 1] = [0; 1];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_input_reads_in_order() {
+        let mut input = BufferInput::containing("ab");
+        assert_eq!(input.read_byte().unwrap(), Some(b'a'));
+        assert_eq!(input.read_byte().unwrap(), Some(b'b'));
+        assert_eq!(input.read_byte().unwrap(), None);
+    }
+
+    #[test]
+    fn buffer_input_clones_share_queue() {
+        let mut input = BufferInput::new();
+        let handle = input.clone();
+        handle.push_str("x");
+        assert_eq!(handle.remaining(), 1);
+        assert_eq!(input.read_byte().unwrap(), Some(b'x'));
+        assert_eq!(handle.remaining(), 0);
+    }
+
+    #[test]
+    fn buffer_output_collects() {
+        let out = BufferOutput::new();
+        let mut dev = out.clone();
+        dev.write_byte(b'h').unwrap();
+        dev.write_byte(b'i').unwrap();
+        assert_eq!(out.contents(), "hi");
+        assert_eq!(out.drain(), "hi");
+        assert_eq!(out.contents(), "");
+    }
+
+    #[test]
+    fn tee_output_writes_both() {
+        let a = BufferOutput::new();
+        let b = BufferOutput::new();
+        let mut tee = TeeOutput::new(Box::new(a.clone()), Box::new(b.clone()));
+        tee.write_byte(b'z').unwrap();
+        assert_eq!(a.contents(), "z");
+        assert_eq!(b.contents(), "z");
+    }
+
+    #[test]
+    fn tee_input_echoes() {
+        let echo = BufferOutput::new();
+        let mut tee = TeeInput::new(Box::new(BufferInput::containing("go\n")), Box::new(echo.clone()));
+        while tee.read_byte().unwrap().is_some() { }
+        assert_eq!(echo.contents(), "go\n");
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let name = format!("synacor-device-test-{}-file_round_trip", ::std::process::id());
+        let path = ::std::env::temp_dir().join(name);
+        let path = path.to_str().unwrap();
+        {
+            let mut out = FileOutput::create(path).unwrap();
+            out.write_byte(b'q').unwrap();
+            out.flush().unwrap();
+        }
+        let mut input = FileInput::open(path).unwrap();
+        assert_eq!(input.read_byte().unwrap(), Some(b'q'));
+        assert_eq!(input.read_byte().unwrap(), None);
+        let _ = ::std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,123 @@
+use std::error::Error;
+use std::fmt;
+
+use address::Address;
+use instruction::Instruction;
+use symbols::SymbolTable;
+use vm::VMError;
+
+/// How many words off the top of the stack a fault remembers
+pub const STACK_CONTEXT : usize = 8;
+
+/// The state of the VM when an instruction failed
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FaultContext {
+    /// where the failing instruction starts
+    pub address: Address,
+    /// the failing instruction, if it decoded
+    pub instruction: Option<Instruction>,
+    pub registers: [u16; 8],
+    /// the top of the stack, top first
+    pub stack_top: Vec<u16>,
+    pub stack_depth: usize,
+    /// how many instructions had completed before this one
+    pub step: u64,
+}
+
+impl FaultContext {
+    /// A crash report for `error`, naming addresses with `symbols` where we can
+    pub fn report(&self, error: &VMError, symbols: &SymbolTable) -> String {
+        let location = match symbols.describe(self.address) {
+            Some(name) => format!("{} <{}>", self.address, name),
+            None => format!("{}", self.address)
+        };
+        let instruction = match self.instruction {
+            Some(ref i) => format!("{}", i),
+            None => String::from("(does not decode)")
+        };
+        let registers : Vec<String> = self.registers.iter().enumerate().map(|(i, v)| format!("R{}={}", i, v)).collect();
+        let stack : Vec<String> = self.stack_top.iter().map(|v| v.to_string()).collect();
+        let more = if self.stack_depth > self.stack_top.len() { " ..." } else { "" };
+
+        let mut lines = vec![];
+        lines.push(format!("Fault: {}", error));
+        lines.push(format!("  at {}: {}", location, instruction));
+        lines.push(format!("  after {} steps", self.step));
+        lines.push(format!("  registers: {}", registers.join(" ")));
+        if self.stack_depth == 0 {
+            lines.push(String::from("  stack is empty"));
+        } else {
+            lines.push(format!("  stack ({} deep, top first): {}{}", self.stack_depth, stack.join(" "), more));
+        }
+        lines.join("\n")
+    }
+}
+
+impl fmt::Display for FaultContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = match self.instruction {
+            Some(ref i) => format!("{}", i),
+            None => String::from("?")
+        };
+        write!(f, "{}: {} after {} steps", self.address, instruction, self.step)
+    }
+}
+
+/// An instruction that failed: what went wrong, and the state of the VM when it did
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VMFault {
+    pub error: VMError,
+    pub context: FaultContext,
+}
+
+impl VMFault {
+    /// A crash report, naming addresses with `symbols` where we can
+    pub fn report(&self, symbols: &SymbolTable) -> String {
+        self.context.report(&self.error, symbols)
+    }
+}
+
+impl fmt::Display for VMFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.error, self.context)
+    }
+}
+
+impl Error for VMFault {}
+
+impl From<VMFault> for VMError {
+    fn from(fault: VMFault) -> VMError {
+        fault.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+
+    #[test]
+    fn report() {
+        let fault = FaultContext {
+            address: Address::new(12),
+            instruction: Some(Instruction::JMP(Argument::new(32775))),
+            registers: [1, 2, 3, 4, 5, 6, 7, 40000],
+            stack_top: vec![9, 8],
+            stack_depth: 2,
+            step: 3,
+        };
+        let mut symbols = SymbolTable::new();
+        symbols.insert("start", Address::new(10));
+
+        assert_eq!(fault.report(&VMError::JumpOutOfBounds(Address::new(40000)), &symbols), "\
+Fault: jump out of bounds to @40000
+  at @12 <start+2>: JMP R7
+  after 3 steps
+  registers: R0=1 R1=2 R2=3 R3=4 R4=5 R5=6 R6=7 R7=40000
+  stack (2 deep, top first): 9 8");
+        assert_eq!(format!("{}", fault), "@12: JMP R7 after 3 steps");
+
+        let fault = VMFault { error: VMError::StackUnderflow, context: fault };
+        assert_eq!(format!("{}", fault), "pop from an empty stack at @12: JMP R7 after 3 steps");
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::VecDeque;
+
+use address::Address;
+use register::Register;
+use callstack::Frame;
+use vm::{VM, VMState};
+use fault::VMFault;
+use watch::WatchTarget;
+
+/// What a VM needs to take back to undo a single instruction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct UndoRecord {
+    /// the VM's step count before the instruction ran
+    pub step: u64,
+    pub instruction_pointer: Address,
+    pub state: VMState,
+    pub registers: [u16; 8],
+    /// registers written by the instruction, whether or not the value changed
+    pub registers_written: Vec<Register>,
+    pub stack: StackChange,
+    /// memory written by the instruction, with the value it held before, in the order written
+    pub memory: Vec<(Address, u16)>,
+    /// a byte of input consumed by the instruction
+    pub input: Option<u8>,
+    /// call frames the instruction returned from
+    pub frames: Vec<Frame>,
+}
+
+/// How an instruction changed the stack: it took `removed` off the top of the stack it started
+/// with, then pushed `pushed` values. A `CALL` to a native routine can do any amount of both.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StackChange {
+    /// bottom first
+    pub removed: Vec<u16>,
+    pub pushed: usize,
+}
+
+/// Everything an instruction did that isn't visible from registers and the stack, as collected
+/// by the VM while journaling.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Journal {
+    pub registers: Vec<Register>,
+    /// the lowest the stack got, and the values popped to get there, top first
+    pub stack_low: usize,
+    pub popped: Vec<u16>,
+    pub memory: Vec<(Address, u16)>,
+    pub input: Option<u8>,
+    pub frames: Vec<Frame>,
+}
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal { registers: vec![], stack_low: 0, popped: vec![], memory: vec![], input: None, frames: vec![] }
+    }
+}
+
+/// How many instructions apart `History` takes checkpoints
+pub const CHECKPOINT_INTERVAL : u64 = 10_000;
+
+/// How many checkpoints `History` keeps, so how far back it can go
+pub const CHECKPOINT_LIMIT : usize = 100;
+
+/// A copy of the VM from before the instruction at `step`, and all the input read since
+struct Checkpoint {
+    vm: VM,
+    input: Vec<u8>,
+}
+
+/// A bounded log of undo records, one per instruction executed through it. Once full, the oldest
+/// records are dropped.
+///
+/// Every `CHECKPOINT_INTERVAL` instructions we also keep a copy of the VM, which shares memory
+/// with it until one of them writes. Rewinding past the oldest record replays from the latest
+/// checkpoint before it, with the same input, to recreate the records. That relies on the
+/// program doing the same thing again, so observers which change what it does will confuse it.
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    limit: usize,
+    checkpoints: VecDeque<Checkpoint>,
+}
+
+impl History {
+    pub fn new(limit: usize) -> History {
+        History { records: VecDeque::new(), limit, checkpoints: VecDeque::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.checkpoints.clear();
+    }
+
+    /// How many checkpoints are kept, see `CHECKPOINT_INTERVAL`
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Step the VM once, remembering how to undo it
+    pub fn step(&mut self, vm: &mut VM) -> Result<VMState, VMFault> {
+        let step = vm.step_count();
+        let due = match self.checkpoints.back() {
+            Some(c) => step >= c.vm.step_count() + CHECKPOINT_INTERVAL,
+            None => true
+        };
+        if due {
+            if self.checkpoints.len() == CHECKPOINT_LIMIT { self.checkpoints.pop_front(); }
+            self.checkpoints.push_back(Checkpoint { vm: vm.clone(), input: vec![] });
+        }
+
+        let result = self.record(vm);
+        if vm.step_count() > step {
+            if let Some(b) = self.records.back().and_then(|r| r.input) {
+                for c in self.checkpoints.iter_mut() { c.input.push(b); }
+            }
+        }
+        result
+    }
+
+    /// Step the VM once, adding an undo record for it
+    fn record(&mut self, vm: &mut VM) -> Result<VMState, VMFault> {
+        let step = vm.step_count();
+        let instruction_pointer = vm.instruction_pointer();
+        let state = vm.state();
+        let registers = vm.registers();
+
+        vm.begin_journal();
+        let result = vm.step();
+        let journal = vm.end_journal();
+
+        // a failed instruction, or an IN waiting for input, didn't get anywhere
+        match result {
+            Ok(VMState::AwaitingInput) | Err(_) => return result,
+            _ => ()
+        }
+
+        let stack = StackChange {
+            removed: journal.popped.iter().rev().cloned().collect(),
+            pushed: vm.stack().len() - journal.stack_low,
+        };
+
+        if self.records.len() == self.limit { self.records.pop_front(); }
+        if self.limit > 0 {
+            self.records.push_back(UndoRecord {
+                step,
+                instruction_pointer,
+                state,
+                registers,
+                registers_written: journal.registers,
+                stack,
+                memory: journal.memory,
+                input: journal.input,
+                frames: journal.frames,
+            });
+        }
+
+        return result;
+    }
+
+    /// Undo the most recent instruction, returning false if there's nothing left to undo. Output
+    /// the instruction produced is not taken back.
+    pub fn reverse_step(&mut self, vm: &mut VM) -> bool {
+        if self.records.is_empty() && !self.replay(vm) { return false; }
+
+        match self.records.pop_back() {
+            Some(record) => {
+                // checkpoints after this are of a future we're leaving behind
+                while let Some(c) = self.checkpoints.back() {
+                    if c.vm.step_count() <= record.step { break; }
+                    self.checkpoints.pop_back();
+                }
+                if record.input.is_some() {
+                    for c in self.checkpoints.iter_mut() { c.input.pop(); }
+                }
+                vm.undo(&record);
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Recreate the undo records leading up to where `vm` is, from the latest checkpoint before
+    /// it, returning false if there isn't one
+    fn replay(&mut self, vm: &VM) -> bool {
+        let mut fork = match self.checkpoints.iter().rev().find(|c| c.vm.step_count() < vm.step_count()) {
+            Some(c) => {
+                let mut fork = c.vm.clone();
+                fork.replace_input(&c.input);
+                fork
+            },
+            None => return false
+        };
+
+        fork.set_yield_on_input(true);
+        while fork.step_count() < vm.step_count() {
+            match self.record(&mut fork) {
+                Ok(VMState::RUN) | Ok(VMState::WatchpointHit) => (),
+                _ => break
+            }
+        }
+
+        let same = fork.step_count() == vm.step_count()
+            && fork.instruction_pointer() == vm.instruction_pointer()
+            && fork.registers() == vm.registers()
+            && fork.stack() == vm.stack();
+        if !same { self.records.clear(); }
+        same && !self.records.is_empty()
+    }
+
+    /// The most recent recorded instruction which wrote to the target, if it's still in the
+    /// history
+    pub fn last_write(&self, target: WatchTarget) -> Option<&UndoRecord> {
+        match target {
+            WatchTarget::Memory(a) => self.records.iter().rev().find(|r| r.memory.iter().any(|&(addr, _)| addr == a)),
+            WatchTarget::Register(reg) => self.records.iter().rev().find(|r| r.registers_written.contains(&reg))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+    use instruction::Instruction;
+    use register::Register;
+    use device::{BufferInput, BufferOutput};
+    use constants::*;
+
+    // @0  SET R0 5
+    // @3  PUSH R0
+    // @5  WMEM 100 R0
+    // @8  IN R1
+    // @10 WMEM 100 R1
+    // @13 POP R2
+    fn vm() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(5)),
+            Instruction::PUSH(Argument::new(REGISTER_0)),
+            Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_0)),
+            Instruction::IN(Argument::new(REGISTER_1)),
+            Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_1)),
+            Instruction::POP(Register::R2)
+        ]).unwrap();
+        vm.start(Address::new(0));
+        vm.set_yield_on_input(true);
+        vm.push_input("x");
+        return vm;
+    }
+
+    fn run(history: &mut History, vm: &mut VM) {
+        while history.step(vm) == Ok(VMState::RUN) { }
+    }
+
+    #[test]
+    fn rewind_everything() {
+        let mut vm = vm();
+        let before = vm.snapshot();
+        let mut history = History::new(100);
+
+        run(&mut history, &mut vm);
+        assert_eq!(vm.state(), VMState::HALT);
+        assert_eq!(history.len(), 7);
+
+        while history.reverse_step(&mut vm) { }
+
+        assert_eq!(vm.snapshot(), before);
+        assert_eq!(vm.step_count(), 0);
+    }
+
+    #[test]
+    fn rewind_and_replay() {
+        let mut vm = vm();
+        let mut history = History::new(100);
+        run(&mut history, &mut vm);
+        let after = vm.snapshot();
+
+        for _ in 0..4 { history.reverse_step(&mut vm); }
+        assert_eq!(vm.instruction_pointer(), Address::new(8));
+        assert_eq!(vm.peek(Address::new(100)), Ok(5));
+        assert_eq!(vm.stack(), &[5]);
+
+        run(&mut history, &mut vm);
+        assert_eq!(vm.snapshot(), after);
+    }
+
+    #[test]
+    fn limit() {
+        let mut vm = vm();
+        let before = vm.snapshot();
+        let mut history = History::new(2);
+        run(&mut history, &mut vm);
+        assert_eq!(history.len(), 2);
+
+        // the rest comes back from the checkpoint at the start
+        while history.reverse_step(&mut vm) { assert!(history.len() <= 2); }
+        assert_eq!(vm.snapshot(), before);
+        assert_eq!(vm.step_count(), 0);
+    }
+
+    #[test]
+    fn last_write() {
+        let mut vm = vm();
+        let mut history = History::new(100);
+        run(&mut history, &mut vm);
+
+        let mem = history.last_write(WatchTarget::Memory(Address::new(100))).unwrap();
+        assert_eq!(mem.instruction_pointer, Address::new(10));
+        assert_eq!(mem.memory, vec![(Address::new(100), 5)]);
+
+        let reg = history.last_write(WatchTarget::Register(Register::R0)).unwrap();
+        assert_eq!(reg.instruction_pointer, Address::new(0));
+        assert_eq!(reg.registers_written, vec![Register::R0]);
+
+        assert!(history.last_write(WatchTarget::Register(Register::R7)).is_none());
+        assert!(history.last_write(WatchTarget::Memory(Address::new(200))).is_none());
+    }
+
+    // @0 SET R0 5
+    // @3 SET R0 5
+    #[test]
+    fn last_write_of_the_same_value() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(5)),
+            Instruction::SET(Register::R0, Argument::new(5))
+        ]).unwrap();
+        vm.start(Address::new(0));
+        let mut history = History::new(100);
+        run(&mut history, &mut vm);
+
+        let reg = history.last_write(WatchTarget::Register(Register::R0)).unwrap();
+        assert_eq!(reg.instruction_pointer, Address::new(3));
+    }
+
+    // @0  IN R1
+    // @2  ADD R0 R0 1
+    // @6  WMEM 100 R0
+    // @9  JT R0 2
+    // @12 HALT
+    #[test]
+    fn rewind_past_the_limit() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::IN(Argument::new(REGISTER_1)),
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_0)),
+            Instruction::JT(Argument::new(REGISTER_0), Argument::new(2))
+        ]).unwrap();
+        vm.start(Address::new(0));
+        vm.set_yield_on_input(true);
+        vm.push_input("x");
+
+        let start = vm.snapshot();
+        let mut history = History::new(1000);
+        let mut middle = None;
+        for i in 0..25_000 {
+            if i == 12_345 { middle = Some(vm.snapshot()); }
+            assert_eq!(history.step(&mut vm), Ok(VMState::RUN));
+        }
+        assert_eq!(history.checkpoints(), 3);
+
+        for _ in 0..(25_000 - 12_345) { assert!(history.reverse_step(&mut vm)); }
+        assert_eq!(vm.step_count(), 12_345);
+        assert_eq!(Some(vm.snapshot()), middle);
+
+        while history.reverse_step(&mut vm) { }
+        assert_eq!(vm.snapshot(), start);
+        assert_eq!(history.checkpoints(), 1);
+    }
+
+    // @0 PUSH 1
+    // @2 PUSH 2
+    // @4 PUSH 3
+    // @6 CALL 100, where a native routine swaps the top two values for three others
+    #[test]
+    fn rewind_a_native_routine() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::PUSH(Argument::new(1)),
+            Instruction::PUSH(Argument::new(2)),
+            Instruction::PUSH(Argument::new(3)),
+            Instruction::CALL(Argument::new(100))
+        ]).unwrap();
+        vm.set_native(Address::new(100), |vm| {
+            vm.pop_stack()?;
+            vm.pop_stack()?;
+            for v in &[7, 8, 9] { vm.push_stack(*v)?; }
+            Ok(())
+        });
+        vm.start(Address::new(0));
+
+        let mut history = History::new(100);
+        for _ in 0..3 { history.step(&mut vm).unwrap(); }
+        let before = vm.snapshot();
+
+        history.step(&mut vm).unwrap();
+        assert_eq!(vm.stack(), &[1, 7, 8, 9]);
+
+        assert!(history.reverse_step(&mut vm));
+        assert_eq!(vm.snapshot(), before);
+        assert_eq!(vm.stack(), &[1, 2, 3]);
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt;
 
 use register::Register;
 use argument::Argument;
+use constants::*;
 
 
 /// Represents a machine instruction
@@ -63,6 +64,76 @@ impl Instruction {
     }
 
 
+    /// The instruction's arguments in order, with register-only positions given as
+    /// `Argument::Register`
+    pub fn arguments(&self) -> Vec<Argument> {
+        match *self {
+            Instruction::HALT | Instruction::RET | Instruction::NOOP => vec![],
+            Instruction::PUSH(a) | Instruction::JMP(a) | Instruction::CALL(a) |
+            Instruction::OUT(a) | Instruction::IN(a) => vec![a],
+            Instruction::POP(r) => vec![Argument::Register(r)],
+            Instruction::SET(r, a) | Instruction::NOT(r, a) | Instruction::RMEM(r, a) => vec![Argument::Register(r), a],
+            Instruction::JT(a, b) | Instruction::JF(a, b) | Instruction::WMEM(a, b) => vec![a, b],
+            Instruction::EQ(r, a, b) | Instruction::GT(r, a, b) | Instruction::ADD(r, a, b) |
+            Instruction::MULT(r, a, b) | Instruction::MOD(r, a, b) | Instruction::AND(r, a, b) |
+            Instruction::OR(r, a, b) => vec![Argument::Register(r), a, b],
+        }
+    }
+
+    /// The number of words the instruction takes up in memory
+    pub fn size(&self) -> u16 {
+        self.to_owned().to_u16_sequence().len() as u16
+    }
+
+    /// Checks that a sequence of 16b values can be decoded without tripping over a bad argument:
+    /// the opcode is known, there are enough arguments, every argument is a literal or register,
+    /// and register-only positions hold registers.
+    pub fn is_well_formed(seq: &[u16]) -> bool {
+        let opcode = match seq.first() {
+            Some(o) => *o,
+            None => return false
+        };
+        let arg_count = match Instruction::arg_count(opcode) {
+            Some(a) => a,
+            None => return false
+        };
+        if seq.len() < arg_count + 1 { return false; }
+
+        let args = &seq[1..arg_count + 1];
+        if args.iter().any(|a| *a > REGISTER_7) { return false; }
+
+        let register_first = [1, 3, 4, 5, 9, 10, 11, 12, 13, 14, 15].contains(&opcode);
+        return !register_first || args[0] >= REGISTER_0;
+    }
+
+    /// The opcode the instruction is encoded with
+    pub fn opcode(&self) -> u16 {
+        match *self {
+            Instruction::HALT           => 0,
+            Instruction::SET(_, _)      => 1,
+            Instruction::PUSH(_)        => 2,
+            Instruction::POP(_)         => 3,
+            Instruction::EQ(_, _, _)    => 4,
+            Instruction::GT(_, _, _)    => 5,
+            Instruction::JMP(_)         => 6,
+            Instruction::JT(_, _)       => 7,
+            Instruction::JF(_, _)       => 8,
+            Instruction::ADD(_, _, _)   => 9,
+            Instruction::MULT(_, _, _)  => 10,
+            Instruction::MOD(_, _, _)   => 11,
+            Instruction::AND(_, _, _)   => 12,
+            Instruction::OR(_, _, _)    => 13,
+            Instruction::NOT(_, _)      => 14,
+            Instruction::RMEM(_, _)     => 15,
+            Instruction::WMEM(_, _)     => 16,
+            Instruction::CALL(_)        => 17,
+            Instruction::RET            => 18,
+            Instruction::OUT(_)         => 19,
+            Instruction::IN(_)          => 20,
+            Instruction::NOOP           => 21
+        }
+    }
+
     /// Given an Instruction, produce it's opcode equivalent
     pub fn to_u16_sequence(self) -> Vec<u16> {
         match self {
@@ -1127,6 +1198,62 @@ mod tests {
         }
     }
 
+    mod well_formed {
+        use super::*;
+
+        #[test]
+        fn good() {
+            assert!(Instruction::is_well_formed(&[9, REGISTER_0, REGISTER_1, 4]));
+            assert!(Instruction::is_well_formed(&[21]));
+            assert!(Instruction::is_well_formed(&[19, 65, 99]));
+        }
+
+        #[test]
+        fn bad_opcode() {
+            assert!(!Instruction::is_well_formed(&[22]));
+            assert!(!Instruction::is_well_formed(&[]));
+        }
+
+        #[test]
+        fn too_short() {
+            assert!(!Instruction::is_well_formed(&[9, REGISTER_0, 4]));
+        }
+
+        #[test]
+        fn literal_in_register_position() {
+            assert!(!Instruction::is_well_formed(&[1, 5, 4]));
+        }
+
+        #[test]
+        fn invalid_argument() {
+            assert!(!Instruction::is_well_formed(&[19, 40000]));
+        }
+
+        #[test]
+        fn arguments() {
+            assert_eq!(Instruction::HALT.arguments(), vec![]);
+            assert_eq!(
+                Instruction::ADD(Register::R0, Argument::new(REGISTER_1), Argument::new(4)).arguments(),
+                vec![Argument::new(REGISTER_0), Argument::new(REGISTER_1), Argument::new(4)]
+            );
+            assert_eq!(Instruction::WMEM(Argument::new(5), Argument::new(6)).arguments(), vec![Argument::new(5), Argument::new(6)]);
+        }
+
+        #[test]
+        fn size() {
+            assert_eq!(Instruction::HALT.size(), 1);
+            assert_eq!(Instruction::JT(Argument::new(1), Argument::new(2)).size(), 3);
+        }
+
+        #[test]
+        fn opcode() {
+            for op in 0..22 {
+                let instruction = Instruction::from_u16_sequence(&vec![op, REGISTER_0, REGISTER_0, REGISTER_0]).unwrap();
+                assert_eq!(instruction.opcode(), op);
+            }
+        }
+    }
+
     mod from_u16_sequence {
         use super::*;
 
@@ -22,5 +22,27 @@ pub mod u15;
 pub mod instruction;
 pub mod argument;
 pub mod binary;
+pub mod bytes;
+pub mod device;
+pub mod snapshot;
+pub mod session;
+pub mod script;
+pub mod symbols;
+pub mod debugger;
+pub mod watch;
+pub mod trace;
+pub mod controls;
+pub mod history;
+pub mod callstack;
+pub mod cancel;
+pub mod pages;
+pub mod livelock;
+pub mod config;
+pub mod fault;
+pub mod profile;
+pub mod observer;
+pub mod teleporter;
+pub mod blocks;
+pub mod aot;
 pub mod vm;
 
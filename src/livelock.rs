@@ -0,0 +1,286 @@
+use std::cmp;
+use std::fmt;
+
+use address::Address;
+use vm::VM;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LoopKind {
+    /// The VM came back round to exactly the same state, so it will go round forever
+    RepeatedState,
+    /// The VM went a long time without changing memory, consuming input or producing output
+    NoProgress
+}
+
+/// Where a program got stuck
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LoopReport {
+    pub kind: LoopKind,
+    /// the lowest and highest addresses executed while going round
+    pub start: Address,
+    pub end: Address,
+    /// for `RepeatedState`, the steps taken to come back round; for `NoProgress`, the steps
+    /// taken without making any
+    pub steps: u64,
+}
+
+impl fmt::Display for LoopReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            LoopKind::RepeatedState => write!(f, "infinite loop between {} and {}, repeating every {} steps", self.start, self.end, self.steps),
+            LoopKind::NoProgress => write!(f, "no progress in {} steps, looping between {} and {}", self.steps, self.start, self.end)
+        }
+    }
+}
+
+/// Everything that decides what the VM does next, apart from memory, which we only know has
+/// changed by its generation
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct State {
+    instruction_pointer: Address,
+    registers: [u16; 8],
+    stack: Vec<u16>,
+    generation: u64,
+    step: u64,
+}
+
+impl State {
+    fn of(vm: &VM) -> State {
+        State {
+            instruction_pointer: vm.instruction_pointer(),
+            registers: vm.registers(),
+            stack: vm.stack().to_vec(),
+            generation: vm.memory_generation(),
+            step: vm.step_count(),
+        }
+    }
+
+    /// Cheapest comparisons first, the stack only gets looked at when everything else matches
+    fn matches(&self, vm: &VM) -> bool {
+        self.instruction_pointer == vm.instruction_pointer()
+            && self.generation == vm.memory_generation()
+            && self.registers == vm.registers()
+            && self.stack == *vm.stack()
+    }
+}
+
+/// Spots a VM going round in circles, see `VM::set_loop_detector`.
+///
+/// Any loop has to jump backwards at some point, so we sample the VM's state after every
+/// backwards jump and look for a repeat using Brent's algorithm: keep one sample, replacing it
+/// whenever the number of samples since it reaches the next power of two. Once the sample is
+/// inside the loop, and the loop is no longer than the power, we'll see it again.
+///
+/// Optionally, we also give up on a program which runs too long without visibly doing anything.
+#[derive(Debug, Clone)]
+pub struct LoopDetector {
+    repeated_state: bool,
+    no_progress_limit: Option<u64>,
+
+    saved: Option<State>,
+    power: u64,
+    samples: u64,
+    /// addresses executed since `saved`
+    low: u16,
+    high: u16,
+
+    progress: u64,
+    progress_step: u64,
+    /// addresses executed since the last progress
+    progress_low: u16,
+    progress_high: u16,
+}
+
+impl Default for LoopDetector {
+    fn default() -> LoopDetector {
+        LoopDetector::new()
+    }
+}
+
+impl LoopDetector {
+    /// Look for repeated states only
+    pub fn new() -> LoopDetector {
+        LoopDetector {
+            repeated_state: true,
+            no_progress_limit: None,
+            saved: None,
+            power: 1,
+            samples: 0,
+            low: u16::max_value(),
+            high: 0,
+            progress: 0,
+            progress_step: 0,
+            progress_low: u16::max_value(),
+            progress_high: 0,
+        }
+    }
+
+    /// Also stop a program after `steps` without changing memory, consuming input or producing
+    /// output. Long computations, like the teleporter check, look just like this too.
+    pub fn with_no_progress_limit(mut self, steps: u64) -> LoopDetector {
+        self.no_progress_limit = Some(steps);
+        self
+    }
+
+    /// Only use the no progress limit
+    pub fn without_repeated_state(mut self) -> LoopDetector {
+        self.repeated_state = false;
+        self
+    }
+
+    /// Forget everything seen so far
+    pub fn reset(&mut self) {
+        *self = LoopDetector { repeated_state: self.repeated_state, no_progress_limit: self.no_progress_limit, ..LoopDetector::new() };
+    }
+
+    /// Look at the VM after it executed the instruction at `address`
+    pub fn check(&mut self, address: Address, vm: &VM) -> Option<LoopReport> {
+        let a = address.value();
+
+        if let Some(limit) = self.no_progress_limit {
+            let progress = vm.memory_generation() + vm.output_count();
+            if progress != self.progress || self.progress_step == 0 {
+                self.progress = progress;
+                self.progress_step = vm.step_count();
+                self.progress_low = a;
+                self.progress_high = a;
+            } else {
+                self.progress_low = cmp::min(self.progress_low, a);
+                self.progress_high = cmp::max(self.progress_high, a);
+                let steps = vm.step_count() - self.progress_step;
+                if steps >= limit {
+                    return Some(LoopReport { kind: LoopKind::NoProgress, start: Address::new(self.progress_low), end: Address::new(self.progress_high), steps });
+                }
+            }
+        }
+
+        if !self.repeated_state { return None; }
+
+        self.low = cmp::min(self.low, a);
+        self.high = cmp::max(self.high, a);
+        if vm.instruction_pointer().value() > a { return None; }
+
+        if let Some(ref saved) = self.saved {
+            if saved.matches(vm) {
+                return Some(LoopReport {
+                    kind: LoopKind::RepeatedState,
+                    start: Address::new(self.low),
+                    end: Address::new(self.high),
+                    steps: vm.step_count() - saved.step
+                });
+            }
+        }
+
+        self.samples += 1;
+        if self.saved.is_none() || self.samples >= self.power {
+            self.saved = Some(State::of(vm));
+            self.power *= 2;
+            self.samples = 0;
+            self.low = u16::max_value();
+            self.high = 0;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+    use register::Register;
+    use instruction::Instruction;
+    use device::{BufferInput, BufferOutput};
+    use vm::{VMState, VMError};
+    use fault::VMFault;
+    use constants::*;
+
+    fn vm(program: Vec<Instruction>) -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &program).unwrap();
+        return vm;
+    }
+
+    fn stuck(result: Result<VMState, VMFault>) -> LoopReport {
+        match result {
+            Err(VMFault { error: VMError::Livelock(report), .. }) => report,
+            other => panic!("expected a livelock, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn jump_to_self() {
+        let mut vm = vm(vec![Instruction::NOOP, Instruction::JMP(Argument::new(1))]);
+        vm.set_loop_detector(Some(LoopDetector::new()));
+
+        let report = stuck(vm.run(Address::new(0)));
+        assert_eq!(report, LoopReport { kind: LoopKind::RepeatedState, start: Address::new(1), end: Address::new(1), steps: 1 });
+    }
+
+    // @0 ADD R0 R0 1
+    // @4 MOD R0 R0 3
+    // @8 OUT 'a'
+    // @10 JMP 0
+    #[test]
+    fn loop_with_a_counter() {
+        let mut vm = vm(vec![
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::MOD(Register::R0, Argument::new(REGISTER_0), Argument::new(3)),
+            Instruction::OUT(Argument::new(97)),
+            Instruction::JMP(Argument::new(0))
+        ]);
+        vm.set_loop_detector(Some(LoopDetector::new()));
+
+        let report = stuck(vm.run(Address::new(0)));
+        assert_eq!(report.kind, LoopKind::RepeatedState);
+        assert_eq!((report.start, report.end), (Address::new(0), Address::new(10)));
+        assert_eq!(report.steps, 12);
+    }
+
+    // @0 ADD R0 R0 1
+    // @4 WMEM 100 R0
+    // @7 JMP 0
+    #[test]
+    fn writing_memory_is_not_a_repeat() {
+        let mut vm = vm(vec![
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_0)),
+            Instruction::JMP(Argument::new(0))
+        ]);
+        vm.set_loop_detector(Some(LoopDetector::new()));
+        vm.start(Address::new(0));
+        assert_eq!(vm.run_for(100_000), Ok(VMState::BudgetExhausted));
+    }
+
+    // @0 ADD R0 R0 1
+    // @4 JT R0 0
+    // @7 ADD R1 R1 1
+    // @11 JMP 0
+    #[test]
+    fn no_progress() {
+        let mut vm = vm(vec![
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::JT(Argument::new(REGISTER_0), Argument::new(0)),
+            Instruction::ADD(Register::R1, Argument::new(REGISTER_1), Argument::new(1)),
+            Instruction::JMP(Argument::new(0))
+        ]);
+        vm.set_loop_detector(Some(LoopDetector::new().without_repeated_state().with_no_progress_limit(1000)));
+
+        let report = stuck(vm.run(Address::new(0)));
+        assert_eq!(report.kind, LoopKind::NoProgress);
+        assert_eq!((report.start, report.end), (Address::new(0), Address::new(4)));
+        assert_eq!(report.steps, 1000);
+    }
+
+    #[test]
+    fn halting_programs_are_fine() {
+        let mut vm = vm(vec![
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::EQ(Register::R1, Argument::new(REGISTER_0), Argument::new(1000)),
+            Instruction::JF(Argument::new(REGISTER_1), Argument::new(0)),
+            Instruction::HALT
+        ]);
+        vm.set_loop_detector(Some(LoopDetector::new()));
+        assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+    }
+}
@@ -0,0 +1,70 @@
+use address::Address;
+use register::Register;
+use instruction::Instruction;
+use vm::{VMState, VMError};
+
+/// What to do with the instruction about to execute
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Verdict {
+    Continue,
+    /// Carry on with the next instruction as if this one were a NOOP
+    Skip,
+    /// Halt the VM instead of executing it
+    Halt
+}
+
+/// Hooks into everything the running program does, for tracing, coverage, profiling and so on
+/// outside the VM. Every method has a default which changes nothing, so implement just the
+/// ones you need.
+///
+/// With several observers on a VM, they're called in the order they were added, each seeing the
+/// value left by the one before. A veto stops the chain. Accesses made through the inspection
+/// methods (`peek`, `poke`, `set_register` etc) and instruction fetch aren't observed.
+pub trait VmObserver: Send {
+    /// Called once the instruction at `address` is decoded, before it runs
+    fn before_instruction(&mut self, _address: Address, _instruction: &Instruction) -> Verdict {
+        Verdict::Continue
+    }
+
+    /// Called after the instruction at `address` ran, or failed to. An `IN` waiting for input
+    /// reports `AwaitingInput` here, and is seen again when the VM resumes.
+    fn after_instruction(&mut self, _address: Address, _instruction: &Instruction, _result: &Result<VMState, VMError>) {}
+
+    /// A read of memory by the program, return the value the program should see
+    fn memory_read(&mut self, _address: Address, value: u16) -> u16 {
+        value
+    }
+
+    /// A write to memory by the program, return the value to write or `None` to drop the write
+    fn memory_write(&mut self, _address: Address, value: u16) -> Option<u16> {
+        Some(value)
+    }
+
+    /// A write to a register, return the value to write or `None` to drop the write
+    fn register_write(&mut self, _register: Register, value: u16) -> Option<u16> {
+        Some(value)
+    }
+
+    /// A value pushed on the stack, including return addresses pushed by `CALL`
+    fn push(&mut self, value: u16) -> u16 {
+        value
+    }
+
+    /// A value popped off the stack, including return addresses popped by `RET`
+    fn pop(&mut self, value: u16) -> u16 {
+        value
+    }
+
+    /// A character output by the program, return the byte to output or `None` to swallow it
+    fn output(&mut self, byte: u8) -> Option<u8> {
+        Some(byte)
+    }
+
+    /// The program executed an `IN`. Return a byte to hand it that instead of reading input.
+    fn input_requested(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// A byte the program read with `IN`, wherever it came from
+    fn input(&mut self, _byte: u8) {}
+}
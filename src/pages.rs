@@ -0,0 +1,105 @@
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+/// How many entries go in a page
+pub const PAGE_SIZE : usize = 256;
+
+/// A fixed length array split into pages which clones share. Cloning copies a pointer per page,
+/// and a page is only copied when one of the arrays sharing it is written to.
+#[derive(Debug, Clone)]
+pub struct Pages<T: Clone> {
+    pages: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T: Clone> Pages<T> {
+    /// `len` copies of `value`, all on one shared page until written
+    pub fn new(len: usize, value: T) -> Pages<T> {
+        let page = Arc::new(vec![value; PAGE_SIZE]);
+        Pages { pages: vec![page; (len + PAGE_SIZE - 1) / PAGE_SIZE], len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, i: usize) -> Option<&T> {
+        if i >= self.len { return None; }
+        Some(&self.pages[i / PAGE_SIZE][i % PAGE_SIZE])
+    }
+
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = &'a T> + 'a {
+        self.pages.iter().flat_map(|p| p.iter()).take(self.len)
+    }
+
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    /// How many pages are still shared with `other`
+    pub fn shared_with(&self, other: &Pages<T>) -> usize {
+        self.pages.iter().zip(other.pages.iter()).filter(|&(a, b)| Arc::ptr_eq(a, b)).count()
+    }
+}
+
+impl<T: Clone> Index<usize> for Pages<T> {
+    type Output = T;
+
+    fn index(&self, i: usize) -> &T {
+        match self.get(i) {
+            Some(v) => v,
+            None => panic!("index {} out of range for {} entries", i, self.len)
+        }
+    }
+}
+
+impl<T: Clone> IndexMut<usize> for Pages<T> {
+    /// Copies the page first if it's shared
+    fn index_mut(&mut self, i: usize) -> &mut T {
+        if i >= self.len { panic!("index {} out of range for {} entries", i, self.len); }
+        &mut Arc::make_mut(&mut self.pages[i / PAGE_SIZE])[i % PAGE_SIZE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_and_writes() {
+        let mut pages = Pages::new(1000, 0u16);
+        pages[0] = 1;
+        pages[999] = 2;
+        assert_eq!(pages[0], 1);
+        assert_eq!(pages.get(999), Some(&2));
+        assert_eq!(pages.get(1000), None);
+        assert_eq!(pages.iter().count(), 1000);
+        assert_eq!(pages.to_vec().iter().map(|&v| v as u32).sum::<u32>(), 3);
+        assert!(!pages.is_empty());
+        assert!(Pages::new(0, 0u16).is_empty());
+    }
+
+    #[test]
+    fn clones_copy_on_write() {
+        let mut pages = Pages::new(PAGE_SIZE * 4, 0u16);
+        pages[0] = 1;
+        let mut fork = pages.clone();
+        assert_eq!(fork.shared_with(&pages), 4);
+
+        fork[PAGE_SIZE + 1] = 5;
+        assert_eq!(fork.shared_with(&pages), 3);
+        assert_eq!(pages[PAGE_SIZE + 1], 0);
+        assert_eq!(fork[0], 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn out_of_range() {
+        let mut pages = Pages::new(10, 0u16);
+        pages[10] = 1;
+    }
+}
@@ -0,0 +1,465 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use address::Address;
+use instruction::Instruction;
+use observer::{VmObserver, Verdict};
+use symbols::SymbolTable;
+use vm::{VMState, VMError};
+use constants::*;
+
+/// Execution counts gathered by a `Profiler`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Profile {
+    /// instructions executed, indexed by address
+    pub addresses: Vec<u64>,
+    /// instructions executed by opcode name
+    pub opcodes: BTreeMap<&'static str, u64>,
+    /// every call stack seen, as a tree of frames. A frame's parent always comes before it.
+    pub frames: Vec<Frame>,
+    pub total: u64,
+}
+
+/// One function on a call stack, below the frame it was called from. Functions are named by the
+/// address they were called at, the outermost one by wherever profiling started.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Frame {
+    pub parent: Option<usize>,
+    pub address: u16,
+    /// instructions executed in the function itself under this call stack
+    pub count: u64,
+}
+
+/// Instructions counted against one function
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FunctionCount {
+    pub address: Address,
+    /// executed in the function itself
+    pub own: u64,
+    /// executed in the function or anything it called
+    pub total: u64,
+}
+
+/// An address in a function, relative to the closest symbol
+fn location(address: Address, symbols: &SymbolTable) -> String {
+    symbols.describe(address).unwrap_or_else(|| format!("{}", address))
+}
+
+/// A function, by its own name if it has one
+fn function(address: Address, symbols: &SymbolTable) -> String {
+    symbols.name_of(address).map(String::from).unwrap_or_else(|| format!("{}", address))
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { 100.0 * count as f64 / total as f64 }
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        Profile::new()
+    }
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile { addresses: vec![0; MODULUS as usize], opcodes: BTreeMap::new(), frames: vec![], total: 0 }
+    }
+
+    /// The call stack a frame belongs to, outermost function first
+    pub fn stack(&self, frame: usize) -> Vec<u16> {
+        let mut stack = vec![];
+        let mut next = Some(frame);
+        while let Some(f) = next {
+            stack.push(self.frames[f].address);
+            next = self.frames[f].parent;
+        }
+        stack.reverse();
+        return stack;
+    }
+
+    /// Walk the frames depth first, calling `visit` with each frame and `true` on the way in, and
+    /// with `false` once everything it called has been visited
+    fn walk<F: FnMut(usize, bool)>(&self, mut visit: F) {
+        let mut children = vec![vec![]; self.frames.len()];
+        let mut roots = vec![];
+        for (i, frame) in self.frames.iter().enumerate() {
+            match frame.parent {
+                Some(p) => children[p].push(i),
+                None => roots.push(i)
+            }
+        }
+
+        for root in roots {
+            // each frame on the way down, with how many of its children have been visited
+            let mut path = vec![(root, 0)];
+            visit(root, true);
+            while let Some(&mut (frame, ref mut next)) = path.last_mut() {
+                if *next < children[frame].len() {
+                    let child = children[frame][*next];
+                    *next += 1;
+                    path.push((child, 0));
+                    visit(child, true);
+                } else {
+                    path.pop();
+                    visit(frame, false);
+                }
+            }
+        }
+    }
+
+    /// The `n` most executed addresses, most executed first
+    pub fn hottest(&self, n: usize) -> Vec<(Address, u64)> {
+        let mut hot : Vec<(Address, u64)> = self.addresses.iter().enumerate()
+            .filter(|&(_, &c)| c > 0)
+            .map(|(a, &c)| (Address::new(a as u16), c))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(n);
+        return hot;
+    }
+
+    /// Every function seen, by most instructions executed in total first. A recursive function
+    /// only counts once towards its own total.
+    pub fn functions(&self) -> Vec<FunctionCount> {
+        // instructions executed in each frame or anything it called; children come after parents
+        let mut below : Vec<u64> = self.frames.iter().map(|f| f.count).collect();
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            if let Some(p) = frame.parent { below[p] += below[i]; }
+        }
+
+        let mut counts : BTreeMap<u16, (u64, u64)> = BTreeMap::new();
+        let mut on_stack : HashMap<u16, usize> = HashMap::new();
+        self.walk(|i, entering| {
+            let address = self.frames[i].address;
+            let depth = on_stack.entry(address).or_insert(0);
+            if !entering {
+                *depth -= 1;
+                return;
+            }
+
+            let count = counts.entry(address).or_insert((0, 0));
+            count.0 += self.frames[i].count;
+            if *depth == 0 { count.1 += below[i]; }
+            *depth += 1;
+        });
+
+        let mut functions : Vec<FunctionCount> = counts.into_iter()
+            .filter(|&(_, (_, total))| total > 0)
+            .map(|(a, (own, total))| FunctionCount { address: Address::new(a), own, total })
+            .collect();
+        functions.sort_by(|a, b| b.total.cmp(&a.total).then(b.own.cmp(&a.own)).then(a.address.cmp(&b.address)));
+        return functions;
+    }
+
+    /// A human readable summary of where the time went, showing the top `n` of each table
+    pub fn report(&self, symbols: &SymbolTable, n: usize) -> String {
+        let mut out = format!("Executed {} instructions\n", self.total);
+
+        out.push_str("\nHottest addresses:\n");
+        out.push_str(&format!("{:>12} {:>7}  address\n", "count", "%"));
+        for (a, c) in self.hottest(n) {
+            out.push_str(&format!("{:>12} {:>6.2}%  {}\n", c, percent(c, self.total), location(a, symbols)));
+        }
+
+        out.push_str("\nFunctions:\n");
+        out.push_str(&format!("{:>12} {:>7} {:>12} {:>7}  function\n", "self", "%", "total", "%"));
+        for f in self.functions().into_iter().take(n) {
+            out.push_str(&format!("{:>12} {:>6.2}% {:>12} {:>6.2}%  {}\n",
+                                  f.own, percent(f.own, self.total), f.total, percent(f.total, self.total), function(f.address, symbols)));
+        }
+
+        out.push_str("\nOpcodes:\n");
+        out.push_str(&format!("{:>12} {:>7}  opcode\n", "count", "%"));
+        let mut opcodes : Vec<(&&'static str, &u64)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1));
+        for (op, &c) in opcodes {
+            out.push_str(&format!("{:>12} {:>6.2}%  {}\n", c, percent(c, self.total), op));
+        }
+
+        return out;
+    }
+
+    /// Write the call stacks in the collapsed format flamegraph tools read, one
+    /// `outer;inner;innermost COUNT` line per stack.
+    pub fn write_collapsed<W: Write>(&self, w: &mut W, symbols: &SymbolTable) -> io::Result<()> {
+        let mut names : Vec<String> = vec![];
+        let mut lines : Vec<(String, u64)> = vec![];
+        self.walk(|i, entering| {
+            if !entering {
+                names.pop();
+                return;
+            }
+
+            let frame = &self.frames[i];
+            names.push(function(Address::new(frame.address), symbols));
+            if frame.count > 0 { lines.push((names.join(";"), frame.count)); }
+        });
+        lines.sort();
+
+        for (stack, count) in lines {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The name of an opcode, going by an instruction with a register for every argument
+fn opcode_name(opcode: u16) -> &'static str {
+    Instruction::from_u16_sequence(&vec![opcode, REGISTER_0, REGISTER_0, REGISTER_0]).unwrap().name()
+}
+
+/// The call stacks seen so far, with each frame's counter
+#[derive(Default)]
+struct Frames {
+    frames: Vec<(Option<usize>, u16, Arc<AtomicU64>)>,
+    index: HashMap<(Option<usize>, u16), usize>,
+}
+
+/// The counts a `Profiler` is gathering. They're shared, so they can be read while the profiler
+/// belongs to the VM.
+///
+/// Instructions are counted without taking a lock; the call stacks are only locked when the
+/// program calls into a function.
+pub struct Counts {
+    addresses: Vec<AtomicU64>,
+    opcodes: Vec<AtomicU64>,
+    frames: Mutex<Frames>,
+}
+
+impl Counts {
+    fn new() -> Counts {
+        Counts {
+            addresses: (0..MODULUS).map(|_| AtomicU64::new(0)).collect(),
+            opcodes: (0..22).map(|_| AtomicU64::new(0)).collect(),
+            frames: Mutex::new(Frames::default()),
+        }
+    }
+
+    /// The frame for a function at `address` called from `parent`, with its counter
+    fn enter(&self, parent: Option<usize>, address: u16) -> (usize, Arc<AtomicU64>) {
+        let mut frames = self.frames.lock().unwrap();
+        if let Some(&i) = frames.index.get(&(parent, address)) {
+            return (i, frames.frames[i].2.clone());
+        }
+
+        let i = frames.frames.len();
+        let count = Arc::new(AtomicU64::new(0));
+        frames.frames.push((parent, address, count.clone()));
+        frames.index.insert((parent, address), i);
+        return (i, count);
+    }
+
+    /// The counts so far
+    pub fn snapshot(&self) -> Profile {
+        let addresses : Vec<u64> = self.addresses.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+
+        let mut opcodes = BTreeMap::new();
+        for (op, c) in self.opcodes.iter().enumerate() {
+            let c = c.load(Ordering::Relaxed);
+            if c > 0 { opcodes.insert(opcode_name(op as u16), c); }
+        }
+
+        let frames = self.frames.lock().unwrap().frames.iter()
+            .map(|&(parent, address, ref count)| Frame { parent, address, count: count.load(Ordering::Relaxed) })
+            .collect();
+
+        let total = addresses.iter().sum();
+        Profile { addresses, opcodes, frames, total }
+    }
+}
+
+/// Counts the instructions a VM executes, as a `VmObserver`. Functions are tracked by watching
+/// `CALL` and `RET`: the first instruction after a `CALL` is the function's entry point, unless
+/// it's the return address, in which case the call went to a native routine.
+pub struct Profiler {
+    counts: Arc<Counts>,
+    /// frames on the current call stack, innermost last, with their counters
+    stack: Vec<(usize, Arc<AtomicU64>)>,
+    /// return address of a `CALL` that just executed, until we see where it went
+    pending_call: Option<Address>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler { counts: Arc::new(Counts::new()), stack: vec![], pending_call: None }
+    }
+
+    pub fn profile(&self) -> Arc<Counts> {
+        return self.counts.clone();
+    }
+}
+
+impl VmObserver for Profiler {
+    fn before_instruction(&mut self, address: Address, _instruction: &Instruction) -> Verdict {
+        if self.stack.is_empty() {
+            let frame = self.counts.enter(None, address.value());
+            self.stack.push(frame);
+        }
+
+        if let Some(ret) = self.pending_call.take() {
+            if address != ret {
+                let parent = self.stack.last().map(|f| f.0);
+                let frame = self.counts.enter(parent, address.value());
+                self.stack.push(frame);
+            }
+        }
+
+        Verdict::Continue
+    }
+
+    fn after_instruction(&mut self, address: Address, instruction: &Instruction, result: &Result<VMState, VMError>) {
+        // an IN waiting for input is seen again when it runs
+        match *result {
+            Ok(VMState::AwaitingInput) | Err(_) => return,
+            Ok(_) => ()
+        }
+
+        self.counts.addresses[address.value() as usize].fetch_add(1, Ordering::Relaxed);
+        self.counts.opcodes[instruction.opcode() as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some((_, count)) = self.stack.last() {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        match *instruction {
+            Instruction::CALL(_) => self.pending_call = Some(Address::new(address.value() + instruction.size())),
+            Instruction::RET if self.stack.len() > 1 => { self.stack.pop(); },
+            _ => ()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+    use register::Register;
+    use device::{BufferInput, BufferOutput};
+    use vm::VM;
+
+    // @0  CALL 10
+    // @2  CALL 10
+    // @4  CALL 20
+    // @6  HALT
+    // @10 ADD R0 R0 1
+    // @14 RET
+    // @20 CALL 10
+    // @22 RET
+    fn profiled() -> (VM, Arc<Counts>) {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::CALL(Argument::new(10)),
+            Instruction::CALL(Argument::new(10)),
+            Instruction::CALL(Argument::new(20)),
+            Instruction::HALT
+        ]).unwrap();
+        vm.load_instructions(Address::new(10), &vec![
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+            Instruction::RET
+        ]).unwrap();
+        vm.load_instructions(Address::new(20), &vec![
+            Instruction::CALL(Argument::new(10)),
+            Instruction::RET
+        ]).unwrap();
+
+        let profiler = Profiler::new();
+        let profile = profiler.profile();
+        vm.add_observer(Box::new(profiler));
+        return (vm, profile);
+    }
+
+    #[test]
+    fn counts() {
+        let (mut vm, profile) = profiled();
+        assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+
+        let profile = profile.snapshot();
+        assert_eq!(profile.total, 12);
+        assert_eq!(profile.addresses[10], 3);
+        assert_eq!(profile.opcodes.get("CALL"), Some(&4));
+        assert_eq!(profile.hottest(2), vec![(Address::new(10), 3), (Address::new(14), 3)]);
+    }
+
+    #[test]
+    fn functions() {
+        let (mut vm, profile) = profiled();
+        vm.run(Address::new(0)).unwrap();
+
+        let profile = profile.snapshot();
+        let functions = profile.functions();
+        assert_eq!(functions[0], FunctionCount { address: Address::new(0), own: 4, total: 12 });
+        assert_eq!(functions[1], FunctionCount { address: Address::new(10), own: 6, total: 6 });
+        assert_eq!(functions[2], FunctionCount { address: Address::new(20), own: 2, total: 4 });
+    }
+
+    #[test]
+    fn collapsed_stacks() {
+        let (mut vm, profile) = profiled();
+        vm.run(Address::new(0)).unwrap();
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("increment", Address::new(10));
+        let mut out = vec![];
+        profile.snapshot().write_collapsed(&mut out, &symbols).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "@0 4\n@0;@20 2\n@0;@20;increment 2\n@0;increment 4\n");
+    }
+
+    #[test]
+    fn native_calls_stay_in_the_caller() {
+        let (mut vm, profile) = profiled();
+        vm.set_native(Address::new(20), |_| Ok(()));
+        vm.run(Address::new(0)).unwrap();
+
+        let profile = profile.snapshot();
+        assert_eq!(profile.frames, vec![
+            Frame { parent: None, address: 0, count: 4 },
+            Frame { parent: Some(0), address: 10, count: 4 },
+        ]);
+    }
+
+    // @0  SET R0 3
+    // @3  CALL 10
+    // @5  HALT
+    // @10 JF R0 19
+    // @13 ADD R0 R0 32767
+    // @17 CALL 10
+    // @19 RET
+    #[test]
+    fn recursion() {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(3)),
+            Instruction::CALL(Argument::new(10)),
+            Instruction::HALT
+        ]).unwrap();
+        vm.load_instructions(Address::new(10), &vec![
+            Instruction::JF(Argument::new(REGISTER_0), Argument::new(19)),
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(32767)),
+            Instruction::CALL(Argument::new(10)),
+            Instruction::RET
+        ]).unwrap();
+        let profiler = Profiler::new();
+        let counts = profiler.profile();
+        vm.add_observer(Box::new(profiler));
+        assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+
+        let profile = counts.snapshot();
+        assert_eq!(profile.total, 17);
+        assert_eq!(profile.frames.len(), 5);
+        assert_eq!(profile.stack(4), vec![0, 10, 10, 10, 10]);
+        assert_eq!(profile.frames[4].count, 2);
+        assert_eq!(profile.functions(), vec![
+            FunctionCount { address: Address::new(0), own: 3, total: 17 },
+            FunctionCount { address: Address::new(10), own: 14, total: 14 },
+        ]);
+    }
+}
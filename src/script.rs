@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::prelude::*;
+
+/// One thing an `InputScript` does
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ScriptLine {
+    /// A line to hand the program, without its newline
+    Input(String),
+    /// Wait for the user before going on
+    Pause,
+    /// Save a snapshot of the VM to this file
+    Snapshot(String),
+}
+
+/// Lines of input to play through a program, like `data/answers`, loaded from a file with one
+/// command per line, with spaces around it trimmed. Blank lines and lines starting with `#` are
+/// ignored, apart from the directives `#pause` and `#snapshot NAME`, eg:
+///
+/// ```text
+/// # get the tablet
+/// take tablet
+/// use tablet
+/// #snapshot start.snap
+/// #pause
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct InputScript {
+    lines: VecDeque<ScriptLine>,
+}
+
+impl InputScript {
+    pub fn load(path: &str) -> Result<InputScript, String> {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => InputScript::parse(&contents),
+            Err(e) => Err(format!("Could not read `{}': {}", path, e))
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<InputScript, String> {
+        let mut lines = VecDeque::new();
+
+        for (n, raw) in contents.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() { continue; }
+
+            if !line.starts_with('#') {
+                lines.push_back(ScriptLine::Input(line.to_owned()));
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("#pause"), _) => lines.push_back(ScriptLine::Pause),
+                (Some("#snapshot"), Some(name)) => lines.push_back(ScriptLine::Snapshot(name.to_owned())),
+                (Some("#snapshot"), None) => return Err(format!("line {}: expected `#snapshot NAME', got `{}'", n + 1, raw)),
+                _ => ()
+            }
+        }
+
+        return Ok(InputScript { lines });
+    }
+
+    /// How many lines are left, directives included
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+impl Iterator for InputScript {
+    type Item = ScriptLine;
+
+    fn next(&mut self) -> Option<ScriptLine> {
+        self.lines.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let script = InputScript::parse("# the start\ntake tablet\n\n  use tablet  \r\n  # a note\n#snapshot start.snap\n\t#pause\n#pause now\n").unwrap();
+        assert_eq!(script.len(), 5);
+        assert_eq!(script.collect::<Vec<_>>(), vec![
+            ScriptLine::Input(String::from("take tablet")),
+            ScriptLine::Input(String::from("use tablet")),
+            ScriptLine::Snapshot(String::from("start.snap")),
+            ScriptLine::Pause,
+            ScriptLine::Pause,
+        ]);
+    }
+
+    #[test]
+    fn parse_error() {
+        assert_eq!(InputScript::parse("north\n#snapshot\n"), Err(String::from("line 2: expected `#snapshot NAME', got `#snapshot'")));
+    }
+
+    #[test]
+    fn answers() {
+        let script = InputScript::load(concat!(env!("CARGO_MANIFEST_DIR"), "/data/answers")).unwrap();
+        assert_eq!(script.len(), 54);
+        assert!(script.clone().all(|l| match l { ScriptLine::Input(_) => true, _ => false }));
+    }
+}
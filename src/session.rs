@@ -0,0 +1,404 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+
+use address::Address;
+use bytes::{write_u16, write_u32, write_u64, read_u16, read_u32, read_u64};
+use instruction::Instruction;
+use observer::{VmObserver, Verdict};
+use vm::{VM, VMState, VMError};
+
+/// Every session file starts with these bytes
+const MAGIC : &[u8; 4] = b"SYNR";
+
+/// Bumped whenever the layout below changes
+pub const SESSION_VERSION : u16 = 1;
+
+/// Everything a program read and wrote, as recorded by a `Recorder`, so a `Replayer` can run it
+/// again and check it does the same. Steps are counted from where recording started.
+///
+/// On disk (all values little endian):
+///
+/// - `SYNR` magic, then a u16 version
+/// - u64 fingerprint of the state recording started from
+/// - u64 steps recorded
+/// - u32 input length, then each byte of input as a u64 step and the byte
+/// - u32 output length, then the output bytes
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Session {
+    /// see `Snapshot::fingerprint`
+    pub fingerprint: u64,
+    pub steps: u64,
+    /// every byte read by `IN`, with the step it was read at
+    pub input: Vec<(u64, u8)>,
+    pub output: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SessionError {
+    IOError(String),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::IOError(ref e) => write!(f, "I/O error: {}", e),
+            SessionError::BadMagic => write!(f, "not a session file"),
+            SessionError::UnsupportedVersion(v) => write!(f, "unsupported session version {} (expected {})", v, SESSION_VERSION),
+            SessionError::Truncated => write!(f, "session file is truncated"),
+        }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(e: io::Error) -> SessionError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SessionError::Truncated
+        } else {
+            SessionError::IOError(e.to_string())
+        }
+    }
+}
+
+impl Session {
+    /// An empty session starting from `vm` as it is now
+    pub fn new(vm: &VM) -> Session {
+        Session { fingerprint: vm.snapshot().fingerprint(), steps: 0, input: vec![], output: vec![] }
+    }
+
+    /// Whether `vm` is in the state this session was recorded from
+    pub fn starts_from(&self, vm: &VM) -> bool {
+        vm.snapshot().fingerprint() == self.fingerprint
+    }
+
+    /// Write the session to the file at `path`, replacing it if it exists.
+    pub fn save(&self, path: &str) -> Result<(), SessionError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read a session from the file at `path`
+    pub fn load(path: &str) -> Result<Session, SessionError> {
+        let mut r = BufReader::new(File::open(path)?);
+        Session::read_from(&mut r)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), SessionError> {
+        w.write_all(MAGIC)?;
+        write_u16(w, SESSION_VERSION)?;
+        write_u64(w, self.fingerprint)?;
+        write_u64(w, self.steps)?;
+
+        write_u32(w, self.input.len() as u32)?;
+        for &(step, byte) in &self.input {
+            write_u64(w, step)?;
+            w.write_all(&[byte])?;
+        }
+
+        write_u32(w, self.output.len() as u32)?;
+        w.write_all(&self.output)?;
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Session, SessionError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(SessionError::BadMagic); }
+
+        let version = read_u16(r)?;
+        if version != SESSION_VERSION { return Err(SessionError::UnsupportedVersion(version)); }
+
+        let fingerprint = read_u64(r)?;
+        let steps = read_u64(r)?;
+
+        let input_len = read_u32(r)?;
+        let mut input = vec![];
+        for _ in 0..input_len {
+            let step = read_u64(r)?;
+            let mut byte = [0u8; 1];
+            r.read_exact(&mut byte)?;
+            input.push((step, byte[0]));
+        }
+
+        let output_len = read_u32(r)?;
+        let mut output = vec![];
+        r.take(output_len as u64).read_to_end(&mut output)?;
+        if output.len() != output_len as usize { return Err(SessionError::Truncated); }
+
+        Ok(Session { fingerprint, steps, input, output })
+    }
+}
+
+/// Whether an instruction counts as a step, the same way the VM counts them
+fn completed(result: &Result<VMState, VMError>) -> bool {
+    match *result {
+        Ok(VMState::AwaitingInput) | Err(_) => false,
+        Ok(_) => true
+    }
+}
+
+/// Records a `Session` as a `VmObserver`. The session is shared, so it can be saved while the
+/// recorder belongs to the VM.
+pub struct Recorder {
+    session: Arc<Mutex<Session>>,
+}
+
+impl Recorder {
+    /// Start recording `vm` from where it is now
+    pub fn new(vm: &VM) -> Recorder {
+        Recorder { session: Arc::new(Mutex::new(Session::new(vm))) }
+    }
+
+    pub fn session(&self) -> Arc<Mutex<Session>> {
+        self.session.clone()
+    }
+}
+
+impl VmObserver for Recorder {
+    fn after_instruction(&mut self, _address: Address, _instruction: &Instruction, result: &Result<VMState, VMError>) {
+        if completed(result) { self.session.lock().unwrap().steps += 1; }
+    }
+
+    fn input(&mut self, byte: u8) {
+        let mut session = self.session.lock().unwrap();
+        let step = session.steps;
+        session.input.push((step, byte));
+    }
+
+    fn output(&mut self, byte: u8) -> Option<u8> {
+        self.session.lock().unwrap().output.push(byte);
+        Some(byte)
+    }
+}
+
+/// Where a replay stopped doing what the recording did
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Divergence {
+    /// The program read input at `step`, but the recording read its next byte at `expected`
+    Input { step: u64, expected: u64 },
+    /// Byte `position` of the output, written at `step`, was `actual` rather than what was
+    /// recorded, if anything was
+    Output { step: u64, position: usize, expected: Option<u8>, actual: u8 },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Divergence::Input { step, expected } =>
+                write!(f, "read input at step {}, but the recording read it at step {}", step, expected),
+            Divergence::Output { step, position, expected: Some(e), actual } =>
+                write!(f, "output byte {} at step {} was {:?}, but the recording has {:?}", position, step, actual as char, e as char),
+            Divergence::Output { step, position, expected: None, actual } =>
+                write!(f, "output byte {} at step {} was {:?}, past the end of the recording", position, step, actual as char),
+        }
+    }
+}
+
+/// How far a `Replayer` has got
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct ReplayStatus {
+    pub step: u64,
+    /// bytes of recorded input handed to the program
+    pub input_used: usize,
+    /// bytes of output which matched the recording
+    pub output_matched: usize,
+    pub divergence: Option<Divergence>,
+}
+
+impl ReplayStatus {
+    /// Whether the replay did everything in `session`, and nothing else
+    pub fn matches(&self, session: &Session) -> bool {
+        self.divergence.is_none()
+            && self.step == session.steps
+            && self.input_used == session.input.len()
+            && self.output_matched == session.output.len()
+    }
+}
+
+/// Feeds a program the input from a `Session` at the steps it was recorded, as a `VmObserver`,
+/// checking the output as it goes. The VM halts at the first divergence. Once the recorded input
+/// runs out, the program gets its input as usual.
+pub struct Replayer {
+    session: Session,
+    status: Arc<Mutex<ReplayStatus>>,
+}
+
+impl Replayer {
+    pub fn new(session: Session) -> Replayer {
+        let status = ReplayStatus { step: 0, input_used: 0, output_matched: 0, divergence: None };
+        Replayer { session, status: Arc::new(Mutex::new(status)) }
+    }
+
+    pub fn status(&self) -> Arc<Mutex<ReplayStatus>> {
+        self.status.clone()
+    }
+}
+
+impl VmObserver for Replayer {
+    fn before_instruction(&mut self, _address: Address, instruction: &Instruction) -> Verdict {
+        let mut status = self.status.lock().unwrap();
+        if status.divergence.is_some() { return Verdict::Halt; }
+
+        if let Instruction::IN(_) = *instruction {
+            if let Some(&(expected, _)) = self.session.input.get(status.input_used) {
+                if expected != status.step {
+                    status.divergence = Some(Divergence::Input { step: status.step, expected });
+                    return Verdict::Halt;
+                }
+            }
+        }
+        Verdict::Continue
+    }
+
+    fn after_instruction(&mut self, _address: Address, _instruction: &Instruction, result: &Result<VMState, VMError>) {
+        if completed(result) { self.status.lock().unwrap().step += 1; }
+    }
+
+    fn input_requested(&mut self) -> Option<u8> {
+        let mut status = self.status.lock().unwrap();
+        let byte = self.session.input.get(status.input_used).map(|&(_, b)| b);
+        if byte.is_some() { status.input_used += 1; }
+        byte
+    }
+
+    fn output(&mut self, byte: u8) -> Option<u8> {
+        let mut status = self.status.lock().unwrap();
+        if status.divergence.is_some() { return Some(byte); }
+
+        let expected = self.session.output.get(status.output_matched).cloned();
+        if expected == Some(byte) {
+            status.output_matched += 1;
+        } else {
+            status.divergence = Some(Divergence::Output { step: status.step, position: status.output_matched, expected, actual: byte });
+        }
+        Some(byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argument::Argument;
+    use device::{BufferInput, BufferOutput};
+    use constants::*;
+
+    // @0 IN R0
+    // @2 EQ R1 R0 10
+    // @6 JT R1 13
+    // @9 OUT R0
+    // @11 JMP 0
+    // @13 HALT
+    fn echo() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::IN(Argument::new(REGISTER_0)),
+            Instruction::EQ(::register::Register::R1, Argument::new(REGISTER_0), Argument::new(10)),
+            Instruction::JT(Argument::new(REGISTER_1), Argument::new(13)),
+            Instruction::OUT(Argument::new(REGISTER_0)),
+            Instruction::JMP(Argument::new(0)),
+            Instruction::HALT
+        ]).unwrap();
+        vm.start(Address::new(0));
+        return vm;
+    }
+
+    fn record(input: &str) -> Session {
+        let mut vm = echo();
+        let recorder = Recorder::new(&vm);
+        let session = recorder.session();
+        vm.add_observer(Box::new(recorder));
+        vm.push_input(input);
+        assert_eq!(vm.resume(), Ok(VMState::HALT));
+
+        let session = session.lock().unwrap().clone();
+        assert_eq!(session.steps, vm.step_count());
+        return session;
+    }
+
+    fn replay(vm: &mut VM, session: Session) -> ReplayStatus {
+        let replayer = Replayer::new(session);
+        let status = replayer.status();
+        vm.add_observer(Box::new(replayer));
+        let _ = vm.resume();
+        let status = *status.lock().unwrap();
+        return status;
+    }
+
+    #[test]
+    fn recording() {
+        let session = record("ab\n");
+        assert_eq!(session.input, vec![(0, 97), (5, 98), (10, 10)]);
+        assert_eq!(session.output, b"ab".to_vec());
+        assert_eq!(session.steps, 14);
+        assert!(session.starts_from(&echo()));
+    }
+
+    #[test]
+    fn round_trip() {
+        let session = record("ab\n");
+        let mut bytes = vec![];
+        session.write_to(&mut bytes).unwrap();
+        assert_eq!(Session::read_from(&mut &bytes[..]), Ok(session));
+
+        bytes.truncate(bytes.len() - 1);
+        assert_eq!(Session::read_from(&mut &bytes[..]), Err(SessionError::Truncated));
+        assert_eq!(Session::read_from(&mut &b"NOPE"[..]), Err(SessionError::BadMagic));
+    }
+
+    #[test]
+    fn faithful_replay() {
+        let session = record("ab\n");
+        let mut vm = echo();
+        let status = replay(&mut vm, session.clone());
+
+        assert!(status.matches(&session));
+        assert_eq!(vm.drain_output(), "ab");
+        assert_eq!(vm.state(), VMState::HALT);
+    }
+
+    #[test]
+    fn stopping_part_way() {
+        let session = record("ab\n");
+        let mut vm = echo();
+        let replayer = Replayer::new(session.clone());
+        let status = replayer.status();
+        vm.add_observer(Box::new(replayer));
+        vm.set_yield_on_input(true);
+
+        assert_eq!(vm.run_for(7), Ok(VMState::BudgetExhausted));
+        let status = *status.lock().unwrap();
+        assert_eq!((status.step, status.input_used, status.output_matched), (7, 2, 1));
+        assert!(!status.matches(&session));
+    }
+
+    #[test]
+    fn output_divergence() {
+        let mut session = record("ab\n");
+        session.output[1] = b'x';
+        let mut vm = echo();
+        let status = replay(&mut vm, session);
+
+        assert_eq!(status.divergence, Some(Divergence::Output { step: 8, position: 1, expected: Some(b'x'), actual: b'b' }));
+        assert_eq!(vm.state(), VMState::HALT);
+    }
+
+    #[test]
+    fn input_divergence() {
+        let mut session = record("ab\n");
+        session.input[1].0 = 6;
+        let mut vm = echo();
+        let status = replay(&mut vm, session);
+
+        assert_eq!(status.divergence, Some(Divergence::Input { step: 5, expected: 6 }));
+        assert_eq!(status.input_used, 1);
+    }
+}
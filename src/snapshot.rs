@@ -0,0 +1,261 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use address::Address;
+use bytes::{write_u16, write_u32, read_u16, read_u32};
+use vm::VMState;
+
+/// Every snapshot file starts with these bytes
+const MAGIC : &[u8; 4] = b"SYNS";
+
+/// Bumped whenever the layout below changes
+pub const SNAPSHOT_VERSION : u16 = 1;
+
+/// A complete copy of a VM's state, as produced by `VM::snapshot` and consumed by `VM::restore`.
+///
+/// On disk (all values little endian):
+///
+/// - `SYNS` magic, then a u16 version
+/// - u16 instruction pointer, u8 state
+/// - 8 x u16 registers
+/// - u32 stack length, then the stack from bottom to top
+/// - u16 memory length, then memory. Trailing zero words are dropped when written.
+/// - u32 pending input length, then the pending input bytes
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    pub instruction_pointer: Address,
+    pub state: VMState,
+    pub registers: [u16; 8],
+    pub stack: Vec<u16>,
+    pub memory: Vec<u16>,
+    pub pending_input: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SnapshotError {
+    IOError(String),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    BadState(u8),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::IOError(ref e) => write!(f, "I/O error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {} (expected {})", v, SNAPSHOT_VERSION),
+            SnapshotError::Truncated => write!(f, "snapshot file is truncated"),
+            SnapshotError::BadState(s) => write!(f, "unknown VM state {} in snapshot", s),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::IOError(e.to_string())
+        }
+    }
+}
+
+impl Snapshot {
+    /// A hash of everything in the snapshot, to tell whether two VMs start from the same state.
+    /// Trailing zeros in memory don't count, so it survives a save and load.
+    pub fn fingerprint(&self) -> u64 {
+        let used = self.memory.iter().rposition(|&v| v != 0).map_or(0, |i| i + 1);
+        let words = Some(self.instruction_pointer.value()).into_iter()
+            .chain(Some(state_to_u8(self.state) as u16))
+            .chain(self.registers.iter().cloned())
+            .chain(Some(self.stack.len() as u16))
+            .chain(self.stack.iter().cloned())
+            .chain(self.memory[..used].iter().cloned())
+            .chain(self.pending_input.iter().map(|&b| b as u16));
+
+        // FNV-1a, a word at a time
+        let mut hash : u64 = 0xcbf29ce484222325;
+        for w in words {
+            hash ^= w as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    /// Write the snapshot to the file at `path`, replacing it if it exists.
+    pub fn save(&self, path: &str) -> Result<(), SnapshotError> {
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Read a snapshot from the file at `path`
+    pub fn load(path: &str) -> Result<Snapshot, SnapshotError> {
+        let mut r = BufReader::new(File::open(path)?);
+        Snapshot::read_from(&mut r)
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> Result<(), SnapshotError> {
+        w.write_all(MAGIC)?;
+        write_u16(w, SNAPSHOT_VERSION)?;
+
+        write_u16(w, self.instruction_pointer.value())?;
+        w.write_all(&[state_to_u8(self.state)])?;
+
+        for r in self.registers.iter() { write_u16(w, *r)?; }
+
+        write_u32(w, self.stack.len() as u32)?;
+        for v in &self.stack { write_u16(w, *v)?; }
+
+        let used = self.memory.iter().rposition(|&v| v != 0).map_or(0, |i| i + 1);
+        write_u16(w, used as u16)?;
+        for v in &self.memory[..used] { write_u16(w, *v)?; }
+
+        write_u32(w, self.pending_input.len() as u32)?;
+        w.write_all(&self.pending_input)?;
+
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Snapshot, SnapshotError> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC { return Err(SnapshotError::BadMagic); }
+
+        let version = read_u16(r)?;
+        if version != SNAPSHOT_VERSION { return Err(SnapshotError::UnsupportedVersion(version)); }
+
+        let ip = read_u16(r)?;
+        let mut state = [0u8; 1];
+        r.read_exact(&mut state)?;
+        let state = state_from_u8(state[0])?;
+
+        let mut registers = [0u16; 8];
+        for reg in registers.iter_mut() { *reg = read_u16(r)?; }
+
+        let stack_len = read_u32(r)?;
+        let mut stack = vec![];
+        for _ in 0..stack_len { stack.push(read_u16(r)?); }
+
+        let mem_len = read_u16(r)?;
+        let mut memory = vec![];
+        for _ in 0..mem_len { memory.push(read_u16(r)?); }
+
+        let input_len = read_u32(r)?;
+        let mut pending_input = vec![];
+        r.take(input_len as u64).read_to_end(&mut pending_input)?;
+        if pending_input.len() != input_len as usize { return Err(SnapshotError::Truncated); }
+
+        Ok(Snapshot {
+            instruction_pointer: Address::new(ip),
+            state,
+            registers,
+            stack,
+            memory,
+            pending_input,
+        })
+    }
+}
+
+fn state_to_u8(s: VMState) -> u8 {
+    match s {
+        VMState::RUN => 0,
+        VMState::HALT => 1,
+        VMState::AwaitingInput => 2,
+        VMState::WatchpointHit => 3,
+        VMState::Paused => 4,
+        VMState::BudgetExhausted => 5,
+    }
+}
+
+fn state_from_u8(b: u8) -> Result<VMState, SnapshotError> {
+    match b {
+        0 => Ok(VMState::RUN),
+        1 => Ok(VMState::HALT),
+        2 => Ok(VMState::AwaitingInput),
+        3 => Ok(VMState::WatchpointHit),
+        4 => Ok(VMState::Paused),
+        5 => Ok(VMState::BudgetExhausted),
+        _ => Err(SnapshotError::BadState(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Snapshot {
+        Snapshot {
+            instruction_pointer: Address::new(1234),
+            state: VMState::AwaitingInput,
+            registers: [1, 2, 3, 4, 5, 6, 7, 25734],
+            stack: vec![6080, 16, 32767],
+            memory: vec![21, 19, 65, 0, 0, 0],
+            pending_input: b"north\n".to_vec(),
+        }
+    }
+
+    fn round_trip(s: &Snapshot) -> Result<Snapshot, SnapshotError> {
+        let mut bytes = vec![];
+        s.write_to(&mut bytes).unwrap();
+        Snapshot::read_from(&mut &bytes[..])
+    }
+
+    #[test]
+    fn round_trip_drops_trailing_zeros() {
+        let s = example();
+        let loaded = round_trip(&s).unwrap();
+
+        assert_eq!(loaded.memory, vec![21, 19, 65]);
+        assert_eq!(loaded.registers, s.registers);
+        assert_eq!(loaded.stack, s.stack);
+        assert_eq!(loaded.instruction_pointer, s.instruction_pointer);
+        assert_eq!(loaded.state, s.state);
+        assert_eq!(loaded.pending_input, s.pending_input);
+    }
+
+    #[test]
+    fn every_state() {
+        for state in &[VMState::RUN, VMState::HALT, VMState::AwaitingInput, VMState::WatchpointHit, VMState::Paused, VMState::BudgetExhausted] {
+            let s = Snapshot { state: *state, ..example() };
+            assert_eq!(round_trip(&s).unwrap().state, *state);
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        let s = example();
+        assert_eq!(round_trip(&s).unwrap().fingerprint(), s.fingerprint());
+        assert!(Snapshot { registers: [1, 2, 3, 4, 5, 6, 7, 1], ..example() }.fingerprint() != s.fingerprint());
+        assert!(Snapshot { memory: vec![21, 19], ..example() }.fingerprint() != s.fingerprint());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let bytes = b"NOPE\x01\x00".to_vec();
+        assert_eq!(Snapshot::read_from(&mut &bytes[..]), Err(SnapshotError::BadMagic));
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut bytes = vec![];
+        example().write_to(&mut bytes).unwrap();
+        bytes[4] = 99;
+        assert_eq!(Snapshot::read_from(&mut &bytes[..]), Err(SnapshotError::UnsupportedVersion(99)));
+    }
+
+    #[test]
+    fn truncated() {
+        let mut bytes = vec![];
+        example().write_to(&mut bytes).unwrap();
+        let len = bytes.len();
+        bytes.truncate(len - 3);
+        assert_eq!(Snapshot::read_from(&mut &bytes[..]), Err(SnapshotError::Truncated));
+    }
+}
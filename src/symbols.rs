@@ -0,0 +1,112 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+
+use address::Address;
+
+/// Names for addresses in a program, loaded from a file of `ADDRESS NAME` lines. Blank lines and
+/// anything after a `#` are ignored, eg:
+///
+/// ```text
+/// # teleporter stuff
+/// 6027 confirm
+/// 5451 teleport
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct SymbolTable {
+    by_name: HashMap<String, Address>,
+    by_address: BTreeMap<Address, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> SymbolTable {
+        SymbolTable { by_name: HashMap::new(), by_address: BTreeMap::new() }
+    }
+
+    pub fn load(path: &str) -> Result<SymbolTable, String> {
+        let mut contents = String::new();
+        match File::open(path).and_then(|mut f| f.read_to_string(&mut contents)) {
+            Ok(_) => SymbolTable::parse(&contents),
+            Err(e) => Err(format!("Could not read `{}': {}", path, e))
+        }
+    }
+
+    pub fn parse(contents: &str) -> Result<SymbolTable, String> {
+        let mut table = SymbolTable::new();
+
+        for (n, raw) in contents.lines().enumerate() {
+            let line = raw.split('#').next().unwrap_or("").trim();
+            if line.is_empty() { continue; }
+
+            let mut words = line.split_whitespace();
+            let addr = words.next().and_then(|a| a.trim_start_matches('@').parse::<u16>().ok());
+            let name = words.next();
+
+            match (addr, name) {
+                (Some(a), Some(name)) => table.insert(name, Address::new(a)),
+                _ => return Err(format!("line {}: expected `ADDRESS NAME', got `{}'", n + 1, raw))
+            }
+        }
+
+        return Ok(table);
+    }
+
+    pub fn insert(&mut self, name: &str, address: Address) {
+        self.by_name.insert(name.to_owned(), address);
+        self.by_address.insert(address, name.to_owned());
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<Address> {
+        self.by_name.get(name).cloned()
+    }
+
+    /// The name given exactly to this address, if any
+    pub fn name_of(&self, address: Address) -> Option<&str> {
+        self.by_address.get(&address).map(|s| s.as_str())
+    }
+
+    /// Describe an address relative to the closest symbol at or before it, eg `confirm+12`.
+    pub fn describe(&self, address: Address) -> Option<String> {
+        self.by_address.range(..=address).next_back().map(|(a, name)| {
+            let offset = address.value() - a.value();
+            if offset == 0 { name.clone() } else { format!("{}+{}", name, offset) }
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table() -> SymbolTable {
+        SymbolTable::parse("# comment\n\n6027 confirm\n@5451 teleport # trailing\n").unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let t = table();
+        assert_eq!(t.lookup("confirm"), Some(Address::new(6027)));
+        assert_eq!(t.lookup("teleport"), Some(Address::new(5451)));
+        assert_eq!(t.lookup("nope"), None);
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!(SymbolTable::parse("6027\n").is_err());
+        assert!(SymbolTable::parse("confirm 6027\n").is_err());
+    }
+
+    #[test]
+    fn describe() {
+        let t = table();
+        assert_eq!(t.name_of(Address::new(6027)), Some("confirm"));
+        assert_eq!(t.describe(Address::new(6027)), Some(String::from("confirm")));
+        assert_eq!(t.describe(Address::new(6030)), Some(String::from("confirm+3")));
+        assert_eq!(t.describe(Address::new(10)), None);
+    }
+}
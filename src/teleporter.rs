@@ -0,0 +1,262 @@
+use std::sync::Arc;
+use std::thread;
+
+use u15::u15;
+use address::Address;
+use argument::Argument;
+use register::Register;
+use instruction::Instruction;
+use device::{BufferInput, BufferOutput};
+use vm::{VM, VMState};
+use constants::*;
+
+/// How far past the start of a candidate routine we look for the recursive call
+const ROUTINE_SCAN : usize = 64;
+
+/// How long we let the guest routine run for each check in `verify`, in case it's not what we
+/// think it is and never returns
+const VERIFY_STEPS : u64 = 1_000_000;
+
+/// The teleporter's confirmation check: a call to a recursive routine which uses R7, with the
+/// result compared against an expected value. In the challenge this is `CALL 6027` at @5489,
+/// with R0 = 4, R1 = 1, expecting 6.
+///
+/// The routine computes, with all arithmetic mod 32768 and R7 as `k`:
+///
+/// - f(0, b) = b + 1
+/// - f(a, 0) = f(a - 1, k)
+/// - f(a, b) = f(a - 1, f(a, b - 1))
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Confirmation {
+    pub routine: Address,
+    pub call_site: Address,
+    pub a: u16,
+    pub b: u16,
+    pub expected: u16,
+}
+
+fn literal(arg: Argument) -> Option<u16> {
+    match arg {
+        Argument::Literal(v) => Some(v.0),
+        Argument::Register(_) => None
+    }
+}
+
+/// Whether the code at `start` calls itself and uses R7 before running off into something that
+/// doesn't decode.
+fn is_recursive_on_r7(vm: &VM, start: Address) -> bool {
+    let mut addr = start;
+    let mut recursive = false;
+    let mut uses_r7 = false;
+
+    for _ in 0..ROUTINE_SCAN {
+        let instruction = match vm.decode_at(addr) {
+            Ok(i) => i,
+            Err(_) => break
+        };
+
+        if instruction == Instruction::CALL(Argument::Literal(u15(start.value()))) { recursive = true; }
+        if instruction.arguments().contains(&Argument::Register(Register::R7)) { uses_r7 = true; }
+        if recursive && uses_r7 { return true; }
+
+        addr = Address::new(addr.value() + instruction.size());
+        if !addr.is_memory() { break; }
+    }
+
+    return false;
+}
+
+/// Look for the confirmation check in the program loaded in `vm`: a `CALL` to a routine which is
+/// recursive on R7, set up by `SET R0 a` and `SET R1 b` and followed by comparing R0 against a
+/// literal.
+pub fn locate(vm: &VM) -> Option<Confirmation> {
+    for site in 6..U15_MAX {
+        let call_site = Address::new(site);
+        let routine = match vm.decode_at(call_site) {
+            Ok(Instruction::CALL(Argument::Literal(t))) => Address::new(t.0),
+            _ => continue
+        };
+
+        let expected = match vm.decode_at(Address::new(site + 2)) {
+            Ok(Instruction::EQ(_, Argument::Register(Register::R0), v)) => literal(v),
+            Ok(Instruction::EQ(_, v, Argument::Register(Register::R0))) => literal(v),
+            _ => None
+        };
+        let expected = match expected {
+            Some(e) => e,
+            None => continue
+        };
+
+        let mut a = None;
+        let mut b = None;
+        for back in &[6, 3] {
+            match vm.decode_at(Address::new(site - back)) {
+                Ok(Instruction::SET(Register::R0, v)) => a = literal(v),
+                Ok(Instruction::SET(Register::R1, v)) => b = literal(v),
+                _ => ()
+            }
+        }
+
+        if let (Some(a), Some(b)) = (a, b) {
+            if is_recursive_on_r7(vm, routine) {
+                return Some(Confirmation { routine, call_site, a, b, expected });
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Evaluate the confirmation function natively. Each level of the recursion only depends on the
+/// one below, so we fill in a whole row of f(a, _) at a time rather than recursing. The last row
+/// is only needed as far as `b`.
+pub fn confirm(a: u16, b: u16, r7: u16) -> u16 {
+    let mut row : Vec<u16> = (0..MODULUS).map(|b| (b + 1) % MODULUS).collect();
+    let mut next = vec![0u16; MODULUS as usize];
+
+    for level in 0..a {
+        let len = if level + 1 == a { b as usize + 1 } else { MODULUS as usize };
+        next[0] = row[r7 as usize];
+        for i in 1..len {
+            next[i] = row[next[i - 1] as usize];
+        }
+        ::std::mem::swap(&mut row, &mut next);
+    }
+
+    return row[b as usize];
+}
+
+impl Confirmation {
+    /// Try every value of R7, spread across `threads` threads, returning all the ones the check
+    /// accepts in ascending order.
+    pub fn search(&self, threads: usize) -> Vec<u16> {
+        let threads = threads.max(1) as u32;
+        let this = Arc::new(self.clone());
+
+        let handles : Vec<_> = (0..threads).map(|t| {
+            let this = this.clone();
+            thread::spawn(move || {
+                let mut found = vec![];
+                let mut r7 = t;
+                while r7 < MODULUS as u32 {
+                    if confirm(this.a, this.b, r7 as u16) == this.expected { found.push(r7 as u16); }
+                    r7 += threads;
+                }
+                found
+            })
+        }).collect();
+
+        let mut found = vec![];
+        for h in handles {
+            found.extend(h.join().expect("search thread panicked"));
+        }
+        found.sort();
+        return found;
+    }
+
+    /// Words to write over the start of the routine so it returns the expected value straight
+    /// away: `SET R0 expected; RET`.
+    pub fn patch(&self) -> Vec<u16> {
+        let mut words = Instruction::SET(Register::R0, Argument::new(self.expected)).to_u16_sequence();
+        words.extend(Instruction::RET.to_u16_sequence());
+        return words;
+    }
+
+    /// Check the native version agrees with the routine in `vm` for inputs small enough to run
+    /// the real thing.
+    pub fn verify(&self, vm: &VM) -> bool {
+        let mut snapshot = vm.snapshot();
+        snapshot.stack.clear();
+        let mut guest = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+
+        for a in 0..3 {
+            for b in 0..3 {
+                for r7 in 1..4 {
+                    snapshot.registers[0] = a;
+                    snapshot.registers[1] = b;
+                    snapshot.registers[7] = r7;
+                    if guest.restore(&snapshot).is_err() { return false; }
+
+                    // with an empty stack, the routine's final RET halts the VM
+                    guest.start(self.routine);
+                    if guest.run_for(VERIFY_STEPS) != Ok(VMState::HALT) { return false; }
+                    if guest.register(Register::R0) != confirm(a, b, r7) { return false; }
+                }
+            }
+        }
+
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(confirm(0, 5, 1), 6);
+        assert_eq!(confirm(1, 0, 1), 2);
+        // with k = 1 this is the Ackermann function
+        assert_eq!(confirm(2, 3, 1), 9);
+        assert_eq!(confirm(3, 3, 1), 61);
+        assert_eq!(confirm(4, 1, 25734), 6);
+    }
+
+    // @0  SET R0 4
+    // @3  SET R1 1
+    // @6  CALL 20
+    // @8  EQ R1 R0 6
+    // @12 HALT
+    // @20 the confirmation routine, as in the challenge
+    fn challenge_like() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(4)),
+            Instruction::SET(Register::R1, Argument::new(1)),
+            Instruction::CALL(Argument::new(20)),
+            Instruction::EQ(Register::R1, Argument::new(REGISTER_0), Argument::new(6)),
+            Instruction::HALT
+        ]).unwrap();
+        vm.load_instructions(Address::new(20), &vec![
+            Instruction::JT(Argument::new(REGISTER_0), Argument::new(28)),                  // @20
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_1), Argument::new(1)),    // @23
+            Instruction::RET,                                                               // @27
+            Instruction::JT(Argument::new(REGISTER_1), Argument::new(41)),                  // @28
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(32767)),// @31
+            Instruction::SET(Register::R1, Argument::new(REGISTER_7)),                      // @35
+            Instruction::CALL(Argument::new(20)),                                           // @38
+            Instruction::RET,                                                               // @40
+            Instruction::PUSH(Argument::new(REGISTER_0)),                                   // @41
+            Instruction::ADD(Register::R1, Argument::new(REGISTER_1), Argument::new(32767)),// @43
+            Instruction::CALL(Argument::new(20)),                                           // @47
+            Instruction::SET(Register::R1, Argument::new(REGISTER_0)),                      // @49
+            Instruction::POP(Register::R0),                                                 // @52
+            Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(32767)),// @54
+            Instruction::CALL(Argument::new(20)),                                           // @58
+            Instruction::RET                                                                // @60
+        ]).unwrap();
+        return vm;
+    }
+
+    #[test]
+    fn locates_the_check() {
+        let vm = challenge_like();
+        let c = locate(&vm).unwrap();
+        assert_eq!(c, Confirmation { routine: Address::new(20), call_site: Address::new(6), a: 4, b: 1, expected: 6 });
+        assert!(c.verify(&vm));
+    }
+
+    #[test]
+    fn nothing_to_find() {
+        let mut vm = challenge_like();
+        vm.poke(Address::new(37), REGISTER_6).unwrap();
+        assert_eq!(locate(&vm), None);
+    }
+
+    #[test]
+    fn patch_returns_expected() {
+        let c = locate(&challenge_like()).unwrap();
+        assert_eq!(c.patch(), vec![1, REGISTER_0, 6, 18]);
+    }
+}
@@ -0,0 +1,433 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::str::FromStr;
+
+use address::Address;
+use bytes::{write_u16, write_u64, read_u16, read_u64_or_eof};
+use argument::Argument;
+use instruction::Instruction;
+use register::Register;
+use vm::{VM, VMState, VMError};
+use fault::VMFault;
+use constants::*;
+
+/// Every binary trace starts with these bytes, followed by a u16 version
+const MAGIC : &[u8; 4] = b"SYNT";
+pub const TRACE_VERSION : u16 = 1;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TraceFormat {
+    /// One human readable line per instruction
+    Text,
+    /// One JSON object per line
+    Jsonl,
+    /// Packed little endian records, see `TraceRecord::write_binary`
+    Binary
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TraceFormat, String> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "jsonl" | "json" => Ok(TraceFormat::Jsonl),
+            "binary" | "bin" => Ok(TraceFormat::Binary),
+            _ => Err(format!("Unknown trace format `{}', expected text, jsonl or binary", s))
+        }
+    }
+}
+
+/// Limits on what gets recorded, so tracing a long run doesn't fill the disk
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TraceFilter {
+    /// only record instructions within this (inclusive) address range
+    pub range: Option<(Address, Address)>,
+    /// only record instructions at most this many calls deep, relative to where tracing started
+    pub max_depth: Option<usize>,
+}
+
+impl TraceFilter {
+    pub fn everything() -> TraceFilter {
+        TraceFilter { range: None, max_depth: None }
+    }
+
+    pub fn accepts(&self, address: Address, depth: usize) -> bool {
+        let in_range = self.range.map_or(true, |(lo, hi)| lo <= address && address <= hi);
+        let shallow = self.max_depth.map_or(true, |max| depth <= max);
+        in_range && shallow
+    }
+}
+
+/// Everything we know about one executed instruction
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TraceRecord {
+    /// the VM's step count once the instruction finished
+    pub step: u64,
+    pub address: Address,
+    pub depth: usize,
+    pub instruction: Instruction,
+    /// the value of each argument when the instruction started, registers are read
+    pub operands: Vec<u16>,
+    /// registers which changed, as (register, old, new)
+    pub registers: Vec<(Register, u16, u16)>,
+    pub pushed: Vec<u16>,
+    pub popped: Vec<u16>,
+}
+
+impl TraceRecord {
+    pub fn write_text<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{:>10} {:>6} {:indent$}{:<20}", self.step, format!("{}", self.address), "", format!("{}", self.instruction), indent = self.depth)?;
+        let ops : Vec<String> = self.operands.iter().map(|v| v.to_string()).collect();
+        write!(w, " [{}]", ops.join(", "))?;
+        for &(r, old, new) in &self.registers { write!(w, " {}: {} -> {}", r, old, new)?; }
+        for v in &self.pushed { write!(w, " push {}", v)?; }
+        for v in &self.popped { write!(w, " pop {}", v)?; }
+        writeln!(w)
+    }
+
+    pub fn write_json<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let ops : Vec<String> = self.operands.iter().map(|v| v.to_string()).collect();
+        let regs : Vec<String> = self.registers.iter().map(|&(r, old, new)| format!("\"{}\":[{},{}]", r, old, new)).collect();
+        let pushed : Vec<String> = self.pushed.iter().map(|v| v.to_string()).collect();
+        let popped : Vec<String> = self.popped.iter().map(|v| v.to_string()).collect();
+
+        writeln!(w, "{{\"step\":{},\"addr\":{},\"depth\":{},\"op\":\"{}\",\"text\":\"{}\",\"operands\":[{}],\"regs\":{{{}}},\"push\":[{}],\"pop\":[{}]}}",
+                 self.step, self.address.value(), self.depth, self.instruction.clone().name(), self.instruction,
+                 ops.join(","), regs.join(","), pushed.join(","), popped.join(","))
+    }
+
+    /// Layout, all little endian:
+    ///
+    /// - u64 step, u16 address, u16 depth
+    /// - u8 word count, then the instruction as it was encoded in memory
+    /// - u8 operand count, then u16 operands
+    /// - u8 changed register count, then (u8 register index, u16 old, u16 new)
+    /// - u8 pushed count, then u16 values; u8 popped count, then u16 values
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write_u64(w, self.step)?;
+        write_u16(w, self.address.value())?;
+        write_u16(w, self.depth as u16)?;
+
+        let words = self.instruction.clone().to_u16_sequence();
+        write_u16s(w, &words)?;
+        write_u16s(w, &self.operands)?;
+
+        w.write_all(&[self.registers.len() as u8])?;
+        for &(r, old, new) in &self.registers {
+            w.write_all(&[r.as_index() as u8])?;
+            write_u16(w, old)?;
+            write_u16(w, new)?;
+        }
+
+        write_u16s(w, &self.pushed)?;
+        write_u16s(w, &self.popped)
+    }
+
+    /// Read back a record written by `write_binary`, `None` at a clean end of input.
+    pub fn read_binary<R: Read>(r: &mut R) -> io::Result<Option<TraceRecord>> {
+        let step = match read_u64_or_eof(r)? {
+            Some(step) => step,
+            None => return Ok(None)
+        };
+
+        let address = Address::new(read_u16(r)?);
+        let depth = read_u16(r)? as usize;
+
+        let words = read_u16s(r)?;
+        let instruction = match Instruction::is_well_formed(&words) {
+            true => Instruction::from_u16_sequence(&words).unwrap(),
+            false => return Err(io::Error::new(io::ErrorKind::InvalidData, "bad instruction in trace"))
+        };
+        let operands = read_u16s(r)?;
+
+        let mut count = [0u8; 1];
+        r.read_exact(&mut count)?;
+        let mut registers = vec![];
+        for _ in 0..count[0] {
+            let mut idx = [0u8; 1];
+            r.read_exact(&mut idx)?;
+            let reg = Register::new(REGISTER_0 + (idx[0] as u16 & 7));
+            let old = read_u16(r)?;
+            let new = read_u16(r)?;
+            registers.push((reg, old, new));
+        }
+
+        let pushed = read_u16s(r)?;
+        let popped = read_u16s(r)?;
+
+        Ok(Some(TraceRecord { step, address, depth, instruction, operands, registers, pushed, popped }))
+    }
+}
+
+/// Records every instruction a VM executes, in the given format, to some output.
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    format: TraceFormat,
+    filter: TraceFilter,
+    depth: usize,
+    started: bool,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat, filter: TraceFilter) -> Tracer {
+        Tracer { out, format, filter, depth: 0, started: false }
+    }
+
+    /// Trace to a file, replacing it if it exists
+    pub fn create(path: &str, format: TraceFormat, filter: TraceFilter) -> io::Result<Tracer> {
+        let f = File::create(path)?;
+        Ok(Tracer::new(Box::new(BufWriter::new(f)), format, filter))
+    }
+
+    /// Step the VM once, recording what happened
+    pub fn step(&mut self, vm: &mut VM) -> Result<VMState, VMFault> {
+        self.step_with(vm, VM::step)
+    }
+
+    /// Like `step`, but with `step` doing the stepping, eg `Debugger::record_step`
+    pub fn step_with<F>(&mut self, vm: &mut VM, step: F) -> Result<VMState, VMFault>
+        where F: FnOnce(&mut VM) -> Result<VMState, VMFault> {
+        let address = vm.instruction_pointer();
+        let instruction = vm.decode_at(address);
+        let registers = vm.registers();
+        let stack_len = vm.stack().len();
+        let stack_top = vm.stack().last().cloned();
+
+        let result = step(vm);
+
+        // nothing happened if we failed, or are waiting to retry an IN
+        let instruction = match (instruction, &result) {
+            (Ok(i), &Ok(state)) if state != VMState::AwaitingInput => i,
+            _ => return result
+        };
+
+        let depth = self.depth;
+        match instruction {
+            // a CALL to a native routine returns straight away
+            Instruction::CALL(_) if vm.stack().len() > stack_len => self.depth += 1,
+            Instruction::RET => self.depth = self.depth.saturating_sub(1),
+            _ => ()
+        }
+
+        if !self.filter.accepts(address, depth) { return result; }
+
+        let operands = instruction.arguments().iter().map(|a| match *a {
+            Argument::Literal(v) => v.0,
+            Argument::Register(r) => registers[r.as_index()]
+        }).collect();
+
+        let after = vm.registers();
+        let changed = (0..8).filter(|&i| registers[i] != after[i])
+            .map(|i| (Register::new(REGISTER_0 + i as u16), registers[i], after[i]))
+            .collect();
+
+        let stack = vm.stack();
+        let (pushed, popped) = if stack.len() > stack_len {
+            (stack[stack_len..].to_vec(), vec![])
+        } else if stack.len() < stack_len {
+            (vec![], stack_top.into_iter().collect())
+        } else {
+            (vec![], vec![])
+        };
+
+        let record = TraceRecord {
+            step: vm.step_count(),
+            address,
+            depth,
+            instruction,
+            operands,
+            registers: changed,
+            pushed,
+            popped,
+        };
+
+        match self.write(&record) {
+            Ok(()) => result,
+            Err(e) => Err(vm.fault(VMError::DeviceError(e.to_string()), address))
+        }
+    }
+
+    /// Like `VM::resume`, but tracing each instruction
+    pub fn resume(&mut self, vm: &mut VM) -> Result<VMState, VMFault> {
+        vm.set_yield_on_input(true);
+        loop {
+            match self.step(vm) {
+                Ok(VMState::RUN) => continue,
+                other => {
+                    let _ = self.flush();
+                    return other;
+                }
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.started && self.format == TraceFormat::Binary {
+            self.out.write_all(MAGIC)?;
+            write_u16(&mut self.out, TRACE_VERSION)?;
+        }
+        self.started = true;
+
+        match self.format {
+            TraceFormat::Text => record.write_text(&mut self.out),
+            TraceFormat::Jsonl => record.write_json(&mut self.out),
+            TraceFormat::Binary => record.write_binary(&mut self.out),
+        }
+    }
+}
+
+/// Check the header of a binary trace, leaving the reader at the first record
+pub fn read_binary_header<R: Read>(r: &mut R) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC { return Err(io::Error::new(io::ErrorKind::InvalidData, "not a binary trace")); }
+    match read_u16(r)? {
+        TRACE_VERSION => Ok(()),
+        v => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported trace version {}", v)))
+    }
+}
+
+fn write_u16s<W: Write>(w: &mut W, vs: &[u16]) -> io::Result<()> {
+    w.write_all(&[vs.len() as u8])?;
+    for v in vs { write_u16(w, *v)?; }
+    Ok(())
+}
+
+fn read_u16s<R: Read>(r: &mut R) -> io::Result<Vec<u16>> {
+    let mut count = [0u8; 1];
+    r.read_exact(&mut count)?;
+    let mut vs = vec![];
+    for _ in 0..count[0] { vs.push(read_u16(r)?); }
+    Ok(vs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use device::{BufferInput, BufferOutput};
+
+    /// A `Write` we can look at after handing it to a tracer
+    #[derive(Clone)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.lock().unwrap().extend_from_slice(buf); Ok(buf.len()) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    // @0 SET R0 5
+    // @3 CALL 7
+    // @5 HALT
+    // @6 NOOP
+    // @7 PUSH R0
+    // @9 POP R1
+    // @11 RET
+    fn vm() -> VM {
+        let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+        vm.load_instructions(Address::new(0), &vec![
+            Instruction::SET(Register::R0, Argument::new(5)),
+            Instruction::CALL(Argument::new(7)),
+            Instruction::HALT,
+            Instruction::NOOP,
+            Instruction::PUSH(Argument::new(REGISTER_0)),
+            Instruction::POP(Register::R1),
+            Instruction::RET
+        ]).unwrap();
+        vm.start(Address::new(0));
+        return vm;
+    }
+
+    fn trace(format: TraceFormat, filter: TraceFilter) -> Vec<u8> {
+        let out = Shared(Arc::new(Mutex::new(vec![])));
+        let mut tracer = Tracer::new(Box::new(out.clone()), format, filter);
+        let mut vm = vm();
+        assert_eq!(tracer.resume(&mut vm), Ok(VMState::HALT));
+        let bytes = out.0.lock().unwrap().clone();
+        bytes
+    }
+
+    fn records(filter: TraceFilter) -> Vec<TraceRecord> {
+        let bytes = trace(TraceFormat::Binary, filter);
+        let mut r = &bytes[..];
+        read_binary_header(&mut r).unwrap();
+        let mut records = vec![];
+        while let Some(rec) = TraceRecord::read_binary(&mut r).unwrap() { records.push(rec); }
+        records
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let records = records(TraceFilter::everything());
+        assert_eq!(records.len(), 6);
+
+        assert_eq!(records[0].instruction, Instruction::SET(Register::R0, Argument::new(5)));
+        assert_eq!(records[0].registers, vec![(Register::R0, 0, 5)]);
+
+        assert_eq!(records[1].pushed, vec![5]);
+        assert_eq!(records[2].address, Address::new(7));
+        assert_eq!(records[2].depth, 1);
+        assert_eq!(records[2].operands, vec![5]);
+        assert_eq!(records[3].popped, vec![5]);
+        assert_eq!(records[3].registers, vec![(Register::R1, 0, 5)]);
+        assert_eq!(records[4].popped, vec![5]);
+        assert_eq!(records[5].step, 6);
+        assert_eq!(records[5].depth, 0);
+    }
+
+    #[test]
+    fn filters() {
+        let shallow = records(TraceFilter { range: None, max_depth: Some(0) });
+        assert_eq!(shallow.iter().map(|r| r.address.value()).collect::<Vec<u16>>(), vec![0, 3, 5]);
+
+        let ranged = records(TraceFilter { range: Some((Address::new(7), Address::new(9))), max_depth: None });
+        assert_eq!(ranged.iter().map(|r| r.address.value()).collect::<Vec<u16>>(), vec![7, 9]);
+    }
+
+    #[test]
+    fn native_calls_stay_at_the_same_depth() {
+        let out = Shared(Arc::new(Mutex::new(vec![])));
+        let mut tracer = Tracer::new(Box::new(out.clone()), TraceFormat::Binary, TraceFilter { range: None, max_depth: Some(0) });
+        let mut vm = vm();
+        vm.set_native(Address::new(7), |_| Ok(()));
+        assert_eq!(tracer.resume(&mut vm), Ok(VMState::HALT));
+
+        let bytes = out.0.lock().unwrap().clone();
+        let mut r = &bytes[..];
+        read_binary_header(&mut r).unwrap();
+        let mut records = vec![];
+        while let Some(rec) = TraceRecord::read_binary(&mut r).unwrap() { records.push(rec); }
+        assert_eq!(records.iter().map(|r| (r.address.value(), r.depth)).collect::<Vec<_>>(), vec![(0, 0), (3, 0), (5, 0)]);
+    }
+
+    #[test]
+    fn jsonl() {
+        let out = String::from_utf8(trace(TraceFormat::Jsonl, TraceFilter::everything())).unwrap();
+        let first = out.lines().next().unwrap();
+        assert_eq!(first, "{\"step\":1,\"addr\":0,\"depth\":0,\"op\":\"SET\",\"text\":\"SET R0 5\",\"operands\":[0,5],\"regs\":{\"R0\":[0,5]},\"push\":[],\"pop\":[]}");
+        assert_eq!(out.lines().count(), 6);
+    }
+
+    #[test]
+    fn text() {
+        let out = String::from_utf8(trace(TraceFormat::Text, TraceFilter::everything())).unwrap();
+        let lines : Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[0].contains("SET R0 5"));
+        assert!(lines[0].ends_with("R0: 0 -> 5"));
+        assert!(lines[1].ends_with("push 5"));
+    }
+
+    #[test]
+    fn format_from_str() {
+        assert_eq!("jsonl".parse::<TraceFormat>(), Ok(TraceFormat::Jsonl));
+        assert!("xml".parse::<TraceFormat>().is_err());
+    }
+}
@@ -1,26 +1,91 @@
-use std::convert::From;
-use std::io;
-use std::io::Read;
+use std::error::Error;
+use std::cmp;
+use std::fmt;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use u15::u15;
 use address::Address;
 use argument::Argument;
 use register::Register;
 use instruction::Instruction;
+use device::{InputDevice, OutputDevice, StdinDevice, StdoutDevice, BufferInput, BufferOutput};
+use snapshot::Snapshot;
+use watch::{Watchpoints, WatchTarget, Access};
+use callstack::{CallStack, Frame};
+use fault::{FaultContext, VMFault, STACK_CONTEXT};
+use history::{Journal, UndoRecord};
+use observer::{VmObserver, Verdict};
+use blocks::{Block, Engine, Exit, Op, Src, MAX_BLOCK_LENGTH};
+use cancel::CancelHandle;
+use livelock::{LoopDetector, LoopReport};
+use pages::Pages;
+use config::{VMConfig, NonAsciiOutput, EmptyReturn, LiteralInput, EndOfInput};
 use constants::*;
 
 pub struct VM {
     instruction_pointer: Address,
     stack: Vec<u16>,
-    memory: [u16; U15_MAX as usize],
+    /// shared page by page with any clones
+    memory: Pages<u16>,
     registers: [u16; 8],
     current_state: VMState,
+    input: Box<dyn InputDevice>,
+    output: Box<dyn OutputDevice>,
+    pending_input: VecDeque<u8>,
+    pending_output: Vec<u8>,
+    yielding: bool,
+    /// where the instruction currently executing started
+    instruction_start: Address,
+    watchpoints: Watchpoints,
+    steps: u64,
+    /// what the current instruction changed, while a `History` is recording
+    journal: Option<Journal>,
+    observers: Vec<Box<dyn VmObserver>>,
+    /// the guest calls we're in, shadowing the return addresses on the stack
+    call_stack: CallStack,
+    /// the step count to stop at, during `run_for`
+    step_limit: Option<u64>,
+    deadline: Option<Instant>,
+    cancel: CancelHandle,
+    loop_detector: Option<LoopDetector>,
+    /// bumped whenever memory changes or input is consumed
+    memory_generation: u64,
+    outputs: u64,
+    config: VMConfig,
+    natives: BTreeMap<Address, NativeRoutine>,
+    /// instructions already decoded, with their size, by address. Entries are dropped whenever
+    /// memory they cover is written.
+    decoded: Pages<Option<(Instruction, u16)>>,
+    use_decode_cache: bool,
+    engine: Engine,
+    /// compiled blocks by start address, and which words of memory they were compiled from
+    blocks: Pages<Option<Arc<Block>>>,
+    block_cover: Pages<bool>,
 }
 
+/// The longest instruction is an opcode and three arguments
+const MAX_INSTRUCTION_SIZE : u16 = 4;
+
+/// How many times round the run loop between looking at the clock
+const DEADLINE_CHECK_INTERVAL : u32 = 1024;
+
+/// A host function standing in for a guest routine, see `VM::set_native`
+pub type NativeRoutine = Arc<dyn Fn(&mut VM) -> Result<(), VMError> + Send + Sync>;
+
 #[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum VMState {
     RUN,
-    HALT
+    HALT,
+    /// Stopped on an `IN` with nothing buffered, push some input and `resume`
+    AwaitingInput,
+    /// A watchpoint with `WatchAction::Break` fired during the last instruction
+    WatchpointHit,
+    /// Stopped between instructions by a `CancelHandle`
+    Paused,
+    /// Stopped between instructions because `run_for` ran its steps, or the deadline passed
+    BudgetExhausted
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -31,79 +96,621 @@ pub enum VMError {
     InvalidCharacterArgument(Argument),
     JumpOutOfBounds(Address),
     StackUnderflow,
+    InvalidValue(u16),
+    EndOfInput,
+    DeviceError(String),
+    /// The loop detector found the program going round in circles
+    Livelock(LoopReport),
     UnknownError
 }
 
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VMError::BadOpcode(o) => write!(f, "bad opcode {}", o),
+            VMError::InvalidMemoryAccess(a) => write!(f, "invalid memory access at {}", a),
+            VMError::MalformedInstruction(ref words) => write!(f, "malformed instruction {:?}", words),
+            VMError::InvalidCharacterArgument(a) => write!(f, "{} is not a character", a),
+            VMError::JumpOutOfBounds(a) => write!(f, "jump out of bounds to {}", a),
+            VMError::StackUnderflow => write!(f, "pop from an empty stack"),
+            VMError::InvalidValue(v) => write!(f, "invalid value {}", v),
+            VMError::EndOfInput => write!(f, "end of input"),
+            VMError::DeviceError(ref e) => write!(f, "device error: {}", e),
+            VMError::Livelock(ref report) => write!(f, "{}", report),
+            VMError::UnknownError => write!(f, "unknown error")
+        }
+    }
+}
+
+impl Error for VMError {}
+
 type VMResult = Result<VMState, VMError>;
 
+/// The result of running the VM, which carries the state of the VM with any error
+type RunResult = Result<VMState, VMFault>;
+
+/// Memory only goes up to 32767, and only holds numbers and register references
+fn check_store(address: Address, value: u16) -> Result<(), VMError> {
+    if !address.is_memory() { return Err(VMError::InvalidMemoryAccess(address)); }
+    check_value(value)
+}
+
+/// A word the VM can hold in memory, a register or on the stack: a number or a register reference
+fn check_value(value: u16) -> Result<(), VMError> {
+    if Address::new(value).is_invalid() { return Err(VMError::InvalidValue(value)); }
+    Ok(())
+}
+
 impl VM {
+    /// A fresh VM reading from stdin and writing to stdout
     pub fn init() -> VM {
+        VM::with_devices(Box::new(StdinDevice::new()), Box::new(StdoutDevice::new()))
+    }
+
+    /// A fresh VM attached to the given input and output devices
+    pub fn with_devices(input: Box<dyn InputDevice>, output: Box<dyn OutputDevice>) -> VM {
         VM {
             instruction_pointer: Address::new(0),
             stack: vec![],
-            memory: [0; U15_MAX as usize],
+            memory: Pages::new(MODULUS as usize, 0),
             registers: [0; 8],
             current_state: VMState::HALT,
+            input,
+            output,
+            pending_input: VecDeque::new(),
+            pending_output: vec![],
+            yielding: false,
+            instruction_start: Address::new(0),
+            watchpoints: Watchpoints::new(),
+            steps: 0,
+            journal: None,
+            observers: vec![],
+            call_stack: CallStack::new(),
+            step_limit: None,
+            deadline: None,
+            cancel: CancelHandle::new(),
+            loop_detector: None,
+            memory_generation: 0,
+            outputs: 0,
+            config: VMConfig::default(),
+            natives: BTreeMap::new(),
+            decoded: Pages::new(MODULUS as usize, None),
+            use_decode_cache: true,
+            engine: Engine::Interpreter,
+            blocks: Pages::new(MODULUS as usize, None),
+            block_cover: Pages::new(MODULUS as usize, false),
         }
     }
 
+    /// Swap out the device `IN` reads from, returning the old one.
+    pub fn set_input_device(&mut self, input: Box<dyn InputDevice>) -> Box<dyn InputDevice> {
+        ::std::mem::replace(&mut self.input, input)
+    }
+
+    /// Swap out the device `OUT` writes to, returning the old one.
+    pub fn set_output_device(&mut self, output: Box<dyn OutputDevice>) -> Box<dyn OutputDevice> {
+        ::std::mem::replace(&mut self.output, output)
+    }
+
+    /// Flush anything the output device is holding on to.
+    pub fn flush_output(&mut self) -> Result<(), VMError> {
+        self.output.flush().map_err(|e| VMError::DeviceError(e.to_string()))
+    }
+
     pub fn instruction_pointer(&self) -> Address {
         return self.instruction_pointer;
     }
 
-    /// Given an offset and some bytecode, write the bytecode to machine memory.
-    pub fn load_program(&mut self, offset: Address, bytecode: &Vec<u16>) {
+    pub fn set_instruction_pointer(&mut self, address: Address) -> Result<(), VMError> {
+        if !address.is_memory() { return Err(VMError::JumpOutOfBounds(address)); }
+        self.instruction_pointer = address;
+        Ok(())
+    }
+
+    pub fn registers(&self) -> [u16; 8] {
+        self.registers
+    }
+
+    pub fn register(&self, r: Register) -> u16 {
+        self.registers[r.as_index()]
+    }
+
+    /// Set a register directly, the value must be a valid 15b number.
+    pub fn set_register(&mut self, r: Register, value: u16) -> Result<(), VMError> {
+        if value > U15_MAX { return Err(VMError::InvalidValue(value)); }
+        if let Some(ref mut j) = self.journal { j.registers.push(r); }
+        self.registers[r.as_index()] = value;
+        Ok(())
+    }
+
+    /// The stack, bottom first.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    /// Push a word onto the stack directly. Like `set_register` the value must be a valid 15b
+    /// number, since a `POP` may copy it straight into a register.
+    pub fn push_stack(&mut self, value: u16) -> Result<(), VMError> {
+        if value > U15_MAX { return Err(VMError::InvalidValue(value)); }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Pop the top of the stack directly. Any guest call whose return address goes with it is
+    /// forgotten.
+    pub fn pop_stack(&mut self) -> Result<u16, VMError> {
+        match self.pop_word() {
+            Some(v) => {
+                self.call_stack.truncate(self.stack.len());
+                Ok(v)
+            },
+            None => Err(VMError::StackUnderflow)
+        }
+    }
+
+    /// Read a word of memory without executing anything.
+    pub fn peek(&self, address: Address) -> Result<u16, VMError> {
+        match self.memory.get(address.to_usize()) {
+            Some(v) if address.is_memory() => Ok(*v),
+            _ => Err(VMError::InvalidMemoryAccess(address))
+        }
+    }
+
+    /// Write a word of memory without executing anything.
+    pub fn poke(&mut self, address: Address, value: u16) -> Result<(), VMError> {
+        if let Err(e) = check_store(address, value) { return Err(e); }
+
+        if let Some(ref mut j) = self.journal { j.memory.push((address, self.memory[address.to_usize()])); }
+        if self.memory[address.to_usize()] != value { self.memory_generation += 1; }
+        self.memory[address.to_usize()] = value;
+        self.invalidate(address);
+        Ok(())
+    }
+
+    /// Read `length` words of memory starting at `start`, without executing anything.
+    pub fn peek_range(&self, start: Address, length: usize) -> Result<Vec<u16>, VMError> {
+        if let Err(e) = self.check_range(start, length) { return Err(e); }
+        Ok((start.to_usize()..start.to_usize() + length).map(|i| self.memory[i]).collect())
+    }
+
+    /// Write `values` to memory starting at `start`, without executing anything. Nothing is
+    /// written unless every word fits and is valid.
+    pub fn poke_range(&mut self, start: Address, values: &[u16]) -> Result<(), VMError> {
+        if let Err(e) = self.check_range(start, values.len()) { return Err(e); }
+        if let Some(&v) = values.iter().find(|v| Address::new(**v).is_invalid()) {
+            return Err(VMError::InvalidValue(v));
+        }
+
+        for (i, v) in values.iter().enumerate() {
+            if let Err(e) = self.poke(Address::new(start.value() + i as u16), *v) { return Err(e); }
+        }
+        Ok(())
+    }
+
+    /// Fails on the first address past the end of memory, if the range goes that far
+    fn check_range(&self, start: Address, length: usize) -> Result<(), VMError> {
+        if start.to_usize() + length > self.memory.len() {
+            let first = cmp::max(start.to_usize(), self.memory.len());
+            return Err(VMError::InvalidMemoryAccess(Address::new(first as u16)));
+        }
+        Ok(())
+    }
+
+    /// How many pages of memory this VM still shares with `other`, a clone of it or something it
+    /// was cloned from
+    pub fn shared_memory_pages(&self, other: &VM) -> usize {
+        self.memory.shared_with(&other.memory)
+    }
+
+    /// Decode the instruction stored at `address`, without moving the instruction pointer.
+    pub fn decode_at(&self, address: Address) -> Result<Instruction, VMError> {
+        let opcode = match self.peek(address) {
+            Ok(o) => o,
+            Err(e) => return Err(e)
+        };
+
+        let arg_count = match Instruction::arg_count(opcode) {
+            Some(a) => a,
+            None => return Err(VMError::BadOpcode(opcode))
+        };
+
+        let mut opcode_sequence = vec![opcode];
+        for i in 1..(arg_count as u16 + 1) {
+            match self.peek(Address::new(address.value() + i)) {
+                Ok(arg) => opcode_sequence.push(arg),
+                Err(e) => return Err(e)
+            }
+        }
+
+        if !Instruction::is_well_formed(&opcode_sequence) {
+            return Err(VMError::MalformedInstruction(opcode_sequence));
+        }
+
+        match Instruction::from_u16_sequence(&opcode_sequence) {
+            Some(i) => Ok(i),
+            None => Err(VMError::MalformedInstruction(opcode_sequence))
+        }
+    }
+
+    /// Copy out everything needed to put the VM back exactly where it is now. Devices and
+    /// undrained output are not included.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            instruction_pointer: self.instruction_pointer,
+            state: self.current_state,
+            registers: self.registers,
+            stack: self.stack.clone(),
+            memory: self.memory.to_vec(),
+            pending_input: self.pending_input.iter().cloned().collect(),
+        }
+    }
+
+    /// Put the VM back into the state captured by `snapshot`. Memory not covered by the snapshot
+    /// is zeroed. Nothing changes if the snapshot doesn't fit, or holds an invalid word.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), VMError> {
+        if snapshot.memory.len() > self.memory.len() {
+            return Err(VMError::InvalidMemoryAccess(Address::new(snapshot.memory.len() as u16)));
+        }
+        let mut words = snapshot.memory.iter().chain(snapshot.registers.iter()).chain(snapshot.stack.iter());
+        if let Some(&v) = words.find(|v| Address::new(**v).is_invalid()) {
+            return Err(VMError::InvalidValue(v));
+        }
+
+        self.memory = Pages::new(MODULUS as usize, 0);
+        for (i, &v) in snapshot.memory.iter().enumerate() {
+            if v != 0 { self.memory[i] = v; }
+        }
+        self.instruction_pointer = snapshot.instruction_pointer;
+        self.current_state = snapshot.state;
+        self.registers = snapshot.registers;
+        self.stack = snapshot.stack.clone();
+        self.pending_input = snapshot.pending_input.iter().cloned().collect();
+        self.call_stack.clear();
+        self.memory_generation += 1;
+        if let Some(ref mut d) = self.loop_detector { d.reset(); }
+        self.clear_decode_cache();
+
+        Ok(())
+    }
+
+    /// Given an offset and some bytecode, write the bytecode to machine memory. Nothing is
+    /// written if it runs past the end of memory or holds an invalid word.
+    pub fn load_program(&mut self, offset: Address, bytecode: &Vec<u16>) -> Result<(), VMError> {
+        let end = offset.to_usize() + bytecode.len();
+        if !offset.is_memory() || end > self.memory.len() {
+            return Err(VMError::InvalidMemoryAccess(Address::new(end.min(u16::max_value() as usize) as u16)));
+        }
+        if let Some(&v) = bytecode.iter().find(|v| Address::new(**v).is_invalid()) {
+            return Err(VMError::InvalidValue(v));
+        }
+
+        self.memory_generation += 1;
         let mut write_addr = offset;
         for v in bytecode {
-            if write_addr.is_valid() {
-                self.write_memory(&write_addr, *v);
-                write_addr.next();
-            } else {
-                panic!("Attempted to load program, but ran out of memory.");
-            }
+            self.memory[write_addr.to_usize()] = *v;
+            self.invalidate(write_addr);
+            write_addr.next();
         }
+        Ok(())
     }
 
     /// Given a series of raw instructions, compile them and write the resulting bytecode to memory
-    pub fn load_instructions(&mut self, offset: Address, instructions: &Vec<Instruction>) {
+    pub fn load_instructions(&mut self, offset: Address, instructions: &Vec<Instruction>) -> Result<(), VMError> {
         let mut program = vec![];
         for i in instructions {
             let mut bytecode = i.to_owned().to_u16_sequence();
             program.append(&mut bytecode);
         }
-        self.load_program(offset, &program);
+        self.load_program(offset, &program)
     }
 
-    pub fn run(&mut self, start_position: Address) -> VMResult {
+    pub fn run(&mut self, start_position: Address) -> RunResult {
+        self.start(start_position);
+        self.yielding = false;
+        self.run_loop()
+    }
+
+    /// Point the VM at `start_position` and mark it running, without executing anything. Use
+    /// `resume` to actually get going.
+    pub fn start(&mut self, start_position: Address) {
         self.instruction_pointer = start_position;
         self.current_state = VMState::RUN;
+    }
+
+    /// Run until the program halts or wants input that hasn't been pushed yet. Unlike `run`, this
+    /// never blocks on the input device, and output is held in the VM for `drain_output` instead
+    /// of going to the output device. The VM stays in this mode for any later `step`s, until the
+    /// next `run`.
+    pub fn resume(&mut self) -> RunResult {
+        self.unpause();
+        self.set_yield_on_input(true);
+        self.run_loop()
+    }
+
+    /// Carry on running for at most `steps` instructions, stopping with `BudgetExhausted` if the
+    /// program is still going after that. Input is handled as it was for the last `run` or
+    /// `resume`.
+    pub fn run_for(&mut self, steps: u64) -> RunResult {
+        self.unpause();
+        self.step_limit = Some(self.steps.saturating_add(steps));
+        let result = self.run_loop();
+        self.step_limit = None;
+        return result;
+    }
+
+    /// Stop any run with `BudgetExhausted` once `deadline` has passed, until it's changed. The
+    /// clock is only checked every so often, so a run can go a little past it.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Set the deadline to `timeout` from now
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.deadline = Some(Instant::now() + timeout);
+    }
+
+    /// A handle which stops this VM with `Paused` from another thread
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Pick up again after stopping for something other than halting
+    fn unpause(&mut self) {
+        match self.current_state {
+            VMState::AwaitingInput | VMState::WatchpointHit | VMState::Paused | VMState::BudgetExhausted => self.current_state = VMState::RUN,
+            _ => ()
+        }
+    }
+
+    /// Whether an `IN` with no pushed input should stop with `AwaitingInput` (and hold output for
+    /// `drain_output`) rather than going to the devices.
+    pub fn set_yield_on_input(&mut self, yielding: bool) {
+        self.yielding = yielding;
+    }
+
+    /// Queue up input for the program, it's consumed before anything on the input device.
+    pub fn push_input(&mut self, input: &str) {
+        self.pending_input.extend(input.bytes());
+    }
+
+    /// Throw away any queued input the program hasn't read yet, and queue `bytes` instead.
+    pub fn replace_input(&mut self, bytes: &[u8]) {
+        self.pending_input.clear();
+        self.pending_input.extend(bytes);
+    }
+
+    /// Take all the output produced while resumed.
+    pub fn drain_output(&mut self) -> String {
+        let bytes : Vec<u8> = self.pending_output.drain(..).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    pub fn state(&self) -> VMState {
+        self.current_state
+    }
+
+    fn run_loop(&mut self) -> RunResult {
+        let mut iterations : u32 = 0;
 
         while self.is_running() {
-            match self.step() {
-                Ok(state) => self.current_state = state,
-                Err(e) => return Err(e)
+            if let Some(state) = self.interruption(iterations) {
+                self.current_state = state;
+                break;
+            }
+            iterations = iterations.wrapping_add(1);
+
+            // observers, watchpoints and the loop detector need to see every instruction, and a
+            // block could take us past the step limit
+            let whole_block = self.step_limit.map_or(true, |l| l.saturating_sub(self.steps) > MAX_BLOCK_LENGTH as u64 + 1);
+            let per_instruction = !self.observers.is_empty() || !self.watchpoints.is_empty() || self.loop_detector.is_some();
+            let result = if self.engine == Engine::Blocks && !per_instruction && whole_block {
+                self.step_block()
+            } else {
+                self.step()
+            };
+
+            if let Err(e) = result {
+                let _ = self.flush_output();
+                return Err(e);
             }
         }
 
-        return Ok(self.current_state); // this should always end up being HALT here.
+        if let Err(e) = self.flush_output() {
+            let start = self.instruction_start;
+            return Err(self.fault(e, start));
+        }
+
+        return Ok(self.current_state); // HALT, AwaitingInput if we're yielding, or interrupted
+    }
+
+    /// Why the run loop should stop before the next instruction, if it should
+    fn interruption(&self, iterations: u32) -> Option<VMState> {
+        if self.cancel.is_cancelled() && self.cancel.reset() { return Some(VMState::Paused); }
+
+        if let Some(limit) = self.step_limit {
+            if self.steps >= limit { return Some(VMState::BudgetExhausted); }
+        }
+
+        if let Some(deadline) = self.deadline {
+            if iterations % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() >= deadline { return Some(VMState::BudgetExhausted); }
+        }
+
+        None
     }
 
     pub fn is_running(&self) -> bool {
         self.current_state == VMState::RUN
     }
 
-    pub fn step(&mut self) -> VMResult {
-        match self.current_instruction() {
-            Ok(current_instruction) => self.execute_instruction(current_instruction),
+    pub fn step(&mut self) -> RunResult {
+        let start = self.instruction_pointer;
+        self.instruction_start = start;
+
+        let mut result = match self.current_instruction() {
+            Ok(current_instruction) => {
+                if self.observers.is_empty() {
+                    self.execute_instruction(current_instruction)
+                } else {
+                    self.execute_observed(start, current_instruction)
+                }
+            },
             Err(e) => Err(e)
+        };
+
+        // an IN with nothing to read gets retried when we resume
+        if result == Ok(VMState::AwaitingInput) { self.instruction_pointer = start; }
+
+        if self.watchpoints.take_break() && result == Ok(VMState::RUN) {
+            result = Ok(VMState::WatchpointHit);
+        }
+        let state = match result {
+            Ok(state) => state,
+            Err(e) => return Err(self.fault(e, start))
+        };
+        self.current_state = state;
+        if state != VMState::AwaitingInput { self.steps += 1; }
+
+        if state == VMState::RUN && self.loop_detector.is_some() {
+            if let Some(report) = self.check_loops(start) {
+                return Err(self.fault(VMError::Livelock(report), start));
+            }
+        }
+
+        return Ok(state);
+    }
+
+    fn check_loops(&mut self, address: Address) -> Option<LoopReport> {
+        let mut detector = match self.loop_detector.take() {
+            Some(d) => d,
+            None => return None
+        };
+        let report = detector.check(address, self);
+        self.loop_detector = Some(detector);
+        return report;
+    }
+
+    /// Stop running with `VMError::Livelock` when `detector` thinks the program is stuck, or
+    /// `None` to stop looking. The program can be resumed afterwards, and will likely be
+    /// reported again.
+    pub fn set_loop_detector(&mut self, detector: Option<LoopDetector>) {
+        self.loop_detector = detector;
+    }
+
+    /// Changes whenever memory changes or input is consumed, so two VMs with the same
+    /// generation, registers, stack and instruction pointer are in the same state
+    pub fn memory_generation(&self) -> u64 {
+        self.memory_generation
+    }
+
+    /// How many characters the program has output
+    pub fn output_count(&self) -> u64 {
+        self.outputs
+    }
+
+    /// `error`, with what the VM looks like now, blamed on the instruction at `address`
+    pub fn fault(&self, error: VMError, address: Address) -> VMFault {
+        let context = FaultContext {
+            address,
+            instruction: self.decode_at(address).ok(),
+            registers: self.registers,
+            stack_top: self.stack.iter().rev().take(STACK_CONTEXT).cloned().collect(),
+            stack_depth: self.stack.len(),
+            step: self.steps,
+        };
+        VMFault { error, context }
+    }
+
+    /// How many instructions have been executed
+    pub fn step_count(&self) -> u64 {
+        self.steps
+    }
+
+    /// Start recording register and memory writes and input consumed, for `History`.
+    pub fn begin_journal(&mut self) {
+        self.journal = Some(Journal { stack_low: self.stack.len(), ..Journal::new() });
+    }
+
+    /// Stop recording, returning everything recorded since `begin_journal`.
+    pub fn end_journal(&mut self) -> Journal {
+        self.journal.take().unwrap_or_else(Journal::new)
+    }
+
+    /// Take back a single instruction, as recorded by `History::step`. Undoing records out of
+    /// order leaves the VM in a state it was never in. Output can't be taken back.
+    pub fn undo(&mut self, record: &UndoRecord) {
+        self.memory_generation += 1;
+        for &(address, old) in record.memory.iter().rev() {
+            self.memory[address.to_usize()] = old;
+            self.invalidate(address);
+        }
+        let kept = self.stack.len() - record.stack.pushed;
+        self.stack.truncate(kept);
+        self.stack.extend(&record.stack.removed);
+        self.call_stack.truncate(self.stack.len());
+        self.call_stack.extend(&record.frames);
+        if let Some(b) = record.input { self.pending_input.push_front(b); }
+
+        self.instruction_pointer = record.instruction_pointer;
+        self.current_state = record.state;
+        self.registers = record.registers;
+        self.steps = record.step;
+    }
+
+    /// Add an observer, which sees everything the program does from the next instruction on
+    pub fn add_observer(&mut self, observer: Box<dyn VmObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Remove all the observers, handing them back
+    pub fn take_observers(&mut self) -> Vec<Box<dyn VmObserver>> {
+        self.observers.drain(..).collect()
+    }
+
+    /// Run `routine` instead of the guest code whenever the program `CALL`s `address`. The
+    /// routine gets the VM as it is at the call, with the instruction pointer already past the
+    /// `CALL`, and execution carries on from there as if the guest routine had returned. An error
+    /// from the routine stops the VM like any other.
+    pub fn set_native<F>(&mut self, address: Address, routine: F)
+        where F: Fn(&mut VM) -> Result<(), VMError> + Send + Sync + 'static {
+        self.natives.insert(address, Arc::new(routine));
+    }
+
+    /// Go back to running the guest code at `address`, returning whether there was a native
+    /// routine there.
+    pub fn remove_native(&mut self, address: Address) -> bool {
+        self.natives.remove(&address).is_some()
+    }
+
+    pub fn has_native(&self, address: Address) -> bool {
+        self.natives.contains_key(&address)
+    }
+
+    /// Execute an instruction, letting the observers have their say before and after
+    fn execute_observed(&mut self, address: Address, instruction: Instruction) -> VMResult {
+        let mut verdict = Verdict::Continue;
+        for o in self.observers.iter_mut() {
+            verdict = o.before_instruction(address, &instruction);
+            if verdict != Verdict::Continue { break; }
+        }
+
+        let result = match verdict {
+            Verdict::Continue => self.execute_instruction(instruction.clone()),
+            Verdict::Skip => Ok(VMState::RUN),
+            Verdict::Halt => Ok(VMState::HALT)
+        };
+
+        for o in self.observers.iter_mut() {
+            o.after_instruction(address, &instruction, &result);
         }
+
+        return result;
     }
 
     fn execute_instruction(&mut self, instruction: Instruction) -> VMResult {
        match instruction {
            Instruction::HALT         => Ok(VMState::HALT),
            Instruction::SET(r,a)     => self.write_register(r, a),
-           Instruction::PUSH(arg)    => { self.push(arg); Ok(VMState::RUN) } // push can never fail
+           Instruction::PUSH(arg)    => self.push(arg),
            Instruction::POP(r)       => self.pop(r),
            Instruction::EQ(r, arg_a, arg_b)   => {
                let a : u15 = u15(self.parse_argument(arg_a));
@@ -179,20 +786,60 @@ impl VM {
        }
     }
 
+    /// Choose how to handle the corners of the spec, see `VMConfig`
+    pub fn set_config(&mut self, config: VMConfig) {
+        self.config = config;
+    }
+
+    pub fn config(&self) -> VMConfig {
+        self.config
+    }
+
+    /// reads a byte of pushed input, or from the input device, into the target. Output is
+    /// flushed first so any prompt is visible before we block. When yielding, we never touch the
+    /// device and instead report that we're waiting.
     fn read_input(&mut self, a: Argument) -> VMResult {
-        let mut stdin = io::stdin();
-        let mut buf : [u8; 1] = [0; 1];
+        if let Argument::Literal(addr) = a {
+            if self.config.literal_input == LiteralInput::Error {
+                return Err(VMError::MalformedInstruction(vec![20, addr.0]));
+            }
+        }
 
-        stdin.read_exact(&mut buf);
+        let mut supplied = None;
+        for o in self.observers.iter_mut() {
+            supplied = o.input_requested();
+            if supplied.is_some() { break; }
+        }
+
+        let byte = match supplied.or_else(|| self.pending_input.pop_front()) {
+            Some(b) => b,
+            None => {
+                if self.yielding { return Ok(VMState::AwaitingInput); }
+                if let Err(e) = self.flush_output() { return Err(e); }
+
+                match self.input.read_byte() {
+                    Ok(Some(b)) => b,
+                    Ok(None) => return match self.config.end_of_input {
+                        EndOfInput::Error => Err(VMError::EndOfInput),
+                        EndOfInput::Halt => Ok(VMState::HALT)
+                    },
+                    Err(e) => return Err(VMError::DeviceError(e.to_string()))
+                }
+            }
+        };
+        if supplied.is_none() {
+            if let Some(ref mut j) = self.journal { j.input = Some(byte); }
+        }
+        for o in self.observers.iter_mut() { o.input(byte); }
+        self.memory_generation += 1;
 
         match a {
             Argument::Literal(addr) => {
                 let target = Address::new(addr.0);
-                self.write_memory(&target, buf[0] as u16);
-                Ok(VMState::RUN)
+                self.write_memory(&target, byte as u16)
             },
             Argument::Register(r) => {
-                self.write_register(r, Argument::new(buf[0] as u16))
+                self.write_register(r, Argument::new(byte as u16))
             }
         }
     }
@@ -210,46 +857,118 @@ impl VM {
         let target = Address::new(self.parse_argument(t));
         let value = self.parse_argument(v);
 
-        self.write_memory(&target, value);
-
-        Ok(VMState::RUN)
+        self.write_memory(&target, value)
     }
 
     /// Push the address of the next instruction to the stack, jump to given address
+    /// If there's a native routine at the target, run that instead and carry on.
     fn call(&mut self, a: Argument) -> VMResult {
+        let target = self.parse_argument(a);
+
+        if !self.natives.is_empty() {
+            let native = self.natives.get(&Address::new(target)).cloned();
+            if let Some(routine) = native {
+                return match routine(self) {
+                    Ok(()) => Ok(VMState::RUN),
+                    Err(e) => Err(e)
+                };
+            }
+        }
+
         // get the position of the next instruction
         let cur_ptr = self.instruction_pointer.to_u16();
-        self.push(Argument::new(cur_ptr));
-        self.jump(a)
+        if let Err(e) = self.push(Argument::new(cur_ptr)) { return Err(e); }
+        self.enter(self.instruction_start, target);
+        self.jump(Argument::Literal(u15(target)))
     }
 
     /// Pop the top of the stack, jump to the address attained.
-    /// If empty, halt.
-    ///
-    /// Note that this is very similar to #pop, but does not error on StackUnderflow
+    /// If empty, halt, unless the config says otherwise.
     fn ret(&mut self) -> VMResult {
-        match self.stack.pop() {
-          Some(v) => self.jump(Argument::new(v)),
-          None => Ok(VMState::HALT)
+        match self.pop_word() {
+          Some(v) => {
+              let v = match self.observe_pop(v) {
+                  Ok(v) => v,
+                  Err(e) => return Err(e)
+              };
+              self.leave(self.instruction_start, v);
+              self.jump(Argument::Literal(u15(v)))
+          },
+          None => self.empty_return()
+        }
+    }
+
+    fn empty_return(&self) -> VMResult {
+        match self.config.empty_return {
+            EmptyReturn::Halt => Ok(VMState::HALT),
+            EmptyReturn::Error => Err(VMError::StackUnderflow)
         }
     }
 
+    /// Note a call to `target` from the `CALL` at `call_site`, with its return address just pushed
+    fn enter(&mut self, call_site: Address, target: u16) {
+        let frame = Frame {
+            call_site,
+            target: Address::new(target),
+            return_address: Address::new(self.stack.last().cloned().unwrap_or(0)),
+            stack_depth: self.stack.len(),
+        };
+        self.call_stack.call(frame);
+    }
+
+    /// Note the `RET` at `at` popping `value`
+    fn leave(&mut self, at: Address, value: u16) {
+        let removed = self.journal.as_mut().map(|j| &mut j.frames);
+        self.call_stack.ret(at, value, self.stack.len(), removed);
+    }
+
+    /// The guest calls the VM is in the middle of
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
     /// Pop a value off the stack, put it in given register
     fn pop(&mut self, r: Register) -> VMResult {
-        match self.stack.pop() {
-          Some(v) => self.write_register(r, Argument::new(v)),
+        match self.pop_word() {
+          Some(v) => match self.observe_pop(v) {
+              Ok(v) => self.write_register(r, Argument::Literal(u15(v))),
+              Err(e) => Err(e)
+          },
           None => Err(VMError::StackUnderflow)
         }
     }
 
+    /// Pop the top of the stack, journaling it if it was there before the instruction started
+    fn pop_word(&mut self) -> Option<u16> {
+        let v = self.stack.pop();
+        if let (Some(v), Some(ref mut j)) = (v, self.journal.as_mut()) {
+            if self.stack.len() < j.stack_low {
+                j.stack_low = self.stack.len();
+                j.popped.push(v);
+            }
+        }
+        v
+    }
+
     /// Push the given argument onto the stack
-    fn push(&mut self, arg: Argument) {
-        let v = self.parse_argument(arg);
+    fn push(&mut self, arg: Argument) -> VMResult {
+        let mut v = self.parse_argument(arg);
+        for o in self.observers.iter_mut() { v = o.push(v); }
+        if let Err(e) = check_value(v) { return Err(e); }
         self.stack.push(v);
+        Ok(VMState::RUN)
+    }
+
+    /// Let the observers see, and change, a value popped off the stack
+    fn observe_pop(&mut self, value: u16) -> Result<u16, VMError> {
+        let mut v = value;
+        for o in self.observers.iter_mut() { v = o.pop(v); }
+        if let Err(e) = check_value(v) { return Err(e); }
+        return Ok(v);
     }
 
     /// Checks if the argument is non-zero
-    fn check_true(&self, arg: Argument) -> bool {
+    fn check_true(&mut self, arg: Argument) -> bool {
         let target = match arg {
             Argument::Literal(v) => v.0,
             Argument::Register(r) => self.read_register(r)
@@ -275,52 +994,291 @@ impl VM {
     }
 
     /// extract the value of an argument, either reading the register, or interpreting as a literal
-    fn parse_argument(&self, arg: Argument) -> u16 {
+    fn parse_argument(&mut self, arg: Argument) -> u16 {
         match arg {
             Argument::Literal(v) => v.0,
             Argument::Register(r) => self.read_register(r)
         }
     }
 
-    /// writes the argument to stdout
-    ///
-    /// TODO: make this write to a buffer held in the VM struct
-    fn write_output(&self, arg: Argument) -> VMResult {
-        let chr = char::from(self.parse_argument(arg) as u8);
+    /// writes the argument to the output device
+    fn write_output(&mut self, arg: Argument) -> VMResult {
+        let value = self.parse_argument(arg);
+        let ascii = match self.config.non_ascii_output {
+            NonAsciiOutput::Error => value < 128,
+            NonAsciiOutput::Truncate => (value as u8) < 128,
+            NonAsciiOutput::Raw => true
+        };
+        if !ascii { return Err(VMError::InvalidCharacterArgument(arg)); }
 
-        if !chr.is_ascii() { return Err(VMError::InvalidCharacterArgument(arg)); }
+        let mut byte = value as u8;
+        for o in self.observers.iter_mut() {
+            match o.output(byte) {
+                Some(b) => byte = b,
+                None => return Ok(VMState::RUN)
+            }
+        }
+
+        self.outputs += 1;
+        if self.yielding {
+            self.pending_output.push(byte);
+            return Ok(VMState::RUN);
+        }
 
-        print!("{}", chr);
+        match self.output.write_byte(byte) {
+            Ok(()) => Ok(VMState::RUN),
+            Err(e) => Err(VMError::DeviceError(e.to_string()))
+        }
+    }
 
-        Ok(VMState::RUN)
+    /// The watchpoints on this VM. Only accesses made by executing instructions are watched,
+    /// instruction fetch and the inspection methods (`peek`, `register`, etc) are not.
+    pub fn watchpoints(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    /// Note an access of a register or memory made by the running program
+    fn observe(&mut self, target: WatchTarget, access: Access, value: u16) {
+        if self.watchpoints.is_empty() { return; }
+        self.watchpoints.check(target, access, value, self.instruction_start);
     }
 
     /// read the value stored in the given register
-    fn read_register(&self, r: Register) -> u16 {
-        return self.registers[r.as_index()];
+    fn read_register(&mut self, r: Register) -> u16 {
+        let v = self.registers[r.as_index()];
+        self.observe(WatchTarget::Register(r), Access::Read, v);
+        return v;
     }
 
     /// write the given value to the given register
     fn write_register(&mut self, r: Register, a: Argument) -> VMResult {
-        let arg = self.parse_argument(a);
+        let mut arg = self.parse_argument(a);
+        for o in self.observers.iter_mut() {
+            match o.register_write(r, arg) {
+                Some(v) => arg = v,
+                None => return Ok(VMState::RUN)
+            }
+        }
+        if let Err(e) = check_value(arg) { return Err(e); }
 
+        if let Some(ref mut j) = self.journal { j.registers.push(r); }
         self.registers[r.as_index()] = arg;
+        self.observe(WatchTarget::Register(r), Access::Write, arg);
 
         Ok(VMState::RUN)
     }
 
     /// write the given value at the given address in memory.
-    fn write_memory(&mut self, address: &Address, value: u16) {
+    fn write_memory(&mut self, address: &Address, value: u16) -> VMResult {
+        let mut value = value;
+        for o in self.observers.iter_mut() {
+            match o.memory_write(*address, value) {
+                Some(v) => value = v,
+                None => return Ok(VMState::RUN)
+            }
+        }
+        if let Err(e) = check_store(*address, value) { return Err(e); }
+
+        if let Some(ref mut j) = self.journal { j.memory.push((*address, self.memory[address.to_usize()])); }
+        if self.memory[address.to_usize()] != value { self.memory_generation += 1; }
         self.memory[address.value() as usize] = value;
+        self.invalidate(*address);
+        self.observe(WatchTarget::Memory(*address), Access::Write, value);
+        Ok(VMState::RUN)
     }
 
     /// Read the value at memory address `location`
-    fn read_memory(&self, location: &Address) -> Result<u16, VMError> {
-        if location.is_invalid() { return Err(VMError::InvalidMemoryAccess(*location)); }
+    fn read_memory(&mut self, location: &Address) -> Result<u16, VMError> {
+        let mut v = match self.fetch(location) {
+            Ok(v) => v,
+            Err(e) => return Err(e)
+        };
+        for o in self.observers.iter_mut() { v = o.memory_read(*location, v); }
+        self.observe(WatchTarget::Memory(*location), Access::Read, v);
+        Ok(v)
+    }
+
+    /// Read the value at `location` without it counting as an access by the program
+    fn fetch(&self, location: &Address) -> Result<u16, VMError> {
+        if !location.is_memory() { return Err(VMError::InvalidMemoryAccess(*location)); }
         Ok(self.memory[location.to_usize()])
     }
 
+    /// Turn the decode cache on or off, it's on by default. There's no reason to turn it off
+    /// other than to measure what it buys us.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.use_decode_cache = enabled;
+        self.clear_decode_cache();
+    }
+
+    fn clear_decode_cache(&mut self) {
+        self.decoded = Pages::new(MODULUS as usize, None);
+        self.clear_blocks();
+    }
+
+    fn clear_blocks(&mut self) {
+        self.blocks = Pages::new(MODULUS as usize, None);
+        self.block_cover = Pages::new(MODULUS as usize, false);
+    }
+
+    /// Forget any decoded instruction or block covering `address`
+    fn invalidate(&mut self, address: Address) {
+        if self.block_cover.get(address.to_usize()) == Some(&true) { self.clear_blocks(); }
+
+        let end = address.to_usize();
+        let start = end.saturating_sub(MAX_INSTRUCTION_SIZE as usize - 1);
+        for i in start..(end + 1).min(self.decoded.len()) {
+            // leave pages shared with a clone alone unless there's really something to drop
+            if self.decoded[i].is_some() { self.decoded[i] = None; }
+        }
+    }
+
+    /// Choose how `run` and `resume` execute the program. `step` always executes a single
+    /// instruction whatever the engine.
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+        self.clear_blocks();
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    fn compile_block(&mut self, start: Address) -> Arc<Block> {
+        let block = Arc::new(Block::compile(self, start));
+        if start.to_usize() < self.blocks.len() {
+            for i in start.to_usize()..block.end.to_usize().min(self.block_cover.len()) {
+                self.block_cover[i] = true;
+            }
+            self.blocks[start.to_usize()] = Some(block.clone());
+        }
+        return block;
+    }
+
+    fn src(&self, s: Src) -> u16 {
+        match s {
+            Src::Lit(v) => v,
+            Src::Reg(i) => self.registers[i]
+        }
+    }
+
+    /// Execute a straight-line op, or report the address to stop at if it underflows the stack
+    fn execute_op(&mut self, op: Op) -> Result<(), Address> {
+        match op {
+            Op::Set(r, a) => self.registers[r] = self.src(a),
+            Op::Eq(r, a, b) => self.registers[r] = (self.src(a) == self.src(b)) as u16,
+            Op::Gt(r, a, b) => self.registers[r] = (self.src(a) > self.src(b)) as u16,
+            Op::Add(r, a, b) => self.registers[r] = (u15(self.src(a)) + u15(self.src(b))).0,
+            Op::Mult(r, a, b) => self.registers[r] = (u15(self.src(a)) * u15(self.src(b))).0,
+            Op::Mod(r, a, b) => self.registers[r] = (u15(self.src(a)) % u15(self.src(b))).0,
+            Op::And(r, a, b) => self.registers[r] = (u15(self.src(a)) & u15(self.src(b))).0,
+            Op::Or(r, a, b) => self.registers[r] = (u15(self.src(a)) | u15(self.src(b))).0,
+            Op::Not(r, a) => self.registers[r] = (!u15(self.src(a))).0,
+            Op::Push(a) => { let v = self.src(a); self.stack.push(v); },
+            Op::Pop(r, next) => match self.stack.pop() {
+                Some(v) => self.registers[r] = v,
+                None => return Err(next)
+            },
+            Op::Noop => ()
+        }
+        Ok(())
+    }
+
+    /// Execute the block starting at the instruction pointer, compiling it first if needed.
+    /// Anything a block can't do is handed to `step`.
+    fn step_block(&mut self) -> RunResult {
+        let start = self.instruction_pointer;
+        let block = match self.blocks.get(start.to_usize()) {
+            Some(&Some(ref b)) => b.clone(),
+            _ => self.compile_block(start)
+        };
+        self.instruction_start = start;
+
+        for (i, op) in block.ops.iter().enumerate() {
+            if let Err(next) = self.execute_op(*op) {
+                self.instruction_pointer = next;
+                self.steps += i as u64;
+                // only POP fails, and it's two words long
+                return Err(self.fault(VMError::StackUnderflow, Address::new(next.value() - 2)));
+            }
+        }
+        let straight_line = block.ops.len() as u64;
+        self.instruction_pointer = block.end;
+
+        let result = match block.exit {
+            Exit::Step(at) => {
+                self.instruction_pointer = at;
+                self.steps += straight_line;
+                return self.step();
+            },
+            Exit::Call(_, at, _) if !self.natives.is_empty() => {
+                self.instruction_pointer = at;
+                self.steps += straight_line;
+                return self.step();
+            },
+            Exit::Jmp(t) => { let t = self.src(t); self.jump(Argument::Literal(u15(t))) },
+            Exit::Jt(c, t) => {
+                if self.src(c) > 0 { let t = self.src(t); self.jump(Argument::Literal(u15(t))) } else { Ok(VMState::RUN) }
+            },
+            Exit::Jf(c, t) => {
+                if self.src(c) == 0 { let t = self.src(t); self.jump(Argument::Literal(u15(t))) } else { Ok(VMState::RUN) }
+            },
+            Exit::CompareBranch(compare, jump_if, t) => {
+                let _ = self.execute_op(compare);
+                self.steps += 1;
+                let condition = match compare {
+                    Op::Eq(r, _, _) | Op::Gt(r, _, _) => self.registers[r] > 0,
+                    _ => false
+                };
+                if condition == jump_if { let t = self.src(t); self.jump(Argument::Literal(u15(t))) } else { Ok(VMState::RUN) }
+            },
+            Exit::Call(t, at, ret) => {
+                let t = self.src(t);
+                self.stack.push(ret.value());
+                self.enter(at, t);
+                self.jump(Argument::Literal(u15(t)))
+            },
+            Exit::Ret => match self.stack.pop() {
+                Some(v) => {
+                    self.leave(Address::new(block.end.value() - 1), v);
+                    self.jump(Argument::Literal(u15(v)))
+                },
+                None => self.empty_return()
+            }
+        };
+
+        self.steps += straight_line;
+        match result {
+            Ok(state) => {
+                self.current_state = state;
+                self.steps += 1;
+                Ok(state)
+            },
+            Err(e) => Err(self.fault(e, Address::new(block.end.value() - block.exit.size())))
+        }
+    }
+
+    /// Decode the instruction at the instruction pointer and move past it, using the decode cache
+    /// where we can.
     fn current_instruction(&mut self) -> Result<Instruction, VMError> {
+        let start = self.instruction_pointer;
+
+        if let Some(&Some((ref instruction, size))) = self.decoded.get(start.to_usize()) {
+            self.instruction_pointer = Address::new(start.value() + size);
+            return Ok(instruction.clone());
+        }
+
+        let result = self.decode_current();
+        if let Ok(ref instruction) = result {
+            if self.use_decode_cache && start.to_usize() < self.decoded.len() {
+                let size = self.instruction_pointer.value() - start.value();
+                self.decoded[start.to_usize()] = Some((instruction.clone(), size));
+            }
+        }
+        return result;
+    }
+
+    fn decode_current(&mut self) -> Result<Instruction, VMError> {
         let opcode = match self.advance() {
             Ok(o) => o,
             Err(e) => return Err(e)
@@ -339,6 +1297,10 @@ impl VM {
             }
         }
 
+        if !Instruction::is_well_formed(&opcode_sequence) {
+            return Err(VMError::MalformedInstruction(opcode_sequence));
+        }
+
         match Instruction::from_u16_sequence(&opcode_sequence) {
             Some(i) => Ok(i),
             None => Err(VMError::MalformedInstruction(opcode_sequence))
@@ -348,16 +1310,59 @@ impl VM {
     /// Get the current value at the instruction_pointer and advance the pointer forward
     /// one address.
     fn advance(&mut self) -> Result<u16, VMError> {
-        let ret = self.read_memory(&self.instruction_pointer);
+        let ret = self.fetch(&self.instruction_pointer);
         self.instruction_pointer.next();
         return ret
     }
 }
 
+/// Fork the VM, eg to try each branch of a search from the same point. Memory and the decode
+/// caches are shared page by page, so a clone only pays for the pages either side writes to.
+///
+/// The clone doesn't share the original's devices: it gets an empty `BufferInput` and a
+/// `BufferOutput`, so drive it with `push_input` and `drain_output`. It also gets its own
+/// `CancelHandle`, and none of the observers, which can't be copied.
+impl Clone for VM {
+    fn clone(&self) -> VM {
+        VM {
+            instruction_pointer: self.instruction_pointer,
+            stack: self.stack.clone(),
+            memory: self.memory.clone(),
+            registers: self.registers,
+            current_state: self.current_state,
+            input: Box::new(BufferInput::new()),
+            output: Box::new(BufferOutput::new()),
+            pending_input: self.pending_input.clone(),
+            pending_output: self.pending_output.clone(),
+            yielding: self.yielding,
+            instruction_start: self.instruction_start,
+            watchpoints: self.watchpoints.clone(),
+            steps: self.steps,
+            journal: self.journal.clone(),
+            observers: vec![],
+            call_stack: self.call_stack.clone(),
+            step_limit: self.step_limit,
+            deadline: self.deadline,
+            cancel: CancelHandle::new(),
+            loop_detector: self.loop_detector.clone(),
+            memory_generation: self.memory_generation,
+            outputs: self.outputs,
+            config: self.config,
+            natives: self.natives.clone(),
+            decoded: self.decoded.clone(),
+            use_decode_cache: self.use_decode_cache,
+            engine: self.engine,
+            blocks: self.blocks.clone(),
+            block_cover: self.block_cover.clone(),
+        }
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use register::Register;
+    use device::{BufferInput, BufferOutput};
 
     fn example_program() -> Vec<u16> {
         // FROM THE SPEC:
@@ -370,7 +1375,7 @@ mod tests {
     // loaded vm with the example program
     fn loaded_vm() -> VM {
         let mut vm = VM::init();
-        vm.load_program(Address::new(1000), &example_program());
+        vm.load_program(Address::new(1000), &example_program()).unwrap();
         return vm;
     }
 
@@ -382,7 +1387,7 @@ mod tests {
         #[test]
         fn valid_program_load() {
             let mut vm = VM::init();
-            vm.load_program(Address::new(1000), &example_program());
+            vm.load_program(Address::new(1000), &example_program()).unwrap();
 
             assert_eq!(vm.memory[1000], 9);
             assert_eq!(vm.memory[1001], 32768);
@@ -391,6 +1396,92 @@ mod tests {
             assert_eq!(vm.memory[1004], 19);
             assert_eq!(vm.memory[1005], 32768);
         }
+
+        #[test]
+        fn last_word() {
+            let mut vm = VM::init();
+            vm.load_program(Address::new(32767), &vec![21]).unwrap();
+            assert_eq!(vm.peek(Address::new(32767)), Ok(21));
+        }
+
+        #[test]
+        fn past_the_end() {
+            let mut vm = VM::init();
+            assert_eq!(vm.load_program(Address::new(32766), &example_program()), Err(VMError::InvalidMemoryAccess(Address::new(32772))));
+            assert_eq!(vm.memory[32766], 0);
+        }
+
+        #[test]
+        fn invalid_word() {
+            let mut vm = VM::init();
+            assert_eq!(vm.load_program(Address::new(0), &vec![6, 40000]), Err(VMError::InvalidValue(40000)));
+            assert_eq!(vm.memory[0], 0);
+        }
+    }
+
+    mod api {
+        use super::*;
+
+        #[test]
+        fn registers() {
+            let mut vm = VM::init();
+            vm.set_register(Register::R3, 7).unwrap();
+            assert_eq!(vm.register(Register::R3), 7);
+            assert_eq!(vm.set_register(Register::R3, 40000), Err(VMError::InvalidValue(40000)));
+            assert_eq!(vm.registers(), [0, 0, 0, 7, 0, 0, 0, 0]);
+        }
+
+        #[test]
+        fn memory_ranges() {
+            let mut vm = VM::init();
+            vm.poke_range(Address::new(100), &[1, 2, REGISTER_7]).unwrap();
+            assert_eq!(vm.peek_range(Address::new(100), 3), Ok(vec![1, 2, REGISTER_7]));
+            assert_eq!(vm.peek_range(Address::new(32765), 3), Ok(vec![0, 0, 0]));
+
+            assert_eq!(vm.peek_range(Address::new(32766), 3), Err(VMError::InvalidMemoryAccess(Address::new(32768))));
+            assert_eq!(vm.peek_range(Address::new(40000), 0), Err(VMError::InvalidMemoryAccess(Address::new(40000))));
+            assert_eq!(vm.poke_range(Address::new(32767), &[1, 2]), Err(VMError::InvalidMemoryAccess(Address::new(32768))));
+            assert_eq!(vm.poke_range(Address::new(100), &[5, 40000]), Err(VMError::InvalidValue(40000)));
+            assert_eq!(vm.memory[100], 1);
+            assert_eq!(vm.memory[32767], 0);
+        }
+
+        #[test]
+        fn stack() {
+            let mut vm = VM::init();
+            vm.push_stack(5).unwrap();
+            vm.push_stack(U15_MAX).unwrap();
+            assert_eq!(vm.push_stack(REGISTER_0), Err(VMError::InvalidValue(REGISTER_0)));
+            assert_eq!(vm.push_stack(40000), Err(VMError::InvalidValue(40000)));
+            assert_eq!(vm.stack(), &[5, U15_MAX]);
+
+            assert_eq!(vm.pop_stack(), Ok(U15_MAX));
+            assert_eq!(vm.pop_stack(), Ok(5));
+            assert_eq!(vm.pop_stack(), Err(VMError::StackUnderflow));
+        }
+
+        #[test]
+        fn popping_a_return_address_leaves_the_call() {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![Instruction::CALL(Argument::new(10))]).unwrap();
+            vm.load_instructions(Address::new(10), &vec![Instruction::IN(Argument::new(REGISTER_0))]).unwrap();
+            vm.start(Address::new(0));
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.call_stack().depth(), 1);
+
+            assert_eq!(vm.pop_stack(), Ok(2));
+            assert_eq!(vm.call_stack().depth(), 0);
+        }
+
+        #[test]
+        fn instruction_pointer_and_state() {
+            let mut vm = VM::init();
+            assert_eq!(vm.state(), VMState::HALT);
+            vm.set_instruction_pointer(Address::new(32767)).unwrap();
+            assert_eq!(vm.instruction_pointer(), Address::new(32767));
+            assert_eq!(vm.set_instruction_pointer(Address::new(REGISTER_0)), Err(VMError::JumpOutOfBounds(Address::new(REGISTER_0))));
+            assert_eq!(vm.instruction_pointer(), Address::new(32767));
+        }
     }
 
     mod instructions {
@@ -405,7 +1496,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::GT(Register::R0, Argument::new(2), Argument::new(2))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -419,7 +1510,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::GT(Register::R0, Argument::new(3), Argument::new(2))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -434,7 +1525,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(13)),
                     Instruction::GT(Register::R0, Argument::new(15), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -449,7 +1540,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(13)),
                     Instruction::GT(Register::R0, Argument::new(REGISTER_1), Argument::new(15))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -465,7 +1556,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(2)),
                     Instruction::SET(Register::R0, Argument::new(1)),
                     Instruction::GT(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -483,7 +1574,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::EQ(Register::R0, Argument::new(2), Argument::new(2))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -498,7 +1589,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::EQ(Register::R0, Argument::new(15), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -513,7 +1604,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::EQ(Register::R0, Argument::new(REGISTER_1), Argument::new(15))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -529,7 +1620,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(1)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::EQ(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -546,7 +1637,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::NOT(Register::R0, Argument::new(4))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -560,7 +1651,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::NOT(Register::R0,  Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -576,7 +1667,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::OR(Register::R0, Argument::new(2), Argument::new(4))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -590,7 +1681,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::OR(Register::R0, Argument::new(4), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -603,7 +1694,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(10)),
                     Instruction::OR(Register::R0, Argument::new(REGISTER_1), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -617,7 +1708,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(10)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::OR(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -633,7 +1724,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::OR(Register::R0, Argument::new(2), Argument::new(5))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -647,7 +1738,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::AND(Register::R0, Argument::new(4), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -660,7 +1751,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::AND(Register::R0, Argument::new(REGISTER_1), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -674,7 +1765,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::AND(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -690,7 +1781,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::ADD(Register::R0, Argument::new(2), Argument::new(2))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -702,7 +1793,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::ADD(Register::R0, Argument::new(3), Argument::new(MODULUS - 3))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -715,7 +1806,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::ADD(Register::R0, Argument::new(2), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -728,7 +1819,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(MODULUS-2)),
                     Instruction::ADD(Register::R0, Argument::new(2), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -741,7 +1832,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::ADD(Register::R0, Argument::new(REGISTER_1), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -754,7 +1845,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(MODULUS-2)),
                     Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -768,7 +1859,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::ADD(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -782,7 +1873,7 @@ mod tests {
                     Instruction::SET(Register::R0, Argument::new(MODULUS-2)),
                     Instruction::SET(Register::R1, Argument::new(2)),
                     Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(REGISTER_1)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -798,7 +1889,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::MULT(Register::R0, Argument::new(2), Argument::new(2))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -810,7 +1901,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::MULT(Register::R0, Argument::new(2), Argument::new(MODULUS - 1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -823,7 +1914,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::MULT(Register::R0, Argument::new(2), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -836,7 +1927,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(MODULUS-1)),
                     Instruction::MULT(Register::R0, Argument::new(2), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -849,7 +1940,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::MULT(Register::R0, Argument::new(REGISTER_1), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -862,7 +1953,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(MODULUS-1)),
                     Instruction::MULT(Register::R0, Argument::new(REGISTER_0), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -876,7 +1967,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::MULT(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -890,7 +1981,7 @@ mod tests {
                     Instruction::SET(Register::R0, Argument::new(MODULUS-2)),
                     Instruction::SET(Register::R1, Argument::new(2)),
                     Instruction::MULT(Register::R0, Argument::new(REGISTER_0), Argument::new(REGISTER_1)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -906,7 +1997,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::MOD(Register::R0, Argument::new(5), Argument::new(2))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -919,7 +2010,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                         Instruction::SET(Register::R1, Argument::new(15)),
                         Instruction::MOD(Register::R0, Argument::new(20), Argument::new(REGISTER_1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -932,7 +2023,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::MOD(Register::R0, Argument::new(REGISTER_1), Argument::new(2)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -946,7 +2037,7 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(15)),
                     Instruction::SET(Register::R0, Argument::new(2)),
                     Instruction::MOD(Register::R0, Argument::new(REGISTER_1), Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -963,7 +2054,7 @@ mod tests {
                 let mut vm = VM::init();
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(15)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -976,7 +2067,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(15)),
                     Instruction::SET(Register::R1, Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -992,7 +2083,7 @@ mod tests {
             fn lit() {
                 let mut vm = VM::init();
 
-                vm.load_instructions(Address::new(0), &vec![Instruction::JMP(Argument::new(10))]);
+                vm.load_instructions(Address::new(0), &vec![Instruction::JMP(Argument::new(10))]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1011,7 +2102,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(15)),
                     Instruction::JMP(Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1030,7 +2121,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::JT(Argument::new(1), Argument::new(10))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1045,7 +2136,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::EQ(Register::R0, Argument::new(2), Argument::new(2)),
                     Instruction::JT(Argument::new(REGISTER_0), Argument::new(10))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1061,7 +2152,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R6, Argument::new(10)),
                     Instruction::JT(Argument::new(0), Argument::new(REGISTER_6))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1077,7 +2168,7 @@ mod tests {
                     Instruction::EQ(Register::R0, Argument::new(2), Argument::new(2)),
                     Instruction::SET(Register::R6, Argument::new(10)),
                     Instruction::JT(Argument::new(REGISTER_0), Argument::new(REGISTER_6))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1096,7 +2187,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::JF(Argument::new(0), Argument::new(10))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1111,7 +2202,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::EQ(Register::R0, Argument::new(3), Argument::new(2)),
                     Instruction::JF(Argument::new(REGISTER_0), Argument::new(10))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1127,7 +2218,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R6, Argument::new(10)),
                     Instruction::JF(Argument::new(10), Argument::new(REGISTER_6))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1143,7 +2234,7 @@ mod tests {
                     Instruction::EQ(Register::R0, Argument::new(3), Argument::new(2)),
                     Instruction::SET(Register::R6, Argument::new(10)),
                     Instruction::JF(Argument::new(REGISTER_0), Argument::new(REGISTER_6))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1161,7 +2252,7 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::PUSH(Argument::new(10))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1176,7 +2267,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(3)),
                     Instruction::PUSH(Argument::new(REGISTER_0))
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1195,7 +2286,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::PUSH(Argument::new(10)),
                     Instruction::POP(Register::R0)
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1211,7 +2302,7 @@ mod tests {
                     Instruction::PUSH(Argument::new(1)),
                     Instruction::PUSH(Argument::new(2)),
                     Instruction::POP(Register::R0)
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1226,12 +2317,29 @@ mod tests {
 
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::POP(Register::R0)
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
-                assert_eq!(result, Err(VMError::StackUnderflow));
+                assert_eq!(result.map_err(|f| f.error), Err(VMError::StackUnderflow));
 
             }
+
+            // a register reference read out of memory is just a number on the stack
+            #[test]
+            fn register_reference() {
+                let mut vm = VM::init();
+
+                vm.load_instructions(Address::new(0), &vec![
+                    Instruction::RMEM(Register::R2, Argument::new(1)),
+                    Instruction::PUSH(Argument::new(REGISTER_2)),
+                    Instruction::SET(Register::R2, Argument::new(5)),
+                    Instruction::POP(Register::R0)
+                ]).unwrap();
+
+                let result = vm.run(Address::new(0));
+                assert_eq!(result, Ok(VMState::HALT));
+                assert_eq!(vm.registers[0], REGISTER_2);
+            }
         }
 
         mod rmem {
@@ -1244,7 +2352,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::RMEM(Register::R0, Argument::new(0)),
                     Instruction::RMEM(Register::R1, Argument::new(1))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1258,7 +2366,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(1)),
                     Instruction::RMEM(Register::R1, Argument::new(REGISTER_0))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1275,7 +2383,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::WMEM(Argument::new(1000), Argument::new(15)),
                     Instruction::RMEM(Register::R1, Argument::new(1000))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1289,7 +2397,7 @@ mod tests {
                     Instruction::SET(Register::R0, Argument::new(17)),
                     Instruction::WMEM(Argument::new(1000), Argument::new(REGISTER_0)),
                     Instruction::RMEM(Register::R1, Argument::new(1000))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1304,12 +2412,36 @@ mod tests {
                     Instruction::SET(Register::R1, Argument::new(18)),
                     Instruction::WMEM(Argument::new(REGISTER_0), Argument::new(REGISTER_1)),
                     Instruction::RMEM(Register::R1, Argument::new(REGISTER_0))
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
                 assert_eq!(vm.registers[1], 18);
             }
+
+            #[test]
+            fn last_address() {
+                let mut vm = VM::init();
+                vm.load_instructions(Address::new(0), &vec![
+                    Instruction::WMEM(Argument::new(32767), Argument::new(19))
+                ]).unwrap();
+                let result = vm.run(Address::new(0));
+                assert_eq!(result, Ok(VMState::HALT));
+
+                assert_eq!(vm.memory[32767], 19);
+            }
+
+            // R0 ends up holding the word 32768, which is a register, not memory
+            #[test]
+            fn register_address() {
+                let mut vm = VM::init();
+                vm.load_instructions(Address::new(0), &vec![
+                    Instruction::RMEM(Register::R0, Argument::new(1)),
+                    Instruction::WMEM(Argument::new(REGISTER_0), Argument::new(1))
+                ]).unwrap();
+                let result = vm.run(Address::new(0));
+                assert_eq!(result.map_err(|f| f.error), Err(VMError::InvalidMemoryAccess(Address::new(REGISTER_0))));
+            }
         }
 
         mod call {
@@ -1319,7 +2451,7 @@ mod tests {
             fn lit() {
                 let mut vm = VM::init();
 
-                vm.load_instructions(Address::new(0), &vec![Instruction::CALL(Argument::new(10))]);
+                vm.load_instructions(Address::new(0), &vec![Instruction::CALL(Argument::new(10))]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1334,7 +2466,7 @@ mod tests {
                 vm.load_instructions(Address::new(0), &vec![
                     Instruction::SET(Register::R0, Argument::new(15)),
                     Instruction::CALL(Argument::new(REGISTER_0)) 
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1362,7 +2494,7 @@ mod tests {
                     Instruction::NOOP,
                     Instruction::NOOP,
                     Instruction::RET
-                ]);
+                ]).unwrap();
 
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
@@ -1380,7 +2512,7 @@ mod tests {
                     Instruction::HALT, // @5
                     Instruction::NOOP, // @6
                     Instruction::RET   // @7
-                ]);
+                ]).unwrap();
                 let result = vm.run(Address::new(0));
                 assert_eq!(result, Ok(VMState::HALT));
 
@@ -1390,12 +2522,269 @@ mod tests {
         }
     }
 
+    mod devices {
+        use super::*;
+
+        fn buffered_vm(input: &str) -> (VM, BufferOutput) {
+            let out = BufferOutput::new();
+            let vm = VM::with_devices(Box::new(BufferInput::containing(input)), Box::new(out.clone()));
+            (vm, out)
+        }
+
+        #[test]
+        fn out_lit() {
+            let (mut vm, out) = buffered_vm("");
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::OUT(Argument::new(104)),
+                Instruction::OUT(Argument::new(105))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(out.contents(), "hi");
+        }
+
+        #[test]
+        fn out_reg() {
+            let (mut vm, out) = buffered_vm("");
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R0, Argument::new(33)),
+                Instruction::OUT(Argument::new(REGISTER_0))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(out.contents(), "!");
+        }
+
+        #[test]
+        fn in_reg() {
+            let (mut vm, _) = buffered_vm("ab");
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::IN(Argument::new(REGISTER_1))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(vm.registers[0], 97);
+            assert_eq!(vm.registers[1], 98);
+        }
+
+        #[test]
+        fn in_end_of_input() {
+            let (mut vm, _) = buffered_vm("");
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::IN(Argument::new(REGISTER_0))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::EndOfInput));
+        }
+
+        #[test]
+        fn echo() {
+            let (mut vm, out) = buffered_vm("x");
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::OUT(Argument::new(REGISTER_0))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(out.contents(), "x");
+        }
+    }
+
+    mod resumable {
+        use super::*;
+
+        fn echo_vm() -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::OUT(Argument::new(62)),          // @0 '>'
+                Instruction::IN(Argument::new(REGISTER_0)),   // @2
+                Instruction::OUT(Argument::new(REGISTER_0)),  // @4
+                Instruction::EQ(Register::R1, Argument::new(REGISTER_0), Argument::new(10)), // @6
+                Instruction::JF(Argument::new(REGISTER_1), Argument::new(2)),   // @10
+                Instruction::HALT                             // @13
+            ]).unwrap();
+            vm.start(Address::new(0));
+            return vm;
+        }
+
+        #[test]
+        fn yields_for_input() {
+            let mut vm = echo_vm();
+
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.state(), VMState::AwaitingInput);
+            assert_eq!(vm.instruction_pointer(), Address::new(2));
+            assert_eq!(vm.drain_output(), ">");
+
+            // nothing pushed, so still waiting
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.drain_output(), "");
+        }
+
+        #[test]
+        fn resumes_with_pushed_input() {
+            let mut vm = echo_vm();
+            vm.resume().unwrap();
+            vm.drain_output();
+
+            vm.push_input("ok\n");
+            assert_eq!(vm.resume(), Ok(VMState::HALT));
+            assert_eq!(vm.drain_output(), "ok\n");
+        }
+
+        #[test]
+        fn partial_input_waits_again() {
+            let mut vm = echo_vm();
+            vm.push_input("a");
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.drain_output(), ">a");
+
+            vm.push_input("\n");
+            assert_eq!(vm.resume(), Ok(VMState::HALT));
+        }
+
+        #[test]
+        fn run_uses_pushed_input_first() {
+            let out = BufferOutput::new();
+            let mut vm = VM::with_devices(Box::new(BufferInput::containing("b")), Box::new(out.clone()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::OUT(Argument::new(REGISTER_0)),
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::OUT(Argument::new(REGISTER_0))
+            ]).unwrap();
+            vm.push_input("a");
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(out.contents(), "ab");
+        }
+    }
+
+    mod snapshots {
+        use super::*;
+
+        #[test]
+        fn restore_round_trip() {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R7, Argument::new(25734)),
+                Instruction::PUSH(Argument::new(99)),
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::OUT(Argument::new(REGISTER_0))
+            ]).unwrap();
+            vm.start(Address::new(0));
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            vm.push_input("q");
+
+            let snap = vm.snapshot();
+
+            let mut other = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            other.memory[20000] = 12;
+            other.restore(&snap).unwrap();
+
+            assert_eq!(other.snapshot(), snap);
+            assert_eq!(other.memory[20000], 0);
+            assert_eq!(other.registers[7], 25734);
+            assert_eq!(other.stack, vec![99]);
+
+            assert_eq!(other.resume(), Ok(VMState::HALT));
+            assert_eq!(other.drain_output(), "q");
+        }
+
+        #[test]
+        fn restore_too_much_memory() {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            let mut snap = vm.snapshot();
+            snap.memory = vec![0; 40000];
+
+            assert_eq!(vm.restore(&snap), Err(VMError::InvalidMemoryAccess(Address::new(40000))));
+        }
+
+        #[test]
+        fn restore_invalid_word() {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            let mut snap = vm.snapshot();
+            snap.stack = vec![1, 50000];
+
+            assert_eq!(vm.restore(&snap), Err(VMError::InvalidValue(50000)));
+            assert!(vm.stack.is_empty());
+        }
+    }
+
+    mod watchpoints {
+        use super::*;
+        use watch::*;
+
+        fn vm() -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R0, Argument::new(5)),                 // @0
+                Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_0)), // @3
+                Instruction::RMEM(Register::R1, Argument::new(100)),              // @6
+                Instruction::JT(Argument::new(REGISTER_7), Argument::new(0)),     // @9
+                Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)) // @12
+            ]).unwrap();
+            return vm;
+        }
+
+        #[test]
+        fn break_on_register_read() {
+            let mut vm = vm();
+            vm.watchpoints().add(Watchpoint::new(WatchTarget::Register(Register::R7), WatchKind::Read, WatchAction::Break));
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::WatchpointHit));
+            assert_eq!(vm.instruction_pointer(), Address::new(12));
+
+            let hits = vm.watchpoints().take_hits();
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].instruction, Address::new(9));
+            assert_eq!(hits[0].access, Access::Read);
+
+            // and we can carry on afterward
+            assert_eq!(vm.resume(), Ok(VMState::HALT));
+            assert_eq!(vm.register(Register::R0), 6);
+        }
+
+        #[test]
+        fn log_memory_accesses() {
+            let mut vm = vm();
+            vm.watchpoints().add(Watchpoint::new(WatchTarget::Memory(Address::new(100)), WatchKind::ReadWrite, WatchAction::Log));
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+
+            let hits = vm.watchpoints().take_hits();
+            assert_eq!(hits.len(), 2);
+            assert_eq!((hits[0].access, hits[0].value, hits[0].instruction), (Access::Write, 5, Address::new(3)));
+            assert_eq!((hits[1].access, hits[1].value, hits[1].instruction), (Access::Read, 5, Address::new(6)));
+        }
+
+        #[test]
+        fn conditional_write() {
+            let mut vm = vm();
+            vm.watchpoints().add(Watchpoint::new(WatchTarget::Register(Register::R0), WatchKind::Write, WatchAction::Break).when(Condition::Equal(6)));
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::WatchpointHit));
+            assert_eq!(vm.instruction_pointer(), Address::new(16));
+        }
+
+        #[test]
+        fn fetch_is_not_a_read() {
+            let mut vm = vm();
+            vm.watchpoints().add(Watchpoint::new(WatchTarget::Memory(Address::new(0)), WatchKind::Read, WatchAction::Break));
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+        }
+    }
+
     mod step {
         use super::*;
 
         #[test]
         fn step() {
             let mut vm = loaded_vm();
+            let out = BufferOutput::new();
+            vm.set_output_device(Box::new(out.clone()));
 
             // force the instruction pointer to the beginning of the program
             vm.instruction_pointer = Address::new(1000);
@@ -1413,15 +2802,15 @@ mod tests {
             assert_eq!(vm.registers[1], 0);
 
             result = vm.step();
-            // FIXME: this should output the ascii value '4' to an output stream, since I don't
-            // have the output stream injected yet, no good way to test for that.
             assert_eq!(result, Ok(VMState::RUN));
+            assert_eq!(out.contents(), "\u{4}");
 
             assert_eq!(vm.instruction_pointer, Address::new(1006));
 
             result = vm.step();
             assert_eq!(result, Ok(VMState::HALT));
             assert_eq!(vm.instruction_pointer, Address::new(1007));
+            assert_eq!(vm.step_count(), 3);
         }
 
         #[test]
@@ -1438,14 +2827,14 @@ mod tests {
 
         #[test]
         fn read_memory_happy() {
-            let vm = loaded_vm();
+            let mut vm = loaded_vm();
             let ptr = Address::new(1000);
             assert_eq!(vm.read_memory(&ptr), Ok(9));
         }
 
         #[test]
         fn read_memory_invalid() {
-            let vm = loaded_vm();
+            let mut vm = loaded_vm();
             let ptr = Address::new(40000);
             assert_eq!(vm.read_memory(&ptr), Err(VMError::InvalidMemoryAccess(ptr)));
         }
@@ -1477,19 +2866,645 @@ mod tests {
         }
 
         #[test]
-        #[should_panic] // XXX: It really shouldn't, but I don't want to refactor this right now.
         fn current_instruction_malformed() {
             let mut vm = loaded_vm();
 
-            vm.write_memory(&Address::new(1002), 40000); // write some bad value into memory, out of bounds or w/e
+            // memory can't hold a bad value at all
+            assert_eq!(vm.write_memory(&Address::new(1002), 40000), Err(VMError::InvalidValue(40000)));
+            assert_eq!(vm.memory[1002], 32769);
+
+            // but it can hold a literal where ADD needs a register
+            vm.write_memory(&Address::new(1001), 5).unwrap();
 
             // force the instruction pointer to the beginning of the program
             vm.instruction_pointer = Address::new(1000);
             assert_eq!(
                 vm.current_instruction(),
-                Err(VMError::MalformedInstruction(vec![9,REGISTER_0, 40000]))
+                Err(VMError::MalformedInstruction(vec![9, 5, REGISTER_1, 4]))
             );
             assert_eq!(vm.instruction_pointer, Address::new(1004));
         }
     }
+
+    mod observers {
+        use super::*;
+        use std::sync::{Arc, Mutex};
+        use observer::{VmObserver, Verdict};
+
+        /// Records everything it sees in a shared log
+        struct Logger(Arc<Mutex<Vec<String>>>);
+
+        impl VmObserver for Logger {
+            fn before_instruction(&mut self, address: Address, instruction: &Instruction) -> Verdict {
+                self.0.lock().unwrap().push(format!("{} {}", address, instruction));
+                Verdict::Continue
+            }
+
+            fn memory_write(&mut self, address: Address, value: u16) -> Option<u16> {
+                self.0.lock().unwrap().push(format!("wmem {} {}", address, value));
+                Some(value)
+            }
+
+            fn push(&mut self, value: u16) -> u16 {
+                self.0.lock().unwrap().push(format!("push {}", value));
+                value
+            }
+
+            fn pop(&mut self, value: u16) -> u16 {
+                self.0.lock().unwrap().push(format!("pop {}", value));
+                value
+            }
+        }
+
+        /// Doubles every register write, refuses memory writes and skips OUTs
+        struct Meddler;
+
+        impl VmObserver for Meddler {
+            fn before_instruction(&mut self, _address: Address, instruction: &Instruction) -> Verdict {
+                match *instruction {
+                    Instruction::OUT(_) => Verdict::Skip,
+                    _ => Verdict::Continue
+                }
+            }
+
+            fn register_write(&mut self, _register: Register, value: u16) -> Option<u16> {
+                Some(value * 2)
+            }
+
+            fn memory_write(&mut self, _address: Address, _value: u16) -> Option<u16> {
+                None
+            }
+
+            fn input_requested(&mut self) -> Option<u8> {
+                Some(b'z')
+            }
+        }
+
+        fn vm() -> (VM, BufferOutput) {
+            let output = BufferOutput::new();
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(output.clone()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R0, Argument::new(5)),                 // @0
+                Instruction::PUSH(Argument::new(REGISTER_0)),                     // @3
+                Instruction::POP(Register::R1),                                   // @5
+                Instruction::WMEM(Argument::new(100), Argument::new(REGISTER_1)), // @7
+                Instruction::OUT(Argument::new(97)),                              // @10
+                Instruction::IN(Argument::new(REGISTER_2))                        // @12
+            ]).unwrap();
+            return (vm, output);
+        }
+
+        #[test]
+        fn sees_everything() {
+            let (mut vm, _) = vm();
+            let log = Arc::new(Mutex::new(vec![]));
+            vm.add_observer(Box::new(Logger(log.clone())));
+            vm.push_input("x");
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            let log = log.lock().unwrap();
+            assert_eq!(log[..6].to_vec(), vec!["@0 SET R0 5", "@3 PUSH R0", "push 5", "@5 POP R1", "pop 5", "@7 WMEM 100 R1"]);
+            assert_eq!(log[6], "wmem @100 5");
+            assert_eq!(log.len(), 10);
+        }
+
+        #[test]
+        fn veto_and_alter() {
+            let (mut vm, output) = vm();
+            vm.add_observer(Box::new(Meddler));
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(vm.register(Register::R0), 10);
+            assert_eq!(vm.register(Register::R1), 20);
+            assert_eq!(vm.register(Register::R2), 244);
+            assert_eq!(vm.peek(Address::new(100)), Ok(0));
+            assert_eq!(output.contents(), "");
+        }
+
+        /// Puts 40000, which no word can hold, wherever it's told to
+        struct Corrupter { register: bool, push: bool, pop: bool }
+
+        impl VmObserver for Corrupter {
+            fn register_write(&mut self, _register: Register, value: u16) -> Option<u16> {
+                Some(if self.register { 40000 } else { value })
+            }
+
+            fn push(&mut self, value: u16) -> u16 {
+                if self.push { 40000 } else { value }
+            }
+
+            fn pop(&mut self, value: u16) -> u16 {
+                if self.pop { 40000 } else { value }
+            }
+        }
+
+        #[test]
+        fn altered_values_are_checked() {
+            let corrupters = vec![
+                (Corrupter { register: true, push: false, pop: false }, Address::new(3)),
+                (Corrupter { register: false, push: true, pop: false }, Address::new(5)),
+                (Corrupter { register: false, push: false, pop: true }, Address::new(7)),
+            ];
+            // each fails on the instruction before `after`, leaving nothing behind
+            for (corrupter, after) in corrupters {
+                let (mut machine, _) = vm();
+                machine.add_observer(Box::new(corrupter));
+                assert_eq!(machine.run(Address::new(0)).map_err(|f| f.error), Err(VMError::InvalidValue(40000)));
+                assert_eq!(machine.instruction_pointer(), after);
+                assert_eq!(machine.register(Register::R1), 0);
+                assert!(machine.stack().is_empty());
+            }
+        }
+
+        #[test]
+        fn halt_verdict() {
+            struct Stopper;
+            impl VmObserver for Stopper {
+                fn before_instruction(&mut self, address: Address, _instruction: &Instruction) -> Verdict {
+                    if address == Address::new(5) { Verdict::Halt } else { Verdict::Continue }
+                }
+            }
+
+            let (mut vm, _) = vm();
+            vm.add_observer(Box::new(Stopper));
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(vm.stack(), &[5]);
+            assert_eq!(vm.take_observers().len(), 1);
+        }
+    }
+
+    mod natives {
+        use super::*;
+
+        // @0  SET R0 4
+        // @3  CALL 10
+        // @5  ADD R0 R0 1
+        // @9  HALT
+        // @10 JMP 10
+        fn vm() -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R0, Argument::new(4)),
+                Instruction::CALL(Argument::new(10)),
+                Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+                Instruction::HALT,
+                Instruction::JMP(Argument::new(10))
+            ]).unwrap();
+            return vm;
+        }
+
+        #[test]
+        fn replaces_the_guest_routine() {
+            let mut vm = vm();
+            vm.set_native(Address::new(10), |vm| {
+                let r0 = vm.register(Register::R0);
+                vm.set_register(Register::R0, r0 * 10)
+            });
+
+            assert!(vm.has_native(Address::new(10)));
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(vm.register(Register::R0), 41);
+            assert!(vm.stack().is_empty());
+            assert_eq!(vm.step_count(), 4);
+        }
+
+        #[test]
+        fn errors_stop_the_vm() {
+            let mut vm = vm();
+            vm.set_native(Address::new(10), |_| Err(VMError::UnknownError));
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::UnknownError));
+        }
+
+        #[test]
+        fn removed() {
+            let mut vm = vm();
+            vm.set_native(Address::new(10), |_| Ok(()));
+            assert!(vm.remove_native(Address::new(10)));
+            assert!(!vm.remove_native(Address::new(10)));
+
+            vm.start(Address::new(0));
+            for _ in 0..5 { vm.step().unwrap(); }
+            assert_eq!(vm.instruction_pointer(), Address::new(10));
+            assert_eq!(vm.stack(), &[5]);
+        }
+    }
+
+    mod decode_cache {
+        use super::*;
+
+        // @0  OUT 'a'
+        // @2  JT R0 14
+        // @5  SET R0 1
+        // @8  WMEM 1 'b'
+        // @11 JMP 0
+        // @13 NOOP
+        // @14 HALT
+        fn self_modifying(cache: bool) -> String {
+            let output = BufferOutput::new();
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(output.clone()));
+            vm.set_decode_cache(cache);
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::OUT(Argument::new(97)),
+                Instruction::JT(Argument::new(REGISTER_0), Argument::new(14)),
+                Instruction::SET(Register::R0, Argument::new(1)),
+                Instruction::WMEM(Argument::new(1), Argument::new(98)),
+                Instruction::JMP(Argument::new(0)),
+                Instruction::NOOP,
+                Instruction::HALT
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            output.contents()
+        }
+
+        #[test]
+        fn writes_invalidate() {
+            assert_eq!(self_modifying(true), "ab");
+            assert_eq!(self_modifying(false), "ab");
+        }
+
+        #[test]
+        fn poke_invalidates() {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![Instruction::SET(Register::R0, Argument::new(1))]).unwrap();
+
+            vm.start(Address::new(0));
+            vm.step().unwrap();
+            vm.poke(Address::new(2), 2).unwrap();
+            vm.start(Address::new(0));
+            vm.step().unwrap();
+
+            assert_eq!(vm.register(Register::R0), 2);
+        }
+    }
+
+    mod forks {
+        use super::*;
+        use pages::PAGE_SIZE;
+
+        // @0 IN R0
+        // @2 WMEM 1000 R0
+        // @5 OUT R0
+        // @7 JMP 0
+        fn echo() -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::IN(Argument::new(REGISTER_0)),
+                Instruction::WMEM(Argument::new(1000), Argument::new(REGISTER_0)),
+                Instruction::OUT(Argument::new(REGISTER_0)),
+                Instruction::JMP(Argument::new(0))
+            ]).unwrap();
+            vm.start(Address::new(0));
+            return vm;
+        }
+
+        #[test]
+        fn forks_run_independently() {
+            let mut vm = echo();
+            vm.push_input("a");
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.drain_output(), "a");
+
+            let mut fork = vm.clone();
+            assert_eq!(fork.shared_memory_pages(&vm), MODULUS as usize / PAGE_SIZE);
+
+            fork.push_input("b");
+            assert_eq!(fork.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(fork.drain_output(), "b");
+            assert_eq!(fork.peek(Address::new(1000)), Ok(98));
+            assert_eq!(vm.peek(Address::new(1000)), Ok(97));
+            assert_eq!(fork.shared_memory_pages(&vm), MODULUS as usize / PAGE_SIZE - 1);
+
+            vm.push_input("c");
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.drain_output(), "c");
+            assert_eq!(vm.step_count(), fork.step_count());
+        }
+
+        #[test]
+        fn forks_keep_their_caches() {
+            let mut vm = echo();
+            vm.set_engine(Engine::Blocks);
+            vm.push_input("ab");
+            assert_eq!(vm.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(vm.drain_output(), "ab");
+
+            let mut fork = vm.clone();
+            fork.push_input("c");
+            assert_eq!(fork.resume(), Ok(VMState::AwaitingInput));
+            assert_eq!(fork.drain_output(), "c");
+            assert_eq!(fork.engine(), Engine::Blocks);
+        }
+
+        #[test]
+        fn forks_can_move_between_threads() {
+            let mut vm = echo();
+            vm.push_input("a");
+            vm.resume().unwrap();
+            vm.drain_output();
+
+            let handles : Vec<_> = "xyz".chars().map(|c| {
+                let mut fork = vm.clone();
+                ::std::thread::spawn(move || {
+                    fork.push_input(&c.to_string());
+                    fork.resume().unwrap();
+                    fork.drain_output()
+                })
+            }).collect();
+            let outputs : Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(outputs, vec!["x", "y", "z"]);
+        }
+    }
+
+    mod budgets {
+        use super::*;
+        use std::thread;
+        use blocks::Engine;
+
+        // @0 ADD R0 R0 1
+        // @4 JMP 0
+        fn forever(engine: Engine) -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.set_engine(engine);
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::ADD(Register::R0, Argument::new(REGISTER_0), Argument::new(1)),
+                Instruction::JMP(Argument::new(0))
+            ]).unwrap();
+            vm.start(Address::new(0));
+            return vm;
+        }
+
+        #[test]
+        fn run_for_steps() {
+            for engine in &[Engine::Interpreter, Engine::Blocks] {
+                let mut vm = forever(*engine);
+                assert_eq!(vm.run_for(1001), Ok(VMState::BudgetExhausted));
+                assert_eq!(vm.step_count(), 1001);
+                assert_eq!(vm.register(Register::R0), 501);
+
+                assert_eq!(vm.run_for(9), Ok(VMState::BudgetExhausted));
+                assert_eq!(vm.step_count(), 1010);
+                assert_eq!(vm.state(), VMState::BudgetExhausted);
+            }
+        }
+
+        #[test]
+        fn run_for_finishes_early() {
+            let mut vm = forever(Engine::Interpreter);
+            vm.poke(Address::new(4), 0).unwrap();
+            assert_eq!(vm.run_for(100), Ok(VMState::HALT));
+            assert_eq!(vm.step_count(), 2);
+        }
+
+        #[test]
+        fn deadline() {
+            let mut vm = forever(Engine::Blocks);
+            vm.set_timeout(Duration::from_millis(20));
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::BudgetExhausted));
+            assert!(vm.step_count() > 0);
+
+            // still past it
+            assert_eq!(vm.resume(), Ok(VMState::BudgetExhausted));
+
+            vm.set_deadline(None);
+            let steps = vm.step_count();
+            assert_eq!(vm.run_for(10), Ok(VMState::BudgetExhausted));
+            assert_eq!(vm.step_count(), steps + 10);
+        }
+
+        #[test]
+        fn cancel_from_another_thread() {
+            let mut vm = forever(Engine::Interpreter);
+            let handle = vm.cancel_handle();
+            let canceller = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(20));
+                handle.cancel();
+            });
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::Paused));
+            canceller.join().unwrap();
+
+            // the request is used up, so we can carry on
+            let steps = vm.step_count();
+            assert_eq!(vm.run_for(5), Ok(VMState::BudgetExhausted));
+            assert_eq!(vm.step_count(), steps + 5);
+        }
+
+        #[test]
+        fn cancel_before_running() {
+            let mut vm = forever(Engine::Interpreter);
+            vm.cancel_handle().cancel();
+            assert_eq!(vm.resume(), Ok(VMState::Paused));
+            assert_eq!(vm.step_count(), 0);
+        }
+    }
+
+    mod faults {
+        use super::*;
+        use blocks::Engine;
+
+        // @0 PUSH 5
+        // @2 POP R1
+        // @4 POP R0
+        fn vm(engine: Engine) -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.set_engine(engine);
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::PUSH(Argument::new(5)),
+                Instruction::POP(Register::R1),
+                Instruction::POP(Register::R0)
+            ]).unwrap();
+            return vm;
+        }
+
+        #[test]
+        fn context() {
+            for engine in &[Engine::Interpreter, Engine::Blocks] {
+                let mut vm = vm(*engine);
+                let fault = vm.run(Address::new(0)).unwrap_err();
+                assert_eq!(fault.error, VMError::StackUnderflow);
+
+                let fault = fault.context;
+                assert_eq!(fault.address, Address::new(4));
+                assert_eq!(fault.instruction, Some(Instruction::POP(Register::R0)));
+                assert_eq!(fault.registers[1], 5);
+                assert_eq!(fault.stack_depth, 0);
+                assert_eq!(fault.step, 2);
+            }
+        }
+
+        #[test]
+        fn undecodable() {
+            let mut vm = vm(Engine::Interpreter);
+            vm.poke(Address::new(4), 30).unwrap();
+            let fault = vm.run(Address::new(0)).unwrap_err();
+            assert_eq!(fault.error, VMError::BadOpcode(30));
+            assert_eq!(fault.context.instruction, None);
+        }
+
+        #[test]
+        fn display() {
+            assert_eq!(format!("{}", VMError::BadOpcode(30)), "bad opcode 30");
+            assert_eq!(format!("{}", VMError::JumpOutOfBounds(Address::new(32768))), "jump out of bounds to @32768");
+            let e : Box<dyn Error> = Box::new(VMError::StackUnderflow);
+            assert_eq!(e.to_string(), "pop from an empty stack");
+        }
+    }
+
+    mod call_stack {
+        use super::*;
+        use callstack::{Frame, Mismatch};
+
+        // @0  CALL 4
+        // @2  HALT
+        // @3  NOOP
+        // @4  CALL 8
+        // @6  RET
+        // @7  NOOP
+        // @8  POP R0
+        // @10 PUSH 2
+        // @12 RET
+        fn vm() -> VM {
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(BufferOutput::new()));
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::CALL(Argument::new(4)),
+                Instruction::HALT,
+                Instruction::NOOP,
+                Instruction::CALL(Argument::new(8)),
+                Instruction::RET,
+                Instruction::NOOP,
+                Instruction::POP(Register::R0),
+                Instruction::PUSH(Argument::new(2)),
+                Instruction::RET
+            ]).unwrap();
+            vm.start(Address::new(0));
+            return vm;
+        }
+
+        #[test]
+        fn frames_follow_calls() {
+            let mut vm = vm();
+            vm.step().unwrap();
+            vm.step().unwrap();
+
+            assert_eq!(vm.call_stack().frames(), &vec![
+                Frame { call_site: Address::new(0), target: Address::new(4), return_address: Address::new(2), stack_depth: 1 },
+                Frame { call_site: Address::new(4), target: Address::new(8), return_address: Address::new(6), stack_depth: 2 },
+            ]);
+        }
+
+        #[test]
+        fn rewritten_return_address() {
+            let mut vm = vm();
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+
+            // the inner call returned straight to @2, skipping the outer call's RET
+            assert_eq!(vm.call_stack().mismatches(), 1);
+            assert_eq!(vm.call_stack().last_mismatch(), Some(Mismatch { at: Address::new(12), expected: Address::new(6), actual: 2, unwound: 1 }));
+            assert_eq!(vm.call_stack().depth(), 1);
+        }
+
+        #[test]
+        fn natives_have_no_frame() {
+            let mut vm = vm();
+            vm.set_native(Address::new(4), |_| Ok(()));
+            vm.step().unwrap();
+            assert_eq!(vm.call_stack().depth(), 0);
+        }
+    }
+
+    mod engines {
+        use super::*;
+        use std::fs;
+        use binary::Binary;
+        use blocks::Engine;
+
+        fn challenge(engine: Engine) -> (VM, BufferOutput) {
+            let mut b = Binary::new(&format!("{}/data/challenge.bin", env!("CARGO_MANIFEST_DIR")));
+            b.parse();
+
+            let output = BufferOutput::new();
+            let mut vm = VM::with_devices(Box::new(BufferInput::new()), Box::new(output.clone()));
+            vm.set_engine(engine);
+            vm.load_program(Address::new(0), b.binary()).unwrap();
+            vm.start(Address::new(0));
+            return (vm, output);
+        }
+
+        /// Play the challenge through with the known answers on both engines, they should end up
+        /// in exactly the same place having printed the same things.
+        #[test]
+        fn blocks_match_the_interpreter_on_the_challenge() {
+            let answers = fs::read_to_string(format!("{}/data/answers", env!("CARGO_MANIFEST_DIR"))).unwrap();
+            let mut runs = vec![];
+
+            for engine in &[Engine::Interpreter, Engine::Blocks] {
+                let (mut vm, _) = challenge(*engine);
+                let mut transcript = String::new();
+
+                for line in answers.lines() {
+                    let state = vm.resume();
+                    transcript.push_str(&vm.drain_output());
+                    if state != Ok(VMState::AwaitingInput) { break; }
+                    vm.push_input(line);
+                    vm.push_input("\n");
+                }
+                transcript.push_str(&vm.drain_output());
+
+                runs.push((transcript, vm.snapshot(), vm.step_count(), vm.call_stack().clone()));
+            }
+
+            assert!(runs[0].0.contains("strange book"));
+            assert_eq!(runs[0].2, runs[1].2);
+            assert_eq!(runs[0].1, runs[1].1);
+            assert_eq!(runs[0].0, runs[1].0);
+            assert_eq!(runs[0].3, runs[1].3);
+        }
+
+        #[test]
+        fn blocks_run_the_teleporter_check() {
+            let mut results = vec![];
+            for engine in &[Engine::Interpreter, Engine::Blocks] {
+                let (mut vm, _) = challenge(*engine);
+                vm.set_register(Register::R0, 2).unwrap();
+                vm.set_register(Register::R1, 3).unwrap();
+                vm.set_register(Register::R7, 2).unwrap();
+                assert_eq!(vm.run(Address::new(6027)), Ok(VMState::HALT));
+                results.push((vm.registers(), vm.step_count(), vm.call_stack().clone()));
+            }
+            assert_eq!(results[0], results[1]);
+        }
+
+        #[test]
+        fn blocks_see_self_modification() {
+            let (mut vm, output) = challenge(Engine::Blocks);
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::OUT(Argument::new(97)),
+                Instruction::JT(Argument::new(REGISTER_0), Argument::new(14)),
+                Instruction::SET(Register::R0, Argument::new(1)),
+                Instruction::WMEM(Argument::new(1), Argument::new(98)),
+                Instruction::JMP(Argument::new(0)),
+                Instruction::NOOP,
+                Instruction::HALT
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)), Ok(VMState::HALT));
+            assert_eq!(output.contents(), "ab");
+            assert_eq!(vm.step_count(), 8);
+        }
+
+        #[test]
+        fn pop_underflow_in_a_block() {
+            let (mut vm, _) = challenge(Engine::Blocks);
+            vm.load_instructions(Address::new(0), &vec![
+                Instruction::SET(Register::R0, Argument::new(1)),
+                Instruction::POP(Register::R1),
+                Instruction::JMP(Argument::new(0))
+            ]).unwrap();
+
+            assert_eq!(vm.run(Address::new(0)).map_err(|f| f.error), Err(VMError::StackUnderflow));
+            assert_eq!(vm.instruction_pointer(), Address::new(5));
+            assert_eq!(vm.step_count(), 1);
+        }
+    }
 }
@@ -0,0 +1,263 @@
+use std::fmt;
+
+use address::Address;
+use register::Register;
+
+/// What a watchpoint is keeping an eye on
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WatchTarget {
+    Register(Register),
+    Memory(Address)
+}
+
+/// A single read or write of a watched target
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write
+}
+
+/// Which accesses a watchpoint cares about
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite
+}
+
+/// Only fire when the value read or written satisfies this
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Condition {
+    Equal(u16),
+    NotEqual(u16),
+    GreaterThan(u16),
+    LessThan(u16)
+}
+
+/// What to do when a watchpoint fires. Either way the hit is recorded, `Break` also stops the VM
+/// once the current instruction finishes.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WatchAction {
+    Break,
+    Log
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+    pub action: WatchAction,
+}
+
+/// A record of a watchpoint firing
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WatchHit {
+    pub id: usize,
+    pub target: WatchTarget,
+    pub access: Access,
+    pub value: u16,
+    /// The address of the instruction which made the access
+    pub instruction: Address,
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WatchTarget::Register(r) => write!(f, "{}", r),
+            WatchTarget::Memory(a) => write!(f, "{}", a),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::Equal(v) => write!(f, "== {}", v),
+            Condition::NotEqual(v) => write!(f, "!= {}", v),
+            Condition::GreaterThan(v) => write!(f, "> {}", v),
+            Condition::LessThan(v) => write!(f, "< {}", v),
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::ReadWrite => "access",
+        };
+        let action = match self.action {
+            WatchAction::Break => "break",
+            WatchAction::Log => "log",
+        };
+        write!(f, "{} on {} {}", action, kind, self.target)?;
+        if let Some(c) = self.condition { write!(f, " {}", c)?; }
+        Ok(())
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match self.access {
+            Access::Read => "read",
+            Access::Write => "write",
+        };
+        write!(f, "watchpoint {}: {} {} {} at {}", self.id, access, self.target, self.value, self.instruction)
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, value: u16) -> bool {
+        match *self {
+            Condition::Equal(v) => value == v,
+            Condition::NotEqual(v) => value != v,
+            Condition::GreaterThan(v) => value > v,
+            Condition::LessThan(v) => value < v,
+        }
+    }
+}
+
+impl Watchpoint {
+    pub fn new(target: WatchTarget, kind: WatchKind, action: WatchAction) -> Watchpoint {
+        Watchpoint { target, kind, condition: None, action }
+    }
+
+    pub fn when(mut self, condition: Condition) -> Watchpoint {
+        self.condition = Some(condition);
+        self
+    }
+
+    pub fn fires_on(&self, target: WatchTarget, access: Access, value: u16) -> bool {
+        let kind_matches = match (self.kind, access) {
+            (WatchKind::ReadWrite, _) => true,
+            (WatchKind::Read, Access::Read) => true,
+            (WatchKind::Write, Access::Write) => true,
+            _ => false
+        };
+
+        self.target == target && kind_matches && self.condition.map_or(true, |c| c.matches(value))
+    }
+}
+
+/// The set of watchpoints on a VM, along with the hits recorded so far.
+#[derive(Debug, Clone)]
+pub struct Watchpoints {
+    watches: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+    hits: Vec<WatchHit>,
+    should_break: bool,
+}
+
+impl Default for Watchpoints {
+    fn default() -> Watchpoints {
+        Watchpoints::new()
+    }
+}
+
+impl Watchpoints {
+    pub fn new() -> Watchpoints {
+        Watchpoints { watches: vec![], next_id: 1, hits: vec![], should_break: false }
+    }
+
+    /// Add a watchpoint, returning an id that can be used to remove it
+    pub fn add(&mut self, w: Watchpoint) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watches.push((id, w));
+        return id;
+    }
+
+    pub fn remove(&mut self, id: usize) -> Option<Watchpoint> {
+        match self.watches.iter().position(|&(i, _)| i == id) {
+            Some(idx) => Some(self.watches.remove(idx).1),
+            None => None
+        }
+    }
+
+    pub fn list(&self) -> &Vec<(usize, Watchpoint)> {
+        &self.watches
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// Check an access against every watchpoint, recording any hits.
+    pub fn check(&mut self, target: WatchTarget, access: Access, value: u16, instruction: Address) {
+        for &(id, ref w) in &self.watches {
+            if w.fires_on(target, access, value) {
+                self.hits.push(WatchHit { id, target, access, value, instruction });
+                if w.action == WatchAction::Break { self.should_break = true; }
+            }
+        }
+    }
+
+    /// Whether a `Break` watchpoint has fired since the last call, resetting it.
+    pub fn take_break(&mut self) -> bool {
+        ::std::mem::replace(&mut self.should_break, false)
+    }
+
+    /// Take all the hits recorded so far
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        self.hits.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        let t = WatchTarget::Register(Register::R7);
+        let w = Watchpoint::new(t, WatchKind::Read, WatchAction::Break);
+        assert!(w.fires_on(t, Access::Read, 0));
+        assert!(!w.fires_on(t, Access::Write, 0));
+        assert!(!w.fires_on(WatchTarget::Register(Register::R6), Access::Read, 0));
+
+        let w = Watchpoint::new(t, WatchKind::ReadWrite, WatchAction::Break);
+        assert!(w.fires_on(t, Access::Read, 0));
+        assert!(w.fires_on(t, Access::Write, 0));
+    }
+
+    #[test]
+    fn conditions() {
+        let t = WatchTarget::Memory(Address::new(100));
+        let w = Watchpoint::new(t, WatchKind::Write, WatchAction::Log).when(Condition::GreaterThan(5));
+        assert!(w.fires_on(t, Access::Write, 6));
+        assert!(!w.fires_on(t, Access::Write, 5));
+    }
+
+    #[test]
+    fn check_records_hits() {
+        let t = WatchTarget::Register(Register::R0);
+        let mut ws = Watchpoints::new();
+        let log = ws.add(Watchpoint::new(t, WatchKind::Write, WatchAction::Log));
+        let brk = ws.add(Watchpoint::new(t, WatchKind::Write, WatchAction::Break).when(Condition::Equal(3)));
+
+        ws.check(t, Access::Write, 1, Address::new(10));
+        assert!(!ws.take_break());
+
+        ws.check(t, Access::Write, 3, Address::new(12));
+        assert!(ws.take_break());
+        assert!(!ws.take_break());
+
+        let hits = ws.take_hits();
+        assert_eq!(hits.len(), 3);
+        assert_eq!(hits[0].id, log);
+        assert_eq!(hits[2].id, brk);
+        assert_eq!(hits[2].instruction, Address::new(12));
+        assert!(ws.take_hits().is_empty());
+    }
+
+    #[test]
+    fn remove() {
+        let mut ws = Watchpoints::new();
+        let id = ws.add(Watchpoint::new(WatchTarget::Register(Register::R0), WatchKind::Read, WatchAction::Log));
+        assert!(ws.remove(id).is_some());
+        assert!(ws.remove(id).is_none());
+        assert!(ws.is_empty());
+    }
+}